@@ -1,38 +1,69 @@
+use crate::attachments::AttachmentStore;
 use crate::config::Config;
 use crate::cursor::Cursor;
 use crate::signal::{
     self, Attachment, GroupIdentifierBytes, GroupMasterKeyBytes, ResolvedGroup, SignalManager,
 };
-use crate::storage::Storage;
+use crate::storage::{self, Storage, MESSAGES_PAGE_SIZE};
 use crate::util::{
     self, FilteredStatefulList, LazyRegex, StatefulList, ATTACHMENT_REGEX, URL_REGEX,
 };
 
 use anyhow::{anyhow, Context as _};
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseEvent};
+use gh_emoji::Replacer;
 use itertools::Itertools;
 use notify_rust::Notification;
 use phonenumber::{Mode, PhoneNumber};
-use presage::prelude::proto::{AttachmentPointer, ReceiptMessage, TypingMessage};
+use presage::prelude::proto::{ReceiptMessage, TypingMessage};
 use presage::prelude::{
     content::{ContentBody, DataMessage, Metadata, SyncMessage},
     proto::{
-        data_message::{Quote, Reaction},
-        sync_message::Sent,
+        data_message::{body_range::AssociatedValue, BodyRange, Delete, Flags, Quote, Reaction},
+        sync_message::{Blocked, Sent},
         GroupContextV2,
     },
     AttachmentSpec, Content, GroupMasterKey, GroupSecretParams, ServiceAddress,
 };
 use regex_automata::Regex;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
-use std::borrow::Cow;
-use std::cmp::Reverse;
+use std::cmp::{Ordering, Reverse};
 use std::collections::{hash_map::Entry, HashMap, HashSet};
-use std::convert::{TryFrom, TryInto};
-use std::path::Path;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Period in which the retention policies are applied while running
+const RETENTION_PERIOD: Duration = Duration::from_secs(60 * 60);
+const MSEC_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+/// Time after sending in which Signal allows to delete a message for everyone
+const DELETE_FOR_EVERYONE_PERIOD_MSEC: u64 = MSEC_PER_DAY;
+/// Timers of disappearing messages in seconds, which are cycled through after disabled
+const EXPIRE_TIMER_PRESETS: &[u32] = &[
+    30,
+    5 * 60,
+    60 * 60,
+    8 * 60 * 60,
+    24 * 60 * 60,
+    7 * 24 * 60 * 60,
+    4 * 7 * 24 * 60 * 60,
+];
+/// Number of times a message is sent before it is marked as failed, if its sends keep failing
+/// in presage, e.g. because the connection is lost
+const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Period in which the outbox is sent again while online, for sends which failed in presage
+/// without gurk noticing the connection loss
+const RESEND_PERIOD: Duration = Duration::from_secs(60);
+/// Period in which the start of typing is sent at most once, shorter than the period after which
+/// Signal clients consider the typing stopped
+const TYPING_STARTED_PERIOD: Duration = Duration::from_secs(10);
+/// Number of members of a group above which no typing indicators are sent, since they are sent to
+/// each member separately
+const MAX_TYPING_GROUP_SIZE: usize = 32;
 
 pub struct App {
     pub config: Config,
@@ -47,6 +78,70 @@ pub struct App {
     pub is_searching: bool,
     pub channel_text_width: usize,
     receipt_handler: ReceiptHandler,
+    /// Channels whose messages are all loaded from the storage
+    fully_loaded_channels: HashSet<ChannelId>,
+    /// Last time the retention policies were applied
+    last_retention_at: Instant,
+    /// Whether gurk is connected to Signal; messages composed while offline are queued
+    is_online: bool,
+    /// Last time the outbox was sent
+    last_flush_at: Instant,
+    /// Whether the contacts and the blocked list were requested from the primary device since
+    /// gurk started
+    sync_requested: bool,
+    /// Input when the typing indicators were last updated, to detect changes
+    typing_input: String,
+    /// Channel to which the start of typing was sent last, and when
+    typing_started: Option<(ChannelId, Instant)>,
+    /// Number of failed attempts of the messages being sent again from the outbox, by channel and
+    /// arrival time
+    send_attempts: HashMap<(ChannelId, u64), u32>,
+    attachment_store: AttachmentStore,
+    /// Sender and receiver of the attachment downloads finished in the background
+    downloads_tx: mpsc::UnboundedSender<FinishedDownload>,
+    downloads_rx: mpsc::UnboundedReceiver<FinishedDownload>,
+    /// Sender and receiver of the sends finished in the background
+    sends_tx: mpsc::UnboundedSender<FinishedSend>,
+    sends_rx: mpsc::UnboundedReceiver<FinishedSend>,
+    emoji_replacer: Replacer,
+}
+
+/// Attachment download which finished in the background
+struct FinishedDownload {
+    channel_id: ChannelId,
+    arrived_at: u64,
+    attachment_idx: usize,
+    result: anyhow::Result<Vec<u8>>,
+}
+
+/// Send which finished in the background
+enum FinishedSend {
+    /// Message which arrived at `arrived_at` in the channel
+    Message {
+        channel_id: ChannelId,
+        arrived_at: u64,
+        result: anyhow::Result<()>,
+    },
+    /// Reaction to the message which arrived at `arrived_at` in the channel
+    Reaction {
+        channel_id: ChannelId,
+        arrived_at: u64,
+        emoji: String,
+        remove: bool,
+        result: anyhow::Result<()>,
+    },
+    /// Deletion for everyone of the message which arrived at `arrived_at` in the channel
+    Delete {
+        channel_id: ChannelId,
+        arrived_at: u64,
+        result: anyhow::Result<()>,
+    },
+    /// Change of the timer of disappearing messages of the channel from `previous`
+    ExpireTimer {
+        channel_id: ChannelId,
+        previous: Option<u32>,
+        result: anyhow::Result<()>,
+    },
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -78,12 +173,15 @@ impl ReceiptHandler {
         true
     }
 
-    pub fn step(&mut self, signal_manager: &dyn SignalManager) -> bool {
+    /// Takes the receipts queued for one contact.
+    ///
+    /// Returns the contact, the timestamps of the messages and the receipt to send, if any.
+    pub fn step(&mut self) -> Option<(Uuid, Vec<u64>, Receipt)> {
         if !self.do_tick() {
-            return false;
+            return None;
         }
         if self.receipt_set.is_empty() {
-            return false;
+            return None;
         }
 
         // Get any key
@@ -94,16 +192,15 @@ impl ReceiptHandler {
             Entry::Occupied(mut e) => {
                 let u = e.get_mut();
                 if let Some((timestamps, receipt)) = u.get_data() {
-                    signal_manager.send_receipt(uuid, timestamps, receipt);
                     if u.is_empty() {
                         e.remove_entry();
                     }
-                    true
+                    Some((uuid, timestamps, receipt))
                 } else {
-                    false
+                    None
                 }
             }
-            Entry::Vacant(_) => false,
+            Entry::Vacant(_) => None,
         }
     }
 }
@@ -186,6 +283,8 @@ impl ReceiptQueues {
 pub struct BoxData {
     pub data: String,
     pub cursor: Cursor,
+    /// Names completed after `@` in the data, together with the mentioned users
+    pub mentions: Vec<(String, Uuid)>,
 }
 
 impl BoxData {
@@ -248,6 +347,7 @@ impl BoxData {
 
     fn take(&mut self) -> String {
         self.cursor = Default::default();
+        self.mentions.clear();
         std::mem::take(&mut self.data)
     }
 }
@@ -256,16 +356,52 @@ impl BoxData {
 pub struct AppData {
     pub channels: FilteredStatefulList<Channel>,
     pub names: HashMap<Uuid, String>,
+    /// Contacts synced from the primary device
+    #[serde(default)]
+    pub contacts: HashMap<Uuid, Contact>,
+    /// Users and groups whose messages are dropped
+    #[serde(default)]
+    pub blocked: HashSet<ChannelId>,
     #[serde(skip)] // ! We may want to save it
     pub input: BoxData,
     #[serde(skip)]
     pub search_box: BoxData,
     #[serde(skip)]
     pub is_multiline_input: bool,
+    /// Messages waiting to be sent, in the order they were composed
+    #[serde(default)]
+    pub outbox: Vec<QueuedMessage>,
+}
+
+impl AppData {
+    /// Finds the loaded message which arrived at `arrived_at` in the channel.
+    fn message_mut(&mut self, channel_id: ChannelId, arrived_at: u64) -> Option<&mut Message> {
+        self.channels
+            .items
+            .iter_mut()
+            .find(|channel| channel.id == channel_id)?
+            .messages
+            .items
+            .iter_mut()
+            .rev()
+            .find(|message| message.arrived_at == arrived_at)
+    }
+}
+
+/// Message waiting in the outbox until gurk is online again
+///
+/// The message is also shown in its channel with the receipt `Queued` until it is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub channel_id: ChannelId,
+    pub message: Message,
+    /// Number of times sending the message failed already
+    #[serde(default)]
+    pub attempts: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(try_from = "JsonChannel")]
+#[serde(from = "JsonChannel")]
 pub struct Channel {
     pub id: ChannelId,
     pub name: String,
@@ -274,6 +410,8 @@ pub struct Channel {
     pub messages: StatefulList<Message>,
     pub unread_messages: usize,
     pub typing: TypingSet,
+    /// Time in seconds after which new messages disappear, if enabled
+    pub expire_timer: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -284,7 +422,8 @@ pub enum TypingSet {
 
 /// Proxy type which allows us to apply post-deserialization conversion.
 ///
-/// Used to migrate the schema. Change this type only in backwards-compatible way.
+/// Used to initialize the fields which are not stored. Change this type only in
+/// backwards-compatible way; schema changes are migrated in `storage::migration`.
 #[derive(Deserialize)]
 pub struct JsonChannel {
     pub id: ChannelId,
@@ -295,13 +434,14 @@ pub struct JsonChannel {
     pub messages: StatefulList<Message>,
     #[serde(default)]
     pub unread_messages: usize,
+    #[serde(default)]
+    pub expire_timer: Option<u32>,
 }
 
-impl TryFrom<JsonChannel> for Channel {
-    type Error = anyhow::Error;
-    fn try_from(channel: JsonChannel) -> anyhow::Result<Self> {
+impl From<JsonChannel> for Channel {
+    fn from(channel: JsonChannel) -> Self {
         let is_group = channel.group_data.is_some();
-        let mut channel = Channel {
+        Channel {
             id: channel.id,
             name: channel.name,
             group_data: channel.group_data,
@@ -314,22 +454,23 @@ impl TryFrom<JsonChannel> for Channel {
                     TypingSet::SingleTyping(false)
                 }
             },
-        };
-
-        // 1. The master key in ChannelId::Group was replaced by group identifier,
-        // the former was stored in group_data.
-        match (channel.id, channel.group_data.as_mut()) {
-            (ChannelId::Group(id), Some(group_data)) if group_data.master_key_bytes == [0; 32] => {
-                group_data.master_key_bytes = id;
-                channel.id = ChannelId::from_master_key_bytes(id)?;
-            }
-            _ => (),
+            expire_timer: channel.expire_timer,
         }
-        Ok(channel)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Contact synced from the primary device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    /// Phone number in E.164 format, if known
+    pub phone_number: Option<String>,
+    /// Name of the contact in the address book of the primary device, possibly empty
+    pub name: String,
+    pub profile_key: Vec<u8>,
+    pub blocked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GroupData {
     #[serde(default)]
     pub master_key_bytes: GroupMasterKeyBytes,
@@ -380,6 +521,31 @@ impl Channel {
         }
     }
 
+    /// Removes the messages which arrived before `arrived_before`, or which are not among the
+    /// latest `keep` messages, and returns them.
+    fn prune_messages(&mut self, arrived_before: Option<u64>, keep: Option<usize>) -> Vec<Message> {
+        let items = &self.messages.items;
+        let num_pruned_by_count = keep.map_or(0, |keep| items.len().saturating_sub(keep));
+        let num_pruned_by_age = arrived_before.map_or(0, |arrived_before| {
+            items
+                .iter()
+                .take_while(|message| message.arrived_at < arrived_before)
+                .count()
+        });
+        let pruned = self
+            .messages
+            .items
+            .drain(..num_pruned_by_count.max(num_pruned_by_age))
+            .collect();
+
+        // the selection is counted from the newest message
+        let len = self.messages.items.len();
+        if matches!(self.messages.state.selected(), Some(idx) if idx >= len) {
+            self.messages.state.select(len.checked_sub(1));
+        }
+        pruned
+    }
+
     fn selected_message(&self) -> Option<&Message> {
         // Messages are shown in reversed order => selected is reversed
         self.messages
@@ -406,7 +572,7 @@ impl Channel {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ChannelId {
     User(Uuid),
     Group(GroupIdentifierBytes),
@@ -419,7 +585,7 @@ impl From<Uuid> for ChannelId {
 }
 
 impl ChannelId {
-    fn from_master_key_bytes(bytes: impl AsRef<[u8]>) -> anyhow::Result<Self> {
+    pub fn from_master_key_bytes(bytes: impl AsRef<[u8]>) -> anyhow::Result<Self> {
         let master_key_ar = bytes
             .as_ref()
             .try_into()
@@ -448,10 +614,23 @@ impl TypingAction {
             }
         }
     }
+
+    pub fn to_i32(self) -> i32 {
+        match self {
+            Self::Started => 0,
+            Self::Stopped => 1,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Receipt {
+    /// The message waits in the outbox until gurk is online again
+    Queued = -5,
+    /// Sending the message failed
+    Failed = -4,
+    /// The message is being sent
+    Pending = -3,
     Sent = -1,
     Delivered = 0,
     Read = 1,
@@ -495,6 +674,39 @@ pub struct Message {
     pub reactions: Vec<(Uuid, String)>,
     #[serde(default)]
     pub receipt: Receipt,
+    /// Whether the message was deleted for everyone by its author; its content is removed
+    #[serde(default)]
+    pub deleted: bool,
+    /// Time in milliseconds since the epoch at which the message disappears, if ever
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Mentions of users in the text, ordered by their position
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+    /// Change of the group, if this is a system message about it
+    #[serde(default)]
+    pub group_change: Option<GroupChange>,
+}
+
+/// Change of a group between two of its revisions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupChange {
+    MemberAdded(Uuid),
+    MemberRemoved(Uuid),
+    TitleChanged(String),
+    /// Timer of disappearing messages in seconds, if enabled
+    ExpireTimerChanged(Option<u32>),
+}
+
+/// Character standing in for a mentioned user in the text of a message
+pub const MENTION_PLACEHOLDER: char = '\u{fffc}';
+
+/// Mention of a user in the text of a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    /// Byte index of the placeholder of the mention in the text
+    pub start: usize,
+    pub uuid: Uuid,
 }
 
 impl Message {
@@ -512,23 +724,82 @@ impl Message {
             attachments,
             reactions: Default::default(),
             receipt: Receipt::Sent,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
         }
     }
 
     pub fn from_quote(quote: Quote) -> Option<Message> {
+        let (message, mentions) = mentions_from_body_ranges(quote.text, &quote.body_ranges);
         Some(Message {
             from_id: quote.author_uuid?.parse().ok()?,
-            message: quote.text,
+            message,
             arrived_at: quote.id?,
             quote: None,
             attachments: Default::default(),
             reactions: Default::default(),
             receipt: Receipt::Sent,
+            deleted: false,
+            expires_at: None,
+            mentions,
+            group_change: None,
         })
     }
 
+    /// Text of the message with each mention replaced by `@` and the name of the mentioned user.
+    pub fn text_with_mentions(&self, mut name: impl FnMut(Uuid) -> String) -> Option<String> {
+        let text = self.message.as_ref()?;
+        let mut out = String::with_capacity(text.len());
+        let mut offset = 0;
+        for mention in &self.mentions {
+            let is_placeholder = mention.start >= offset
+                && matches!(
+                    text.get(mention.start..),
+                    Some(rest) if rest.starts_with(MENTION_PLACEHOLDER)
+                );
+            if !is_placeholder {
+                continue;
+            }
+            out.push_str(&text[offset..mention.start]);
+            out.push('@');
+            out.push_str(&name(mention.uuid));
+            offset = mention.start + MENTION_PLACEHOLDER.len_utf8();
+        }
+        out.push_str(&text[offset..]);
+        Some(out)
+    }
+
+    /// System message about the change of the group, which arrived at `arrived_at`
+    pub fn group_change(change: GroupChange, arrived_at: u64) -> Self {
+        Self {
+            group_change: Some(change),
+            ..Self::new(Uuid::nil(), None, arrived_at, Vec::new())
+        }
+    }
+
+    pub fn is_mentioning(&self, uuid: Uuid) -> bool {
+        self.mentions.iter().any(|mention| mention.uuid == uuid)
+    }
+
+    /// Whether the disappearing message expired at `now`
+    ///
+    /// Our messages which are not sent yet are kept until they are sent.
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+            && !matches!(
+                self.receipt,
+                Receipt::Pending | Receipt::Failed | Receipt::Queued
+            )
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.message.is_none() && self.attachments.is_empty() && self.reactions.is_empty()
+        !self.deleted
+            && self.group_change.is_none()
+            && self.message.is_none()
+            && self.attachments.is_empty()
+            && self.reactions.is_empty()
     }
 }
 
@@ -539,9 +810,16 @@ pub enum Event {
     Click(MouseEvent),
     Input(KeyEvent),
     Message(Content),
-    Resize { cols: u16, rows: u16 },
+    Resize {
+        cols: u16,
+        rows: u16,
+    },
     Quit(Option<anyhow::Error>),
     Tick,
+    /// Connected to Signal and listening for incoming messages
+    Connected,
+    /// Lost the connection to Signal
+    Disconnected,
 }
 
 impl App {
@@ -552,7 +830,10 @@ impl App {
     ) -> anyhow::Result<Self> {
         let user_id = signal_manager.user_id();
         let data = storage.load_app_data(user_id, config.user.name.clone())?;
-        Ok(Self {
+        let attachment_store = AttachmentStore::from_config(&config)?;
+        let (downloads_tx, downloads_rx) = mpsc::unbounded_channel();
+        let (sends_tx, sends_rx) = mpsc::unbounded_channel();
+        let mut app = Self {
             config,
             signal_manager,
             storage,
@@ -565,7 +846,25 @@ impl App {
             is_searching: false,
             channel_text_width: 0,
             receipt_handler: ReceiptHandler::new(),
-        })
+            fully_loaded_channels: Default::default(),
+            last_retention_at: Instant::now(),
+            is_online: false,
+            last_flush_at: Instant::now(),
+            sync_requested: false,
+            typing_input: String::new(),
+            typing_started: None,
+            send_attempts: Default::default(),
+            attachment_store,
+            downloads_tx,
+            downloads_rx,
+            sends_tx,
+            sends_rx,
+            emoji_replacer: Replacer::new(),
+        };
+        app.apply_retention()?;
+        app.remove_expired_stored_messages()?;
+        app.queue_interrupted_sends()?;
+        Ok(app)
     }
 
     pub fn get_input(&mut self) -> &mut BoxData {
@@ -599,8 +898,15 @@ impl App {
         )
     }
 
-    pub fn save(&self) -> anyhow::Result<()> {
-        self.storage.save_app_data(&self.data)
+    /// Saves the metadata of the selected channel.
+    pub fn save_selected_channel(&self) -> anyhow::Result<()> {
+        match self.data.channels.state.selected() {
+            Some(idx) => {
+                let channel_id = self.data.channels.items[idx].id;
+                self.storage.update_channel(&self.data, channel_id)
+            }
+            None => Ok(()),
+        }
     }
 
     pub fn name_by_id(&self, id: Uuid) -> &str {
@@ -641,10 +947,31 @@ impl App {
                 self.get_input().on_backspace();
             }
             KeyCode::Esc => self.reset_message_selection(),
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.retry_failed_message()?;
+            }
+            KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.take_failed_message()?;
+            }
+            KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::ALT) => {
+                self.delete_selected_message();
+            }
+            KeyCode::Char('x') if key.modifiers.contains(KeyModifiers::ALT) => {
+                if let Some(idx) = self.data.channels.state.selected() {
+                    self.toggle_blocked(self.data.channels.filtered_items[idx]);
+                }
+            }
+            KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::ALT) => {
+                if let Some(idx) = self.data.channels.state.selected() {
+                    self.cycle_expire_timer(self.data.channels.filtered_items[idx]);
+                }
+            }
             KeyCode::Char(c) => self.get_input().put_char(c),
             KeyCode::Tab => {
                 if let Some(idx) = self.data.channels.state.selected() {
-                    self.add_reaction(idx);
+                    if !self.complete_mention(idx) {
+                        self.add_reaction(idx);
+                    }
                 }
             }
             _ => {}
@@ -665,6 +992,46 @@ impl App {
         Some(())
     }
 
+    /// Completes the name after `@` before the cursor to the name of the first member of the
+    /// group channel starting with it, ignoring case.
+    ///
+    /// Returns whether a name was completed.
+    fn complete_mention(&mut self, channel_idx: usize) -> bool {
+        let members = match self.data.channels.items[channel_idx].group_data.as_ref() {
+            Some(group_data) => &group_data.members,
+            None => return false,
+        };
+        let input = &self.data.input;
+        let before_cursor = &input.data[..input.cursor.idx];
+        let at = match before_cursor.rfind('@') {
+            Some(at) => at,
+            None => return false,
+        };
+        let prefix = before_cursor[at + 1..].to_lowercase();
+        let in_word = before_cursor[..at].ends_with(|c: char| !c.is_whitespace());
+        if in_word || prefix.contains(char::is_whitespace) {
+            return false;
+        }
+        let mention = members
+            .iter()
+            .filter(|&&uuid| uuid != self.user_id)
+            .map(|&uuid| (name_by_id(&self.data.names, uuid), uuid))
+            .find(|(name, _)| name.to_lowercase().starts_with(&prefix));
+        let (name, uuid) = match mention {
+            Some((name, uuid)) => (name.to_string(), uuid),
+            None => return false,
+        };
+
+        let input = &mut self.data.input;
+        let completion = format!("{} ", name);
+        input
+            .data
+            .replace_range(at + 1..input.cursor.idx, &completion);
+        input.cursor = Cursor::at(&input.data, at + 1 + completion.len());
+        input.mentions.push((name, uuid));
+        true
+    }
+
     /// Returns Some(_) reaction if input is a reaction.
     ///
     /// Inner is None, if the reaction should be removed.
@@ -682,7 +1049,9 @@ impl App {
     pub fn add_reaction(&mut self, channel_idx: usize) -> Option<()> {
         let reaction = self.take_reaction()?;
         let channel = &self.data.channels.items[channel_idx];
-        let message = channel.selected_message()?;
+        let message = channel
+            .selected_message()
+            .filter(|message| message.group_change.is_none())?;
         let remove = reaction.is_none();
         let emoji = reaction.or_else(|| {
             // find emoji which should be removed
@@ -696,11 +1065,25 @@ impl App {
             })
         })?;
 
-        self.signal_manager
+        let sending = self
+            .signal_manager
             .send_reaction(channel, message, emoji.clone(), remove);
-
         let channel_id = channel.id;
         let arrived_at = message.arrived_at;
+        let sends_tx = self.sends_tx.clone();
+        let sent_emoji = emoji.clone();
+        tokio::task::spawn_local(async move {
+            let result = sending.await;
+            // fails only if the app is gone
+            let _ = sends_tx.send(FinishedSend::Reaction {
+                channel_id,
+                arrived_at,
+                emoji: sent_emoji,
+                remove,
+                result,
+            });
+        });
+
         self.handle_reaction(
             channel_id,
             arrived_at,
@@ -714,10 +1097,87 @@ impl App {
         self.bubble_up_channel(channel_idx);
         self.reset_message_selection();
 
-        self.save().unwrap();
+        self.storage.update_channel(&self.data, channel_id).unwrap();
+        Some(())
+    }
+
+    /// Deletes the selected message for everyone, if it was sent by us within the period allowed
+    /// by Signal.
+    ///
+    /// The message is replaced by a tombstone as soon as the deletion is sent.
+    fn delete_selected_message(&mut self) -> Option<()> {
+        let channel_idx = self.data.channels.state.selected()?;
+        let channel = &self.data.channels.items[channel_idx];
+        let message = channel.selected_message()?;
+        let is_sent = !matches!(
+            message.receipt,
+            Receipt::Pending | Receipt::Failed | Receipt::Queued
+        );
+        let age = util::utc_now_timestamp_msec().saturating_sub(message.arrived_at);
+        if message.from_id != self.user_id
+            || message.deleted
+            || !is_sent
+            || age > DELETE_FOR_EVERYONE_PERIOD_MSEC
+        {
+            return None;
+        }
+
+        let sending = self.signal_manager.send_delete(channel, message);
+        let channel_id = channel.id;
+        let arrived_at = message.arrived_at;
+        let sends_tx = self.sends_tx.clone();
+        tokio::task::spawn_local(async move {
+            let result = sending.await;
+            // fails only if the app is gone
+            let _ = sends_tx.send(FinishedSend::Delete {
+                channel_id,
+                arrived_at,
+                result,
+            });
+        });
+
+        self.reset_message_selection();
         Some(())
     }
 
+    /// Switches the timer of disappearing messages of the contact channel to the next longer
+    /// preset, or disables it after the longest one.
+    ///
+    /// The timer is reset if the change fails to send. The timer of a group can only be changed
+    /// by a group change, which is not supported.
+    fn cycle_expire_timer(&mut self, channel_idx: usize) {
+        let channel = &mut self.data.channels.items[channel_idx];
+        if let ChannelId::Group(_) = channel.id {
+            log::warn!("the timer of disappearing messages of a group cannot be changed in gurk");
+            return;
+        }
+        let previous = channel.expire_timer;
+        channel.expire_timer = match previous {
+            None => EXPIRE_TIMER_PRESETS.first().copied(),
+            Some(timer) => EXPIRE_TIMER_PRESETS
+                .iter()
+                .copied()
+                .find(|&preset| preset > timer),
+        };
+
+        let sending = self
+            .signal_manager
+            .send_expire_timer(channel, channel.expire_timer);
+        let channel_id = channel.id;
+        let sends_tx = self.sends_tx.clone();
+        tokio::task::spawn_local(async move {
+            let result = sending.await;
+            // fails only if the app is gone
+            let _ = sends_tx.send(FinishedSend::ExpireTimer {
+                channel_id,
+                previous,
+                result,
+            });
+        });
+
+        self.storage.update_channel(&self.data, channel_id).unwrap();
+    }
+
     fn reset_message_selection(&mut self) {
         if let Some(idx) = self.data.channels.state.selected() {
             let channel = &mut self.data.channels.items[idx];
@@ -730,65 +1190,509 @@ impl App {
         self.get_input().take()
     }
 
+    /// Sends the input to the channel, or queues it in the outbox while offline.
+    ///
+    /// The attachments are kept in the attachment store, s.t. they can be sent again if sending
+    /// fails.
     fn send_input(&mut self, channel_idx: usize) -> anyhow::Result<()> {
+        let completed_mentions = self.data.input.mentions.clone();
         let input = self.take_input();
         let (input, attachments) = self.extract_attachments(&input);
-        let channel = &mut self.data.channels.items[channel_idx];
-        let quote = channel.selected_message();
-        let sent_message = self
+        let channel = &self.data.channels.items[channel_idx];
+        let channel_id = channel.id;
+        let saved_attachments = self.save_outgoing_attachments(channel_id, &attachments);
+        let message = self.new_message(channel, input, &completed_mentions, saved_attachments);
+        let message = if self.is_online {
+            self.start_sending(channel_idx, message, attachments)
+        } else {
+            let message = Message {
+                receipt: Receipt::Queued,
+                ..message
+            };
+            self.queue_message(channel_id, message.clone(), 0)?;
+            message
+        };
+        self.add_sent_message(channel_idx, message)
+    }
+
+    /// New message of the user with the text to the channel, which arrives now
+    ///
+    /// The message quotes the selected message of the channel, if any, and mentions the users
+    /// whose names were completed in the text.
+    fn new_message(
+        &self,
+        channel: &Channel,
+        text: String,
+        completed_mentions: &[(String, Uuid)],
+        attachments: Vec<Attachment>,
+    ) -> Message {
+        let text = self.emoji_replacer.replace_all(&text);
+        let (text, mentions) = insert_mentions(&text, completed_mentions);
+        let arrived_at = util::utc_now_timestamp_msec();
+        Message {
+            from_id: self.user_id,
+            message: if text.is_empty() { None } else { Some(text) },
+            arrived_at,
+            quote: channel
+                .selected_message()
+                .filter(|quote| quote.group_change.is_none())
+                .map(|quote| {
+                    Box::new(Message {
+                        mentions: quote.mentions.clone(),
+                        ..Message::new(
+                            quote.from_id,
+                            quote.message.clone(),
+                            quote.arrived_at,
+                            Vec::new(),
+                        )
+                    })
+                }),
+            attachments,
+            reactions: Default::default(),
+            receipt: Receipt::Pending,
+            deleted: false,
+            expires_at: expires_at(arrived_at, channel.expire_timer),
+            mentions,
+            group_change: None,
+        }
+    }
+
+    /// Starts sending the message to the channel in the background, and returns it as pending.
+    fn start_sending(
+        &self,
+        channel_idx: usize,
+        message: Message,
+        attachments: Vec<(AttachmentSpec, Vec<u8>)>,
+    ) -> Message {
+        let channel = &self.data.channels.items[channel_idx];
+        let sending = self
             .signal_manager
-            .send_text(channel, input, quote, attachments);
+            .send_text(channel, &message, attachments);
+        let sends_tx = self.sends_tx.clone();
+        let (channel_id, arrived_at) = (channel.id, message.arrived_at);
+        tokio::task::spawn_local(async move {
+            let result = sending.await;
+            // fails only if the app is gone
+            let _ = sends_tx.send(FinishedSend::Message {
+                channel_id,
+                arrived_at,
+                result,
+            });
+        });
+        Message {
+            receipt: Receipt::Pending,
+            ..message
+        }
+    }
+
+    /// Saves the attachments to send in the attachment store.
+    ///
+    /// Attachments which cannot be saved are sent anyway, but are not shown and not sent again.
+    fn save_outgoing_attachments(
+        &self,
+        channel_id: ChannelId,
+        attachments: &[(AttachmentSpec, Vec<u8>)],
+    ) -> Vec<Attachment> {
+        attachments
+            .iter()
+            .filter_map(|(spec, data)| {
+                let saved = self.attachment_store.save(
+                    channel_id,
+                    data,
+                    spec.file_name.as_deref(),
+                    Some(&spec.content_type),
+                );
+                match saved {
+                    Ok((id, filename)) => Some(Attachment {
+                        id,
+                        content_type: spec.content_type.clone(),
+                        filename,
+                        size: data.len() as u32,
+                        download: None,
+                    }),
+                    Err(e) => {
+                        log::error!("failed to save sent attachment: {:#}", e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Sends the selected message again, if it failed to send or is queued.
+    ///
+    /// The unsent message is replaced by the new one.
+    fn retry_failed_message(&mut self) -> anyhow::Result<()> {
+        let (channel_idx, failed_message) = match self.take_failed_message()? {
+            Some(failed) => failed,
+            None => return Ok(()),
+        };
+        let channel_id = self.data.channels.items[channel_idx].id;
+        let message = self.resend_message(channel_id, failed_message, 0)?;
+        self.add_sent_message(channel_idx, message)
+    }
+
+    /// Sends the unsent message again, or queues it in the outbox while offline.
+    ///
+    /// `attempts` is the number of times sending the message failed already.
+    fn resend_message(
+        &mut self,
+        channel_id: ChannelId,
+        unsent_message: Message,
+        attempts: u32,
+    ) -> anyhow::Result<Message> {
+        let message = Message {
+            arrived_at: util::utc_now_timestamp_msec(),
+            reactions: Default::default(),
+            ..unsent_message
+        };
+        if !self.is_online {
+            let message = Message {
+                receipt: Receipt::Queued,
+                ..message
+            };
+            self.queue_message(channel_id, message.clone(), attempts)?;
+            return Ok(message);
+        }
+
+        let attachments = message
+            .attachments
+            .iter()
+            .filter_map(|attachment| match read_attachment(attachment) {
+                Ok(attachment) => Some(attachment),
+                Err(e) => {
+                    log::error!("failed to read attachment to send: {:#}", e);
+                    None
+                }
+            })
+            .collect();
+        let channel_idx = self
+            .channel_idx(channel_id)
+            .ok_or_else(|| anyhow!("no channel {:?}", channel_id))?;
+        if attempts > 0 {
+            self.send_attempts
+                .insert((channel_id, message.arrived_at), attempts);
+        }
+        Ok(self.start_sending(channel_idx, message, attachments))
+    }
+
+    /// Puts the message into the outbox.
+    ///
+    /// The message is not added to its channel.
+    fn queue_message(
+        &mut self,
+        channel_id: ChannelId,
+        message: Message,
+        attempts: u32,
+    ) -> anyhow::Result<()> {
+        self.data.outbox.push(QueuedMessage {
+            channel_id,
+            message,
+            attempts,
+        });
+        self.storage.update_outbox(&self.data)
+    }
+
+    /// Removes the selected message from the selected channel, if it failed to send or is queued,
+    /// and returns it together with the index of the channel.
+    fn take_failed_message(&mut self) -> anyhow::Result<Option<(usize, Message)>> {
+        let channel_idx = match self.data.channels.state.selected() {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let channel = &mut self.data.channels.items[channel_idx];
+        // messages are shown in reversed order => selected is reversed
+        let message_idx = match channel.messages.state.selected() {
+            Some(idx) => channel.messages.items.len() - 1 - idx,
+            None => return Ok(None),
+        };
+        if !matches!(
+            channel.messages.items[message_idx].receipt,
+            Receipt::Failed | Receipt::Queued
+        ) {
+            return Ok(None);
+        }
+        let message = channel.messages.items.remove(message_idx);
+        let channel_id = channel.id;
+        self.reset_message_selection();
+        self.storage
+            .delete_message(&self.data, channel_id, &message)?;
+        if message.receipt == Receipt::Queued {
+            self.data.outbox.retain(|queued| {
+                queued.channel_id != channel_id || queued.message.arrived_at != message.arrived_at
+            });
+            self.storage.update_outbox(&self.data)?;
+        }
+        Ok(Some((channel_idx, message)))
+    }
 
+    fn add_sent_message(
+        &mut self,
+        channel_idx: usize,
+        sent_message: Message,
+    ) -> anyhow::Result<()> {
         let sent_with_quote = sent_message.quote.is_some();
-        channel.messages.items.push(sent_message);
+        self.push_sent_message(channel_idx, sent_message)?;
 
         self.reset_unread_messages();
         if sent_with_quote {
             self.reset_message_selection();
         }
+        let channel_id = self.data.channels.items[channel_idx].id;
         self.bubble_up_channel(channel_idx);
-        self.save()
+        self.storage.update_channel(&self.data, channel_id)
+    }
+
+    /// Appends the sent message to the channel, keeping the selected message.
+    fn push_sent_message(
+        &mut self,
+        channel_idx: usize,
+        sent_message: Message,
+    ) -> anyhow::Result<()> {
+        let channel = &mut self.data.channels.items[channel_idx];
+        channel.messages.items.push(sent_message);
+        if let Some(idx) = channel.messages.state.selected() {
+            channel.messages.state.select(Some(idx + 1));
+        }
+        let channel_id = channel.id;
+        self.storage.append_message(&self.data, channel_id)
     }
 
     pub fn select_previous_channel(&mut self) {
         if self.reset_unread_messages() {
-            self.save().unwrap();
+            self.save_selected_channel().unwrap();
         }
         self.data.channels.previous();
     }
 
     pub fn select_next_channel(&mut self) {
         if self.reset_unread_messages() {
-            self.save().unwrap();
+            self.save_selected_channel().unwrap();
         }
         self.data.channels.next();
     }
 
-    pub fn on_pgup(&mut self) {
-        let select = self.data.channels.state.selected().unwrap_or_default();
-        self.data.channels.items[select].messages.next();
-    }
+    /// Sends the typing indicators for the input to the selected channel.
+    ///
+    /// The start of typing is sent when the input changes, at most once per
+    /// `TYPING_STARTED_PERIOD`. The stop is sent when the input is cleared or sent, or another
+    /// channel is selected. Nothing is sent to groups with more than `MAX_TYPING_GROUP_SIZE`
+    /// members.
+    pub fn update_typing(&mut self) {
+        let input_changed = self.typing_input != self.data.input.data;
+        if input_changed {
+            self.typing_input = self.data.input.data.clone();
+        }
 
-    pub fn on_pgdn(&mut self) {
-        let select = self.data.channels.state.selected().unwrap_or_default();
-        self.data.channels.items[select].messages.previous();
-    }
+        let channels = &self.data.channels;
+        let typing_in = channels
+            .state
+            .selected()
+            .and_then(|idx| channels.filtered_items.get(idx))
+            .map(|&channel_idx| &channels.items[channel_idx])
+            .filter(|channel| {
+                let group_size = channel
+                    .group_data
+                    .as_ref()
+                    .map(|group_data| group_data.members.len())
+                    .unwrap_or_default();
+                self.config.send_typing
+                    && !self.data.input.data.is_empty()
+                    && !self.data.blocked.contains(&channel.id)
+                    && group_size <= MAX_TYPING_GROUP_SIZE
+            })
+            .map(|channel| channel.id);
 
-    pub fn reset_unread_messages(&mut self) -> bool {
-        if let Some(selected_idx) = self.data.channels.state.selected() {
-            if self.data.channels.items[selected_idx].unread_messages > 0 {
-                self.data.channels.items[selected_idx].unread_messages = 0;
-                return true;
+        if let Some((channel_id, _)) = self.typing_started {
+            if typing_in != Some(channel_id) {
+                self.typing_started = None;
+                self.send_typing(channel_id, TypingAction::Stopped);
             }
         }
-        false
-    }
-
+        if let Some(channel_id) = typing_in {
+            let is_due = match self.typing_started {
+                Some((_, started_at)) => started_at.elapsed() >= TYPING_STARTED_PERIOD,
+                None => true,
+            };
+            if input_changed && is_due {
+                self.typing_started = Some((channel_id, Instant::now()));
+                self.send_typing(channel_id, TypingAction::Started);
+            }
+        }
+    }
+
+    fn send_typing(&self, channel_id: ChannelId, action: TypingAction) {
+        let channel_idx = match self.channel_idx(channel_id) {
+            Some(idx) if self.is_online => idx,
+            _ => return,
+        };
+        let channel = &self.data.channels.items[channel_idx];
+        let sending = self.signal_manager.send_typing(channel, action);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = sending.await {
+                log::error!("{:#}", e);
+            }
+        });
+    }
+
+    pub fn on_pgup(&mut self) {
+        let select = self.data.channels.state.selected().unwrap_or_default();
+        let messages = &mut self.data.channels.items[select].messages;
+        messages.next();
+        if messages.state.selected() == messages.items.len().checked_sub(1) {
+            // reached the oldest loaded message
+            self.load_older_messages(select);
+        }
+        self.download_selected_attachments();
+    }
+
+    /// Loads the next page of older messages of the channel from the storage, unless all
+    /// messages are loaded already.
+    ///
+    /// The older messages are prepended. Since the selected message and the rendered offset
+    /// are counted from the newest message, both stay valid.
+    fn load_older_messages(&mut self, channel_idx: usize) {
+        let channel = &mut self.data.channels.items[channel_idx];
+        if self.fully_loaded_channels.contains(&channel.id) {
+            return;
+        }
+        let oldest_arrived_at = match channel.messages.items.first() {
+            Some(message) => message.arrived_at,
+            None => {
+                self.fully_loaded_channels.insert(channel.id);
+                return;
+            }
+        };
+        match self
+            .storage
+            .load_older_messages(channel.id, oldest_arrived_at, MESSAGES_PAGE_SIZE)
+        {
+            Ok(older_messages) => {
+                if older_messages.len() < MESSAGES_PAGE_SIZE {
+                    self.fully_loaded_channels.insert(channel.id);
+                }
+                channel.messages.items.splice(0..0, older_messages);
+            }
+            Err(e) => log::error!("failed to load older messages: {}", e),
+        }
+    }
+
+    pub fn on_pgdn(&mut self) {
+        let select = self.data.channels.state.selected().unwrap_or_default();
+        self.data.channels.items[select].messages.previous();
+        self.download_selected_attachments();
+    }
+
+    pub fn reset_unread_messages(&mut self) -> bool {
+        if let Some(selected_idx) = self.data.channels.state.selected() {
+            if self.data.channels.items[selected_idx].unread_messages > 0 {
+                self.data.channels.items[selected_idx].unread_messages = 0;
+                return true;
+            }
+        }
+        false
+    }
+
     pub async fn on_message(&mut self, content: Content) -> anyhow::Result<()> {
         // log::debug!("incoming: {:#?}", content);
         let user_id = self.user_id;
 
+        if self.is_blocked(content.metadata.sender.uuid, &content.body) {
+            log::debug!("dropping content from blocked user or group");
+            return Ok(());
+        }
+
         let (channel_idx, message) = match (content.metadata, content.body) {
+            // Blocked users and groups synced from the primary device
+            (
+                _,
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    blocked: Some(blocked),
+                    ..
+                }),
+            ) => {
+                self.sync_blocked(blocked);
+                return Ok(());
+            }
+            // Contacts synced from the primary device
+            (
+                _,
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    contacts: Some(_), ..
+                }),
+            ) => {
+                self.sync_contacts().await?;
+                return Ok(());
+            }
+            // Message deleted by us from a different device
+            (
+                _,
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    sent:
+                        Some(Sent {
+                            destination_uuid,
+                            message:
+                                Some(DataMessage {
+                                    delete:
+                                        Some(Delete {
+                                            target_sent_timestamp: Some(target_sent_timestamp),
+                                        }),
+                                    group_v2,
+                                    ..
+                                }),
+                            ..
+                        }),
+                    ..
+                }),
+            ) => {
+                let channel_id = if let Some(GroupContextV2 {
+                    master_key: Some(master_key),
+                    ..
+                }) = group_v2
+                {
+                    ChannelId::from_master_key_bytes(master_key)?
+                } else if let Some(uuid) = destination_uuid {
+                    ChannelId::User(uuid.parse()?)
+                } else {
+                    log::warn!("unhandled deletion from us");
+                    return Ok(());
+                };
+
+                self.handle_remote_delete(channel_id, target_sent_timestamp, user_id);
+                return Ok(());
+            }
+            // Message deleted by its sender
+            (
+                Metadata {
+                    sender:
+                        ServiceAddress {
+                            uuid: Some(sender_uuid),
+                            ..
+                        },
+                    ..
+                },
+                ContentBody::DataMessage(DataMessage {
+                    delete:
+                        Some(Delete {
+                            target_sent_timestamp: Some(target_sent_timestamp),
+                        }),
+                    group_v2,
+                    ..
+                }),
+            ) => {
+                let channel_id = if let Some(GroupContextV2 {
+                    master_key: Some(master_key),
+                    ..
+                }) = group_v2
+                {
+                    ChannelId::from_master_key_bytes(master_key)?
+                } else {
+                    ChannelId::User(sender_uuid)
+                };
+
+                self.handle_remote_delete(channel_id, target_sent_timestamp, sender_uuid);
+                return Ok(());
+            }
             // Private note message
             (
                 _,
@@ -809,7 +1713,10 @@ impl App {
                 }),
             ) if destination_uuid.parse() == Ok(user_id) => {
                 let channel_idx = self.ensure_own_channel_exists();
-                let attachments = self.save_attachments(attachment_pointers).await;
+                let attachments = attachment_pointers
+                    .iter()
+                    .map(Attachment::pending)
+                    .collect();
                 let message = Message::new(user_id, body, timestamp, attachments);
                 (channel_idx, message)
             }
@@ -835,6 +1742,9 @@ impl App {
                                     group_v2,
                                     quote,
                                     attachments: attachment_pointers,
+                                    flags,
+                                    expire_timer,
+                                    body_ranges,
                                     ..
                                 }),
                             ..
@@ -868,11 +1778,26 @@ impl App {
                 };
 
                 let quote = quote.and_then(Message::from_quote).map(Box::new);
-                let attachments = self.save_attachments(attachment_pointers).await;
+                let attachments = attachment_pointers
+                    .iter()
+                    .map(Attachment::pending)
+                    .collect();
+                let (body, mentions) = mentions_from_body_ranges(body, &body_ranges);
                 let message = Message {
                     quote,
+                    mentions,
                     ..Message::new(user_id, body, timestamp, attachments)
                 };
+                if is_expire_timer_update(flags) || !message.is_empty() {
+                    self.update_expire_timer(channel_idx, expire_timer);
+                }
+                let message = Message {
+                    expires_at: expires_at(
+                        timestamp,
+                        self.data.channels.items[channel_idx].expire_timer,
+                    ),
+                    ..message
+                };
 
                 (channel_idx, message)
             }
@@ -894,6 +1819,9 @@ impl App {
                     profile_key: Some(profile_key),
                     quote,
                     attachments: attachment_pointers,
+                    flags,
+                    expire_timer,
+                    body_ranges,
                     ..
                 }),
             ) => {
@@ -931,21 +1859,37 @@ impl App {
                     (channel_idx, from)
                 };
 
-                let attachments = self.save_attachments(attachment_pointers).await;
-                self.notify_about_message(&from, body.as_deref(), &attachments);
-
-                // Send "Delivered" receipt
-                self.add_receipt_event(ReceiptEvent::new(uuid, timestamp, Receipt::Delivered));
-
+                let attachments: Vec<Attachment> = attachment_pointers
+                    .iter()
+                    .map(Attachment::pending)
+                    .collect();
                 let quote = quote.and_then(Message::from_quote).map(Box::new);
+                let (body, mentions) = mentions_from_body_ranges(body, &body_ranges);
                 let message = Message {
                     quote,
+                    mentions,
                     ..Message::new(uuid, body, timestamp, attachments)
                 };
+                self.notify_about_message(&from, &message);
+
+                // Send "Delivered" receipt
+                self.add_receipt_event(ReceiptEvent::new(uuid, timestamp, Receipt::Delivered));
+
+                if is_expire_timer_update(flags) || !message.is_empty() {
+                    self.update_expire_timer(channel_idx, expire_timer);
+                }
 
                 if message.is_empty() {
                     return Ok(());
                 }
+                // the timer starts when the message arrives here rather than when it is read
+                let message = Message {
+                    expires_at: expires_at(
+                        util::utc_now_timestamp_msec(),
+                        self.data.channels.items[channel_idx].expire_timer,
+                    ),
+                    ..message
+                };
 
                 (channel_idx, message)
             }
@@ -1101,29 +2045,156 @@ impl App {
             _ => return Ok(()),
         };
 
+        let channel_id = self.data.channels.items[channel_idx].id;
+        let arrived_at = message.arrived_at;
         self.add_message_to_channel(channel_idx, message);
+        self.download_attachments(channel_id, arrived_at, false);
 
         Ok(())
     }
 
-    fn notify_about_message(&mut self, from: &str, body: Option<&str>, attachments: &[Attachment]) {
-        let attachments_text = notification_text_for_attachments(attachments);
-        let notification = [body, attachments_text.as_deref()]
+    /// Notifies about the message, and always if it mentions us.
+    fn notify_about_message(&mut self, from: &str, message: &Message) {
+        let names = &self.data.names;
+        let text = message.text_with_mentions(|uuid| name_by_id(names, uuid).to_string());
+        let attachments_text = notification_text_for_attachments(&message.attachments);
+        let notification = [text.as_deref(), attachments_text.as_deref()]
             .into_iter()
             .flatten()
             .join(" ");
-        if !notification.is_empty() {
+        if message.is_mentioning(self.user_id) {
+            self.notify(&format!("{} mentioned you", from), &notification);
+        } else if !notification.is_empty() {
             self.notify(from, &notification);
         }
     }
 
     pub fn step_receipts(&mut self) -> anyhow::Result<()> {
-        if self.receipt_handler.step(self.signal_manager.as_ref()) {
-            // No need to save if no receipt was sent
-            self.save()
-        } else {
-            Ok(())
+        let (uuid, timestamps, receipt) = match self.receipt_handler.step() {
+            Some(receipts) => receipts,
+            None => return Ok(()),
+        };
+        let sending = self
+            .signal_manager
+            .send_receipt(uuid, timestamps.clone(), receipt);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = sending.await {
+                log::error!("{:#}", e);
+            }
+        });
+
+        // Only read receipts change our data: the messages are marked as read when rendered.
+        if receipt == Receipt::Read {
+            for channel in &self.data.channels.items {
+                let read: Vec<u64> = channel
+                    .messages
+                    .items
+                    .iter()
+                    .filter(|message| {
+                        message.from_id == uuid && timestamps.contains(&message.arrived_at)
+                    })
+                    .map(|message| message.arrived_at)
+                    .collect();
+                if !read.is_empty() {
+                    self.storage
+                        .update_receipts(&self.data, channel.id, &read)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the retention policies and deletes the expired messages from the storage, if this
+    /// was not done within the retention period.
+    pub fn step_retention(&mut self) -> anyhow::Result<()> {
+        if self.last_retention_at.elapsed() >= RETENTION_PERIOD {
+            self.apply_retention()?;
+            self.remove_expired_stored_messages()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the messages exceeding the retention policies from the app data and the storage.
+    ///
+    /// The attachments of the deleted messages are deleted as well, if they are in the attachment
+    /// store and not referenced by the remaining messages.
+    fn apply_retention(&mut self) -> anyhow::Result<()> {
+        self.last_retention_at = Instant::now();
+        let retention = match self.config.retention.as_ref() {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+
+        let now = util::utc_now_timestamp_msec();
+        for channel_idx in 0..self.data.channels.items.len() {
+            let channel = &mut self.data.channels.items[channel_idx];
+            let policy = retention.policy(channel);
+            if policy.is_unlimited() {
+                continue;
+            }
+            let arrived_before = policy
+                .max_age_days
+                .map(|days| now.saturating_sub(days * MSEC_PER_DAY));
+            let mut pruned = channel.prune_messages(arrived_before, policy.max_messages);
+
+            // the storage has to prune as well, if the channel had messages to prune in the app
+            // data, or if it has stored messages which are not loaded
+            let channel_id = channel.id;
+            let has_older_messages = match channel.messages.items.first() {
+                Some(oldest_message) => !self
+                    .storage
+                    .load_older_messages(channel_id, oldest_message.arrived_at, 1)?
+                    .is_empty(),
+                None => false,
+            };
+            if pruned.is_empty() && !has_older_messages {
+                continue;
+            }
+            pruned.extend(self.storage.prune_messages(
+                &self.data,
+                channel_id,
+                arrived_before,
+                policy.max_messages,
+            )?);
+            log::info!(
+                "deleted {} messages exceeding the retention policy",
+                pruned.len()
+            );
+            let pruned_attachments = pruned
+                .into_iter()
+                .flat_map(|message| message.attachments)
+                .map(|attachment| attachment.filename)
+                .collect();
+            self.delete_unreferenced_attachments(channel_idx, pruned_attachments)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the attachment files of the deleted messages of the channel, which are not
+    /// referenced by its remaining messages.
+    ///
+    /// The attachment store keeps a single file per content and channel, which might still back
+    /// other messages of the channel.
+    fn delete_unreferenced_attachments(
+        &self,
+        channel_idx: usize,
+        mut paths: HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let channel = &self.data.channels.items[channel_idx];
+        for message in storage::load_all_messages(self.storage.as_ref(), channel)? {
+            for attachment in &message.attachments {
+                paths.remove(&attachment.filename);
+            }
+        }
+        for path in paths {
+            if let Err(e) = self.attachment_store.delete(&path) {
+                log::warn!("failed to delete attachment: {:#}", e);
+            }
         }
+        Ok(())
     }
 
     fn handle_typing(
@@ -1192,8 +2263,11 @@ impl App {
         Ok(())
     }
 
+    /// Queues the receipt, unless its user is blocked.
     pub fn add_receipt_event(&mut self, event: ReceiptEvent) {
-        self.receipt_handler.add_receipt_event(event);
+        if !self.data.blocked.contains(&ChannelId::User(event.uuid)) {
+            self.receipt_handler.add_receipt_event(event);
+        }
     }
 
     fn handle_receipt(&mut self, sender_uuid: Uuid, receipt: Receipt, mut timestamps: Vec<u64>) {
@@ -1216,9 +2290,8 @@ impl App {
             return;
         }
 
-        let mut is_found = false;
-
         for channel in sender_channels {
+            let mut found_timestamps = Vec::new();
             let mut messages = channel.messages.items.iter_mut().rev();
             for &ts in &timestamps {
                 // Note: `&mut` is needed to advance the iterator `messages` with each `ts`.
@@ -1229,18 +2302,51 @@ impl App {
                     .find(|msg| msg.arrived_at == ts)
                 {
                     msg.receipt = msg.receipt.max(receipt);
-                    is_found = true;
+                    found_timestamps.push(ts);
                 }
             }
 
-            if is_found {
+            if !found_timestamps.is_empty() {
                 // if one ts was found, then all other ts have to be in the same channel
-                self.save().unwrap();
+                let channel_id = channel.id;
+                self.storage
+                    .update_receipts(&self.data, channel_id, &found_timestamps)
+                    .unwrap();
                 return;
             }
         }
     }
 
+    /// Replaces the message which arrived at `target_sent_timestamp` in the channel by a
+    /// tombstone, if it was sent by `author`.
+    ///
+    /// The attachments of the message are left to the garbage collection of the attachment store.
+    fn handle_remote_delete(
+        &mut self,
+        channel_id: ChannelId,
+        target_sent_timestamp: u64,
+        author: Uuid,
+    ) -> Option<()> {
+        let message = self.data.message_mut(channel_id, target_sent_timestamp)?;
+        if message.from_id != author {
+            log::warn!(
+                "ignoring deletion of message {} by somebody else than its author",
+                target_sent_timestamp
+            );
+            return None;
+        }
+        message.message = None;
+        message.quote = None;
+        message.attachments.clear();
+        message.reactions.clear();
+        message.deleted = true;
+
+        self.storage
+            .update_message(&self.data, channel_id, target_sent_timestamp)
+            .unwrap();
+        Some(())
+    }
+
     fn handle_reaction(
         &mut self,
         channel_id: ChannelId,
@@ -1279,26 +2385,32 @@ impl App {
             true
         };
 
-        if is_added && channel_id != ChannelId::User(self.user_id) {
-            // Notification
+        let notification = if is_added && channel_id != ChannelId::User(self.user_id) {
             let sender_name = name_by_id(&self.data.names, sender_uuid);
             let summary = if let ChannelId::Group(_) = channel.id {
-                Cow::from(format!("{} in {}", sender_name, channel.name))
+                format!("{} in {}", sender_name, channel.name)
             } else {
-                Cow::from(sender_name)
+                sender_name.to_string()
             };
             let mut notification = format!("{} reacted {}", summary, emoji);
             if let Some(text) = message.message.as_ref() {
                 notification.push_str(" to: ");
                 notification.push_str(text);
             }
+            Some((summary, notification))
+        } else {
+            None
+        };
+
+        self.storage
+            .update_reactions(&self.data, channel_id, target_sent_timestamp)
+            .unwrap();
+
+        if let Some((summary, notification)) = notification {
             if notify {
                 self.notify(&summary, &notification);
             }
-
             self.touch_channel(channel_idx);
-        } else {
-            self.save().unwrap();
         }
 
         Some(())
@@ -1322,11 +2434,14 @@ impl App {
                 None => true,
             };
             if is_stale {
+                let resolved = self.signal_manager.resolve_group(master_key).await?;
+                let changes = group_changes(&self.data.channels.items[channel_idx], &resolved);
                 let ResolvedGroup {
                     name,
                     group_data,
                     profile_keys,
-                } = self.signal_manager.resolve_group(master_key).await?;
+                    expire_timer,
+                } = resolved;
 
                 self.try_ensure_users_are_known(
                     group_data
@@ -1340,6 +2455,9 @@ impl App {
                 let channel = &mut self.data.channels.items[channel_idx];
                 channel.name = name;
                 channel.group_data = Some(group_data);
+                channel.expire_timer = expire_timer;
+                self.storage.update_channel(&self.data, id)?;
+                self.add_group_changes(channel_idx, changes);
             }
             Ok(channel_idx)
         } else {
@@ -1347,6 +2465,7 @@ impl App {
                 name,
                 group_data,
                 profile_keys,
+                expire_timer,
             } = self.signal_manager.resolve_group(master_key).await?;
 
             self.try_ensure_users_are_known(
@@ -1365,7 +2484,9 @@ impl App {
                 messages: StatefulList::with_items(Vec::new()),
                 unread_messages: 0,
                 typing: TypingSet::GroupTyping(HashSet::new()),
+                expire_timer,
             });
+            self.storage.update_channel(&self.data, id)?;
             Ok(self.data.channels.items.len() - 1)
         }
     }
@@ -1383,6 +2504,7 @@ impl App {
         {
             let phone_number_name = phone_number.format().mode(Mode::E164).to_string();
             self.data.names.insert(uuid, phone_number_name);
+            self.save_name(uuid);
         }
         self.data.names.get(&uuid).unwrap()
     }
@@ -1400,10 +2522,17 @@ impl App {
                 Err(_) => None,
             };
             self.data.names.insert(uuid, name?);
+            self.save_name(uuid);
         }
         self.data.names.get(&uuid).map(|s| s.as_str())
     }
 
+    fn save_name(&self, uuid: Uuid) {
+        if let Err(e) = self.storage.upsert_name(&self.data, uuid) {
+            log::error!("failed to save name of {}: {}", uuid, e);
+        }
+    }
+
     async fn try_ensure_users_are_known(
         &mut self,
         users_with_keys: impl Iterator<Item = (Uuid, Vec<u8>)>,
@@ -1432,11 +2561,131 @@ impl App {
                 messages: StatefulList::with_items(Vec::new()),
                 unread_messages: 0,
                 typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
             });
             self.data.channels.items.len() - 1
         }
     }
 
+    /// Whether the content was sent by a blocked user or to a blocked group
+    fn is_blocked(&self, sender_id: Option<Uuid>, body: &ContentBody) -> bool {
+        let group_id = match body {
+            ContentBody::DataMessage(DataMessage {
+                group_v2:
+                    Some(GroupContextV2 {
+                        master_key: Some(master_key),
+                        ..
+                    }),
+                ..
+            }) => ChannelId::from_master_key_bytes(master_key).ok(),
+            ContentBody::TypingMessage(TypingMessage {
+                group_id: Some(group_id),
+                ..
+            }) => group_id.as_slice().try_into().ok().map(ChannelId::Group),
+            _ => None,
+        };
+        sender_id
+            .filter(|&uuid| uuid != self.user_id)
+            .map(ChannelId::User)
+            .into_iter()
+            .chain(group_id)
+            .any(|channel_id| self.data.blocked.contains(&channel_id))
+    }
+
+    /// Replaces the blocked users and groups by the ones synced from the primary device.
+    ///
+    /// Blocked phone numbers are resolved by the synced contacts.
+    fn sync_blocked(&mut self, blocked: Blocked) {
+        let contacts = &self.data.contacts;
+        let users = blocked
+            .uuids
+            .iter()
+            .filter_map(|uuid| uuid.parse().ok())
+            .chain(blocked.numbers.iter().filter_map(|number| {
+                contacts
+                    .iter()
+                    .find(|(_, contact)| contact.phone_number.as_ref() == Some(number))
+                    .map(|(&uuid, _)| uuid)
+            }))
+            .map(ChannelId::User);
+        let groups = blocked
+            .group_ids
+            .iter()
+            .filter_map(|group_id| group_id.as_slice().try_into().ok())
+            .map(ChannelId::Group);
+        self.data.blocked = users.chain(groups).collect();
+        self.storage.update_blocked(&self.data).unwrap();
+    }
+
+    /// Blocks the channel, or unblocks it if it is blocked.
+    ///
+    /// The changed blocked list is sent to our other devices.
+    fn toggle_blocked(&mut self, channel_idx: usize) {
+        let channel_id = self.data.channels.items[channel_idx].id;
+        if !self.data.blocked.remove(&channel_id) {
+            self.data.blocked.insert(channel_id);
+        }
+        self.storage.update_blocked(&self.data).unwrap();
+
+        let sending = self.signal_manager.send_blocked(&self.data.blocked);
+        tokio::task::spawn_local(async move {
+            if let Err(e) = sending.await {
+                log::error!("{:#}", e);
+            }
+        });
+    }
+
+    /// Stores the contacts synced from the primary device.
+    ///
+    /// The names of the contacts are taken over, and a channel is added for each contact which is
+    /// not blocked, s.t. it can be messaged. Contacts without a name in the address book are named
+    /// by their profile, or else by their phone number.
+    async fn sync_contacts(&mut self) -> anyhow::Result<()> {
+        // users whose name, contact or channel changed, saved at once in the end
+        let mut changed = Vec::new();
+        for (uuid, contact) in self.signal_manager.contacts()? {
+            let mut is_changed = false;
+            let name = if !contact.name.is_empty() {
+                Some(contact.name.clone())
+            } else if let Some(name) = self.data.names.get(&uuid) {
+                Some(name.clone())
+            } else {
+                let profile_name = match contact.profile_key.clone().try_into() {
+                    Ok(profile_key) => self.signal_manager.contact_name(uuid, profile_key).await,
+                    Err(_) => None,
+                };
+                profile_name.or_else(|| contact.phone_number.clone())
+            };
+
+            if let Some(name) = name {
+                if self.data.names.get(&uuid) != Some(&name) {
+                    self.data.names.insert(uuid, name.clone());
+                    is_changed = true;
+                }
+                if !contact.blocked {
+                    let channel_name = self
+                        .channel_idx(ChannelId::User(uuid))
+                        .map(|channel_idx| self.data.channels.items[channel_idx].name.clone());
+                    let channel_idx = self.ensure_contact_channel_exists(uuid, &name).await;
+                    is_changed |=
+                        channel_name.as_ref() != Some(&self.data.channels.items[channel_idx].name);
+                }
+            }
+
+            if self.data.contacts.get(&uuid) != Some(&contact) {
+                self.data.contacts.insert(uuid, contact);
+                is_changed = true;
+            }
+            if is_changed {
+                changed.push(uuid);
+            }
+        }
+        if !changed.is_empty() {
+            self.storage.upsert_contacts(&self.data, &changed)?;
+        }
+        Ok(())
+    }
+
     async fn ensure_contact_channel_exists(&mut self, uuid: Uuid, name: &str) -> usize {
         if let Some(channel_idx) = self
             .data
@@ -1460,11 +2709,92 @@ impl App {
                 messages: StatefulList::with_items(Vec::new()),
                 unread_messages: 0,
                 typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
             });
             self.data.channels.items.len() - 1
         }
     }
 
+    /// Applies the timer of disappearing messages of a message received in the channel.
+    ///
+    /// A missing timer or a timer of 0 disables disappearing messages.
+    fn update_expire_timer(&mut self, channel_idx: usize, expire_timer: Option<u32>) {
+        let expire_timer = expire_timer.filter(|&timer| timer > 0);
+        let channel = &mut self.data.channels.items[channel_idx];
+        if channel.expire_timer != expire_timer {
+            channel.expire_timer = expire_timer;
+            let channel_id = channel.id;
+            self.storage.update_channel(&self.data, channel_id).unwrap();
+        }
+    }
+
+    /// Deletes the disappearing messages which expired from the app data and the storage,
+    /// together with their attachments which are not referenced by other messages.
+    ///
+    /// Our messages which are not sent yet are kept until they are sent.
+    pub fn remove_expired_messages(&mut self) -> anyhow::Result<()> {
+        let now = util::utc_now_timestamp_msec();
+        let is_expired = |message: &Message| message.is_expired(now);
+
+        let mut expired = Vec::new();
+        for (channel_idx, channel) in self.data.channels.items.iter_mut().enumerate() {
+            if !channel.messages.items.iter().any(is_expired) {
+                continue;
+            }
+            let (expired_messages, messages) = std::mem::take(&mut channel.messages.items)
+                .into_iter()
+                .partition(is_expired);
+            channel.messages.items = messages;
+
+            // the selection is counted from the newest message
+            let len = channel.messages.items.len();
+            if matches!(channel.messages.state.selected(), Some(idx) if idx >= len) {
+                channel.messages.state.select(len.checked_sub(1));
+            }
+            expired.push((channel_idx, expired_messages));
+        }
+
+        for (channel_idx, messages) in expired {
+            let channel_id = self.data.channels.items[channel_idx].id;
+            let mut expired_attachments = HashSet::new();
+            for message in messages {
+                self.storage
+                    .delete_message(&self.data, channel_id, &message)?;
+                expired_attachments.extend(
+                    message
+                        .attachments
+                        .into_iter()
+                        .map(|attachment| attachment.filename),
+                );
+            }
+            self.delete_unreferenced_attachments(channel_idx, expired_attachments)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the disappearing messages which expired from the app data and the storage, including
+    /// the stored messages which are not loaded.
+    fn remove_expired_stored_messages(&mut self) -> anyhow::Result<()> {
+        self.remove_expired_messages()?;
+
+        let now = util::utc_now_timestamp_msec();
+        let mut expired_attachments: HashMap<ChannelId, HashSet<PathBuf>> = HashMap::new();
+        for (channel_id, message) in self.storage.delete_expired_messages(now)? {
+            expired_attachments.entry(channel_id).or_default().extend(
+                message
+                    .attachments
+                    .into_iter()
+                    .map(|attachment| attachment.filename),
+            );
+        }
+        for (channel_id, paths) in expired_attachments {
+            if let Some(channel_idx) = self.channel_idx(channel_id) {
+                self.delete_unreferenced_attachments(channel_idx, paths)?;
+            }
+        }
+        Ok(())
+    }
+
     fn add_message_to_channel(&mut self, channel_idx: usize, message: Message) {
         let channel = &mut self.data.channels.items[channel_idx];
 
@@ -1473,19 +2803,46 @@ impl App {
             // keep selection on the old message
             channel.messages.state.select(Some(idx + 1));
         }
+        let channel_id = channel.id;
+        self.storage.append_message(&self.data, channel_id).unwrap();
 
         self.touch_channel(channel_idx);
     }
 
-    fn touch_channel(&mut self, channel_idx: usize) {
+    /// Adds system messages about the changes of the group to its channel.
+    ///
+    /// In contrast to other messages, they don't count as unread.
+    fn add_group_changes(&mut self, channel_idx: usize, changes: Vec<GroupChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        let now = util::utc_now_timestamp_msec();
+        let count = changes.len();
+        let channel = &mut self.data.channels.items[channel_idx];
+        for (idx, change) in changes.into_iter().enumerate() {
+            // distinct arrival times identify the messages in the channel
+            let message = Message::group_change(change, now + idx as u64);
+            channel.messages.items.push(message);
+            if let Some(idx) = channel.messages.state.selected() {
+                channel.messages.state.select(Some(idx + 1));
+            }
+        }
+        let channel_id = channel.id;
+        self.storage
+            .append_messages(&self.data, channel_id, count)
+            .unwrap();
+    }
+
+    fn touch_channel(&mut self, channel_idx: usize) {
         if self.data.channels.state.selected() != Some(channel_idx) {
             self.data.channels.items[channel_idx].unread_messages += 1;
         } else {
             self.reset_unread_messages();
         }
 
+        let channel_id = self.data.channels.items[channel_idx].id;
         self.bubble_up_channel(channel_idx);
-        self.save().unwrap();
+        self.storage.update_channel(&self.data, channel_id).unwrap();
     }
 
     fn bubble_up_channel(&mut self, channel_idx: usize) {
@@ -1551,22 +2908,346 @@ impl App {
         (clean_input, attachments)
     }
 
-    async fn save_attachments(
+    /// Starts downloading the pending attachments of the message in the background.
+    ///
+    /// Unless downloading `on_demand`, only attachments within the configured auto-download limit
+    /// are downloaded, and failed downloads are not retried.
+    fn download_attachments(&mut self, channel_id: ChannelId, arrived_at: u64, on_demand: bool) {
+        let auto_download_limit = self
+            .config
+            .attachments_auto_download_mib
+            .map(|limit_mib| limit_mib * 1024 * 1024);
+        let message = match self.data.message_mut(channel_id, arrived_at) {
+            Some(message) => message,
+            None => return,
+        };
+
+        let mut attachment_pointers = Vec::new();
+        for (attachment_idx, attachment) in message.attachments.iter_mut().enumerate() {
+            let attachment_pointer = match attachment.download.as_ref() {
+                Some(download) if !download.in_progress => download.attachment_pointer(attachment),
+                _ => continue,
+            };
+            let is_auto_download = match auto_download_limit {
+                Some(limit) => attachment.size as u64 <= limit,
+                None => true,
+            };
+            if let Some(download) = attachment.download.as_mut() {
+                if !on_demand && (download.error.is_some() || !is_auto_download) {
+                    continue;
+                }
+                download.in_progress = true;
+                download.error = None;
+            }
+            attachment_pointers.push((attachment_idx, attachment_pointer));
+        }
+
+        for (attachment_idx, attachment_pointer) in attachment_pointers {
+            let download = self.signal_manager.download_attachment(attachment_pointer);
+            let downloads_tx = self.downloads_tx.clone();
+            tokio::task::spawn_local(async move {
+                let result = download.await;
+                // fails only if the app is gone
+                let _ = downloads_tx.send(FinishedDownload {
+                    channel_id,
+                    arrived_at,
+                    attachment_idx,
+                    result,
+                });
+            });
+        }
+    }
+
+    /// Starts downloading the pending attachments of the selected message, including the ones
+    /// which are too large to be downloaded automatically or failed to download before.
+    fn download_selected_attachments(&mut self) {
+        let channel = match self.data.channels.state.selected() {
+            Some(idx) => &self.data.channels.items[idx],
+            None => return,
+        };
+        if let Some(message) = channel.selected_message() {
+            let (channel_id, arrived_at) = (channel.id, message.arrived_at);
+            self.download_attachments(channel_id, arrived_at, true);
+        }
+    }
+
+    /// Applies the result of sending the message which arrived at `arrived_at` in the channel.
+    ///
+    /// A message which failed to send while offline or in presage, e.g. because the connection is
+    /// lost, is queued in the outbox, unless it failed to send `MAX_SEND_ATTEMPTS` times already.
+    /// Otherwise, it is marked as failed.
+    fn on_message_sent(
         &mut self,
-        attachment_pointers: Vec<AttachmentPointer>,
-    ) -> Vec<Attachment> {
-        let mut attachments = vec![];
-        for attachment_pointer in attachment_pointers {
-            match self
-                .signal_manager
-                .save_attachment(attachment_pointer)
-                .await
-            {
-                Ok(attachment) => attachments.push(attachment),
-                Err(e) => log::warn!("failed to save attachment: {}", e),
+        channel_id: ChannelId,
+        arrived_at: u64,
+        result: anyhow::Result<()>,
+    ) {
+        let attempts = self
+            .send_attempts
+            .remove(&(channel_id, arrived_at))
+            .unwrap_or_default()
+            + 1;
+        let receipt = match result {
+            Ok(()) => Receipt::Sent,
+            Err(e) => {
+                log::error!("{:#}", e);
+                let is_connectivity_error = !self.is_online || is_connectivity_error(&e);
+                if is_connectivity_error && attempts < MAX_SEND_ATTEMPTS {
+                    Receipt::Queued
+                } else {
+                    Receipt::Failed
+                }
+            }
+        };
+        let message = match self.data.message_mut(channel_id, arrived_at) {
+            // a receipt of the recipient might have arrived already
+            Some(message) if message.receipt == Receipt::Pending => {
+                message.receipt = receipt;
+                message.clone()
             }
+            _ => return,
+        };
+        self.storage
+            .update_receipts(&self.data, channel_id, &[arrived_at])
+            .unwrap();
+        if receipt == Receipt::Queued {
+            self.queue_message(channel_id, message, attempts).unwrap();
         }
-        attachments
+    }
+
+    /// Reverts the reaction to the message which arrived at `arrived_at` in the channel, if it
+    /// failed to send.
+    fn on_reaction_sent(
+        &mut self,
+        channel_id: ChannelId,
+        arrived_at: u64,
+        emoji: String,
+        remove: bool,
+        result: anyhow::Result<()>,
+    ) {
+        if let Err(e) = result {
+            log::error!("{:#}", e);
+            self.handle_reaction(channel_id, arrived_at, self.user_id, emoji, !remove, false);
+        }
+    }
+
+    /// Queues the messages which were still being sent when gurk stopped in the outbox.
+    fn queue_interrupted_sends(&mut self) -> anyhow::Result<()> {
+        let mut interrupted = Vec::new();
+        for channel in &mut self.data.channels.items {
+            for message in &mut channel.messages.items {
+                if message.receipt == Receipt::Pending {
+                    message.receipt = Receipt::Queued;
+                    interrupted.push((channel.id, message.clone()));
+                }
+            }
+        }
+        interrupted.sort_unstable_by_key(|(_, message)| message.arrived_at);
+        for (channel_id, message) in interrupted {
+            self.storage
+                .update_receipts(&self.data, channel_id, &[message.arrived_at])?;
+            self.queue_message(channel_id, message, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Marks gurk as online and sends the messages in the outbox.
+    ///
+    /// On the first connection, the contacts and the blocked list are requested from the primary
+    /// device. Groups are not requested, since the group sync only carries legacy groups; current
+    /// groups are resolved from the server when their messages arrive.
+    pub fn on_connected(&mut self) -> anyhow::Result<()> {
+        self.is_online = true;
+        if !self.sync_requested {
+            self.sync_requested = true;
+            let requests = [
+                self.signal_manager.request_contacts_sync(),
+                self.signal_manager.request_blocked_sync(),
+            ];
+            for sending in requests {
+                tokio::task::spawn_local(async move {
+                    if let Err(e) = sending.await {
+                        log::error!("{:#}", e);
+                    }
+                });
+            }
+        }
+        self.flush_outbox()
+    }
+
+    /// Marks gurk as offline, s.t. new messages are queued in the outbox.
+    pub fn on_disconnected(&mut self) {
+        self.is_online = false;
+    }
+
+    /// Sends the messages in the outbox again, if gurk is online and this was not done within the
+    /// resend period.
+    pub fn step_outbox(&mut self) -> anyhow::Result<()> {
+        if self.is_online && self.last_flush_at.elapsed() >= RESEND_PERIOD {
+            self.flush_outbox()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the messages in the outbox in the order they were composed.
+    ///
+    /// Each queued message is replaced by the sent one in its channel. Messages of channels which
+    /// do not exist anymore are dropped.
+    fn flush_outbox(&mut self) -> anyhow::Result<()> {
+        self.last_flush_at = Instant::now();
+        while !self.data.outbox.is_empty() {
+            let QueuedMessage {
+                channel_id,
+                message,
+                attempts,
+            } = self.data.outbox.remove(0);
+            let channel_idx = match self.channel_idx(channel_id) {
+                Some(idx) => idx,
+                None => {
+                    log::warn!(
+                        "dropping queued message of removed channel {:?}",
+                        channel_id
+                    );
+                    self.storage.update_outbox(&self.data)?;
+                    continue;
+                }
+            };
+            let channel = &mut self.data.channels.items[channel_idx];
+            if let Some(idx) = channel.messages.items.iter().rposition(|loaded| {
+                loaded.arrived_at == message.arrived_at && loaded.receipt == Receipt::Queued
+            }) {
+                channel.messages.items.remove(idx);
+                // messages are shown in reversed order => selected is reversed
+                if let Some(selected) = channel.messages.state.selected() {
+                    let selected_idx = channel.messages.items.len() - selected;
+                    match selected_idx.cmp(&idx) {
+                        Ordering::Less => channel.messages.state.select(Some(selected - 1)),
+                        Ordering::Equal => channel.messages.state.select(None),
+                        Ordering::Greater => {}
+                    }
+                }
+            }
+            self.storage
+                .delete_message(&self.data, channel_id, &message)?;
+
+            let sent_message = self.resend_message(channel_id, message, attempts)?;
+            self.push_sent_message(channel_idx, sent_message)?;
+            self.bubble_up_channel(channel_idx);
+            self.storage.update_channel(&self.data, channel_id)?;
+            self.storage.update_outbox(&self.data)?;
+        }
+        Ok(())
+    }
+
+    fn channel_idx(&self, channel_id: ChannelId) -> Option<usize> {
+        self.data
+            .channels
+            .items
+            .iter()
+            .position(|channel| channel.id == channel_id)
+    }
+
+    /// Applies the results of the sends which finished in the background.
+    pub fn step_sends(&mut self) {
+        while let Ok(send) = self.sends_rx.try_recv() {
+            self.on_send_finished(send);
+        }
+    }
+
+    fn on_send_finished(&mut self, send: FinishedSend) {
+        match send {
+            FinishedSend::Message {
+                channel_id,
+                arrived_at,
+                result,
+            } => self.on_message_sent(channel_id, arrived_at, result),
+            FinishedSend::Reaction {
+                channel_id,
+                arrived_at,
+                emoji,
+                remove,
+                result,
+            } => self.on_reaction_sent(channel_id, arrived_at, emoji, remove, result),
+            FinishedSend::Delete {
+                channel_id,
+                arrived_at,
+                result: Ok(()),
+            } => {
+                self.handle_remote_delete(channel_id, arrived_at, self.user_id);
+            }
+            FinishedSend::Delete { result: Err(e), .. } => log::error!("{:#}", e),
+            FinishedSend::ExpireTimer {
+                channel_id,
+                previous,
+                result: Err(e),
+            } => {
+                log::error!("{:#}", e);
+                if let Some(channel_idx) = self.channel_idx(channel_id) {
+                    self.data.channels.items[channel_idx].expire_timer = previous;
+                    self.storage.update_channel(&self.data, channel_id).unwrap();
+                }
+            }
+            FinishedSend::ExpireTimer { result: Ok(()), .. } => {}
+        }
+    }
+
+    /// Saves the attachments whose download finished in the background.
+    pub fn step_downloads(&mut self) {
+        while let Ok(download) = self.downloads_rx.try_recv() {
+            if let Err(e) = self.on_download_finished(download) {
+                log::error!("failed to save the downloaded attachment: {:#}", e);
+            }
+        }
+    }
+
+    fn on_download_finished(&mut self, download: FinishedDownload) -> anyhow::Result<()> {
+        let FinishedDownload {
+            channel_id,
+            arrived_at,
+            attachment_idx,
+            result,
+        } = download;
+        // the message might have been pruned in the meantime
+        let attachment = match self
+            .data
+            .message_mut(channel_id, arrived_at)
+            .and_then(|message| message.attachments.get_mut(attachment_idx))
+        {
+            Some(attachment) if attachment.is_pending() => attachment,
+            _ => return Ok(()),
+        };
+
+        let file_name = attachment
+            .download
+            .as_ref()
+            .and_then(|download| download.file_name.clone());
+        let attachment_store = &self.attachment_store;
+        let saved = result.and_then(|data| {
+            let (hash, filename) = attachment_store.save(
+                channel_id,
+                &data,
+                file_name.as_deref(),
+                Some(&attachment.content_type),
+            )?;
+            Ok((hash, filename, data.len()))
+        });
+        match saved {
+            Ok((hash, filename, size)) => {
+                attachment.id = hash;
+                attachment.filename = filename;
+                attachment.size = size as u32;
+                attachment.download = None;
+            }
+            Err(e) => {
+                log::warn!("failed to download attachment: {:#}", e);
+                if let Some(download) = attachment.download.as_mut() {
+                    download.in_progress = false;
+                    download.error = Some(format!("{:#}", e));
+                }
+            }
+        }
+        self.storage
+            .update_attachments(&self.data, channel_id, arrived_at)
     }
 
     pub fn toggle_help(&mut self) {
@@ -1608,6 +3289,169 @@ fn open_url(message: &Message, url_regex: &Regex) -> Option<()> {
     Some(())
 }
 
+/// Reads the saved attachment to send it again.
+fn read_attachment(attachment: &Attachment) -> anyhow::Result<(AttachmentSpec, Vec<u8>)> {
+    let data = std::fs::read(&attachment.filename)
+        .with_context(|| format!("failed to read {}", attachment.filename.display()))?;
+    // saved files are named by the hash of their content, followed by the original file name
+    let file_name = attachment
+        .filename
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(attachment.id.as_str()))
+        .and_then(|name| name.strip_prefix('-'))
+        .map(ToString::to_string);
+    let spec = AttachmentSpec {
+        content_type: attachment.content_type.clone(),
+        length: data.len(),
+        file_name,
+        preview: None,
+        voice_note: None,
+        borderless: None,
+        width: None,
+        height: None,
+        caption: None,
+        blur_hash: None,
+    };
+    Ok((spec, data))
+}
+
+/// Whether sending failed in presage, e.g. because the connection to Signal could not be
+/// established or was lost, in contrast to sends refused by gurk itself
+fn is_connectivity_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.is::<signal::PresageError>())
+}
+
+/// Changes from the group of the channel to the resolved group
+///
+/// Channels without group data have no known previous revision, and so no changes.
+fn group_changes(channel: &Channel, resolved: &ResolvedGroup) -> Vec<GroupChange> {
+    let group_data = match channel.group_data.as_ref() {
+        Some(group_data) => group_data,
+        None => return Vec::new(),
+    };
+    let members = &resolved.group_data.members;
+    let mut changes: Vec<GroupChange> = members
+        .iter()
+        .filter(|uuid| !group_data.members.contains(uuid))
+        .map(|&uuid| GroupChange::MemberAdded(uuid))
+        .collect();
+    changes.extend(
+        group_data
+            .members
+            .iter()
+            .filter(|uuid| !members.contains(uuid))
+            .map(|&uuid| GroupChange::MemberRemoved(uuid)),
+    );
+    if channel.name != resolved.name {
+        changes.push(GroupChange::TitleChanged(resolved.name.clone()));
+    }
+    if channel.expire_timer != resolved.expire_timer {
+        changes.push(GroupChange::ExpireTimerChanged(resolved.expire_timer));
+    }
+    changes
+}
+
+/// Replaces the mentions in the body by placeholders, and returns the body together with the
+/// mentions.
+///
+/// The body ranges of the mentions count UTF-16 code units; invalid ones are ignored.
+fn mentions_from_body_ranges(
+    body: Option<String>,
+    body_ranges: &[BodyRange],
+) -> (Option<String>, Vec<Mention>) {
+    let body = match body {
+        Some(body) => body,
+        None => return (None, Vec::new()),
+    };
+    let mut ranges: Vec<(usize, usize, Uuid)> = body_ranges
+        .iter()
+        .filter_map(|range| {
+            let uuid = match range.associated_value.as_ref()? {
+                AssociatedValue::MentionUuid(uuid) => uuid.parse().ok()?,
+            };
+            let start = range.start? as usize;
+            let end = start + range.length? as usize;
+            Some((
+                utf16_to_byte_idx(&body, start)?,
+                utf16_to_byte_idx(&body, end)?,
+                uuid,
+            ))
+        })
+        .collect();
+    ranges.sort_unstable_by_key(|&(start, _, _)| start);
+
+    let mut text = String::with_capacity(body.len());
+    let mut mentions = Vec::new();
+    let mut offset = 0;
+    for (start, end, uuid) in ranges {
+        if start < offset {
+            continue; // overlaps the previous mention
+        }
+        text.push_str(&body[offset..start]);
+        mentions.push(Mention {
+            start: text.len(),
+            uuid,
+        });
+        text.push(MENTION_PLACEHOLDER);
+        offset = end;
+    }
+    text.push_str(&body[offset..]);
+    (Some(text), mentions)
+}
+
+/// Byte index of the character at the index in UTF-16 code units
+fn utf16_to_byte_idx(text: &str, utf16_idx: usize) -> Option<usize> {
+    let mut utf16_len = 0;
+    for (idx, c) in text.char_indices() {
+        if utf16_len >= utf16_idx {
+            return (utf16_len == utf16_idx).then_some(idx);
+        }
+        utf16_len += c.len_utf16();
+    }
+    (utf16_len == utf16_idx).then_some(text.len())
+}
+
+/// Replaces the completed names `@name` in the text by placeholders, and returns the text together
+/// with the mentions.
+fn insert_mentions(text: &str, completed_mentions: &[(String, Uuid)]) -> (String, Vec<Mention>) {
+    let mut out = String::with_capacity(text.len());
+    let mut mentions = Vec::new();
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        let mention = completed_mentions
+            .iter()
+            .filter(|(name, _)| rest.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len());
+        match mention {
+            Some((name, uuid)) => {
+                mentions.push(Mention {
+                    start: out.len(),
+                    uuid: *uuid,
+                });
+                out.push(MENTION_PLACEHOLDER);
+                rest = &rest[name.len()..];
+            }
+            None => out.push('@'),
+        }
+    }
+    out.push_str(rest);
+    (out, mentions)
+}
+
+/// Time at which a message disappears, which arrived at `arrived_at` in a channel with the timer
+fn expires_at(arrived_at: u64, expire_timer: Option<u32>) -> Option<u64> {
+    expire_timer.map(|timer| arrived_at + u64::from(timer) * 1000)
+}
+
+fn is_expire_timer_update(flags: Option<u32>) -> bool {
+    matches!(flags, Some(flags) if flags & Flags::ExpirationTimerUpdate as u32 != 0)
+}
+
 fn notification_text_for_attachments(attachments: &[Attachment]) -> Option<String> {
     match attachments.len() {
         0 => None,
@@ -1620,26 +3464,37 @@ fn notification_text_for_attachments(attachments: &[Attachment]) -> Option<Strin
 mod tests {
     use super::*;
 
-    use crate::config::User;
+    use crate::config::{ChannelRetention, Retention, RetentionPolicy, User};
     use crate::signal::test::SignalManagerMock;
     use crate::storage::test::InMemoryStorage;
+    use crate::storage::SqliteStorage;
+
+    use presage::prelude::proto::AttachmentPointer;
 
     use std::cell::RefCell;
+    use std::future::Future;
     use std::rc::Rc;
 
     fn test_app() -> (App, Rc<RefCell<Vec<Message>>>) {
         let signal_manager = SignalManagerMock::new();
         let sent_messages = signal_manager.sent_messages.clone();
+        (test_app_with(signal_manager), sent_messages)
+    }
 
-        let mut app = App::try_new(
-            Config::with_user(User {
-                name: "Tyler Durden".to_string(),
-                phone_number: "+0000000000".to_string(),
-            }),
-            Box::new(signal_manager),
-            Box::new(InMemoryStorage::new()),
-        )
-        .unwrap();
+    fn test_app_with(signal_manager: SignalManagerMock) -> App {
+        test_app_with_storage(signal_manager, InMemoryStorage::new())
+    }
+
+    fn test_config() -> Config {
+        Config::with_user(User {
+            name: "Tyler Durden".to_string(),
+            phone_number: "+0000000000".to_string(),
+        })
+    }
+
+    fn test_app_with_storage(signal_manager: SignalManagerMock, storage: InMemoryStorage) -> App {
+        let mut app =
+            App::try_new(test_config(), Box::new(signal_manager), Box::new(storage)).unwrap();
 
         app.data.channels.items.push(Channel {
             id: ChannelId::User(Uuid::new_v4()),
@@ -1657,107 +3512,801 @@ mod tests {
                 attachments: Default::default(),
                 reactions: Default::default(),
                 receipt: Default::default(),
+                deleted: false,
+                expires_at: None,
+                mentions: Default::default(),
+                group_change: None,
             }]),
             unread_messages: 1,
             typing: TypingSet::GroupTyping(HashSet::new()),
+            expire_timer: None,
         });
         app.data.channels.state.select(Some(0));
+        app.is_online = true;
 
-        (app, sent_messages)
+        app
+    }
+
+    /// Runs the test on a local task set, s.t. the app can send in the background.
+    fn run_local<F: Future>(test: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        tokio::task::LocalSet::new().block_on(&runtime, test)
+    }
+
+    /// Waits until `n` sends finished in the background, and applies their results.
+    async fn finish_sends(app: &mut App, n: usize) {
+        for _ in 0..n {
+            let send = app.sends_rx.recv().await.unwrap();
+            app.on_send_finished(send);
+        }
     }
 
     #[test]
     fn test_send_input() {
-        let (mut app, sent_messages) = test_app();
-        let input = "Hello, World!";
-        for c in input.chars() {
-            app.get_input().put_char(c);
+        run_local(async {
+            let (mut app, sent_messages) = test_app();
+            let input = "Hello, World!";
+            for c in input.chars() {
+                app.get_input().put_char(c);
+            }
+            app.send_input(0).unwrap();
+
+            let sent = sent_messages.borrow();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].message.as_ref().unwrap(), input);
+
+            assert_eq!(app.data.channels.items[0].unread_messages, 0);
+
+            assert_eq!(app.get_input().data, "");
+        })
+    }
+
+    #[test]
+    fn test_send_failure_retry_and_discard() {
+        run_local(async {
+            let signal_manager =
+                SignalManagerMock::new().with_send_latency(Duration::from_millis(10));
+            let sent_messages = signal_manager.sent_messages.clone();
+            let send_error = signal_manager.send_error.clone();
+            let mut app = test_app_with(signal_manager);
+            *send_error.borrow_mut() = Some("network is down".to_string());
+
+            for c in "Hello?".chars() {
+                app.get_input().put_char(c);
+            }
+            app.send_input(0).unwrap();
+            assert_eq!(
+                app.data.channels.items[0].messages.items[1].receipt,
+                Receipt::Pending
+            );
+            finish_sends(&mut app, 1).await;
+            assert_eq!(
+                app.data.channels.items[0].messages.items[1].receipt,
+                Receipt::Failed
+            );
+            assert!(app.data.outbox.is_empty());
+
+            // only the selected unsent message is retried
+            *send_error.borrow_mut() = None;
+            app.retry_failed_message().unwrap();
+            assert_eq!(sent_messages.borrow().len(), 1);
+            app.data.channels.items[0].messages.state.select(Some(0));
+            app.retry_failed_message().unwrap();
+            assert_eq!(sent_messages.borrow().len(), 2);
+            let messages = &app.data.channels.items[0].messages;
+            assert_eq!(messages.items.len(), 2);
+            assert_eq!(messages.items[1].message.as_deref(), Some("Hello?"));
+            assert_eq!(messages.items[1].receipt, Receipt::Pending);
+            assert_eq!(messages.state.selected(), None);
+            assert!(app.data.outbox.is_empty());
+            finish_sends(&mut app, 1).await;
+            assert_eq!(
+                app.data.channels.items[0].messages.items[1].receipt,
+                Receipt::Sent
+            );
+
+            *send_error.borrow_mut() = Some("network is down".to_string());
+            for c in "Still there?".chars() {
+                app.get_input().put_char(c);
+            }
+            app.send_input(0).unwrap();
+            finish_sends(&mut app, 1).await;
+            app.data.channels.items[0].messages.state.select(Some(0));
+            app.take_failed_message().unwrap();
+            let messages = &app.data.channels.items[0].messages;
+            assert_eq!(messages.items.len(), 2);
+            assert_eq!(messages.items[1].message.as_deref(), Some("Hello?"));
+            assert!(app.data.outbox.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_outbox() {
+        run_local(async {
+            let (mut app, sent_messages) = test_app();
+            app.on_disconnected();
+            for text in ["Hello?", "Anybody there?"] {
+                for c in text.chars() {
+                    app.get_input().put_char(c);
+                }
+                app.send_input(0).unwrap();
+            }
+            assert!(sent_messages.borrow().is_empty());
+            assert_eq!(app.data.outbox.len(), 2);
+            let messages = &app.data.channels.items[0].messages;
+            assert_eq!(messages.items.len(), 3);
+            assert_eq!(messages.items[1].receipt, Receipt::Queued);
+            assert_eq!(messages.items[2].receipt, Receipt::Queued);
+
+            app.on_connected().unwrap();
+            let sent_texts: Vec<_> = sent_messages
+                .borrow()
+                .iter()
+                .map(|message| message.message.clone().unwrap())
+                .collect();
+            assert_eq!(sent_texts, ["Hello?", "Anybody there?"]);
+            assert!(app.data.outbox.is_empty());
+            let messages = &app.data.channels.items[0].messages;
+            assert_eq!(messages.items.len(), 3);
+            assert_eq!(messages.items[1].message.as_deref(), Some("Hello?"));
+            assert_eq!(messages.items[1].receipt, Receipt::Pending);
+            assert_eq!(messages.items[2].message.as_deref(), Some("Anybody there?"));
+            assert_eq!(messages.items[2].receipt, Receipt::Pending);
+        })
+    }
+
+    #[test]
+    fn test_lost_connection_queues_until_max_attempts() {
+        run_local(async {
+            let signal_manager = SignalManagerMock::new();
+            signal_manager.connection_lost.set(true);
+            let mut app = test_app_with(signal_manager);
+            app.on_connected().unwrap();
+            for c in "Hello?".chars() {
+                app.get_input().put_char(c);
+            }
+            app.send_input(0).unwrap();
+
+            for attempts in 1..MAX_SEND_ATTEMPTS {
+                finish_sends(&mut app, 1).await;
+                assert_eq!(app.data.outbox.len(), 1);
+                assert_eq!(app.data.outbox[0].attempts, attempts);
+                assert_eq!(app.data.channels.items[0].messages.items.len(), 2);
+                app.last_flush_at -= RESEND_PERIOD;
+                app.step_outbox().unwrap();
+            }
+            finish_sends(&mut app, 1).await;
+            assert!(app.data.outbox.is_empty());
+            let messages = &app.data.channels.items[0].messages;
+            assert_eq!(messages.items.len(), 2);
+            assert_eq!(messages.items[1].receipt, Receipt::Failed);
+        })
+    }
+
+    #[test]
+    fn test_interrupted_sends_are_queued() {
+        let (mut app, _) = test_app();
+        app.data.channels.items[0].messages.items[0].receipt = Receipt::Pending;
+
+        app.queue_interrupted_sends().unwrap();
+        assert_eq!(
+            app.data.channels.items[0].messages.items[0].receipt,
+            Receipt::Queued
+        );
+        assert_eq!(app.data.outbox.len(), 1);
+        assert_eq!(app.data.outbox[0].message.arrived_at, 0);
+    }
+
+    #[test]
+    fn test_message_sent_keeps_newer_receipt() {
+        let (mut app, _) = test_app();
+        let channel_id = app.data.channels.items[0].id;
+        app.data.channels.items[0].messages.items[0].receipt = Receipt::Delivered;
+
+        app.on_message_sent(channel_id, 0, Ok(()));
+        assert_eq!(
+            app.data.channels.items[0].messages.items[0].receipt,
+            Receipt::Delivered
+        );
+    }
+
+    #[test]
+    fn test_receipts_are_saved_at_once() {
+        let storage = InMemoryStorage::new();
+        let saves = storage.saves.clone();
+        let mut app = test_app_with_storage(SignalManagerMock::new(), storage);
+        let channel = &mut app.data.channels.items[0];
+        let recipient_id = channel.user_id().unwrap();
+        for arrived_at in 1..4 {
+            let message = Message::new(app.user_id, None, arrived_at, Default::default());
+            channel.messages.items.push(message);
         }
-        app.send_input(0).unwrap();
 
-        let sent = sent_messages.borrow();
-        assert_eq!(sent.len(), 1);
-        assert_eq!(sent[0].message.as_ref().unwrap(), input);
+        saves.set(0);
+        app.handle_receipt(recipient_id, Receipt::Delivered, vec![1, 2, 3]);
+        assert_eq!(saves.get(), 1);
+        assert!(app.data.channels.items[0].messages.items[1..]
+            .iter()
+            .all(|message| message.receipt == Receipt::Delivered));
+    }
+
+    #[test]
+    fn test_group_changes_are_saved_at_once() {
+        let storage = InMemoryStorage::new();
+        let saves = storage.saves.clone();
+        let mut app = test_app_with_storage(SignalManagerMock::new(), storage);
+        let member_id = Uuid::new_v4();
+
+        saves.set(0);
+        app.add_group_changes(
+            0,
+            vec![
+                GroupChange::TitleChanged("Paper Street".to_string()),
+                GroupChange::MemberAdded(member_id),
+                GroupChange::MemberRemoved(member_id),
+            ],
+        );
+        assert_eq!(saves.get(), 1);
+        let messages = &app.data.channels.items[0].messages.items;
+        assert_eq!(messages.len(), 4);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.group_change.is_some()));
+    }
+
+    #[test]
+    fn test_failed_reaction_is_reverted() {
+        run_local(async {
+            let signal_manager = SignalManagerMock::new();
+            *signal_manager.send_error.borrow_mut() = Some("network is down".to_string());
+            let mut app = test_app_with(signal_manager);
+
+            app.data.channels.items[0].messages.state.select(Some(0));
+            app.get_input().put_char('👍');
+            app.add_reaction(0);
+            assert_eq!(
+                app.data.channels.items[0].messages.items[0].reactions.len(),
+                1
+            );
+
+            finish_sends(&mut app, 1).await;
+            assert!(app.data.channels.items[0].messages.items[0]
+                .reactions
+                .is_empty());
+        })
+    }
+
+    #[test]
+    fn test_sync_contacts() {
+        run_local(async {
+            let signal_manager = SignalManagerMock::new();
+            let contacts = signal_manager.contacts.clone();
+            let storage = InMemoryStorage::new();
+            let saves = storage.saves.clone();
+            let mut app = test_app_with_storage(signal_manager, storage);
+            app.on_connected().unwrap();
+            assert!(app.sync_requested);
+
+            let marla_id = Uuid::new_v4();
+            let bob_id = Uuid::new_v4();
+            let contact = |name: &str, blocked| Contact {
+                phone_number: Some("+0000000001".to_string()),
+                name: name.to_string(),
+                profile_key: Vec::new(),
+                blocked,
+            };
+            *contacts.borrow_mut() = vec![
+                (marla_id, contact("Marla Singer", false)),
+                (bob_id, contact("", true)),
+            ];
+
+            saves.set(0);
+            app.sync_contacts().await.unwrap();
+            assert_eq!(saves.get(), 1);
+
+            assert_eq!(app.data.contacts.len(), 2);
+            assert!(app.data.contacts[&bob_id].blocked);
+            assert_eq!(app.name_by_id(marla_id), "Marla Singer");
+            // named by the phone number, but without a channel since blocked
+            assert_eq!(app.name_by_id(bob_id), "+0000000001");
+            let channels = &app.data.channels.items;
+            assert_eq!(channels.len(), 2);
+            assert_eq!(channels[1].id, ChannelId::User(marla_id));
+            assert_eq!(channels[1].name, "Marla Singer");
+            assert_eq!(channels[1].unread_messages, 0);
+
+            // nothing is saved if nothing changed
+            app.sync_contacts().await.unwrap();
+            assert_eq!(saves.get(), 1);
+        })
+    }
+
+    #[test]
+    fn test_blocked() {
+        run_local(async {
+            let (mut app, _) = test_app();
+            let marla_id = Uuid::new_v4();
+            let bob_id = Uuid::new_v4();
+            app.data.contacts.insert(
+                bob_id,
+                Contact {
+                    phone_number: Some("+0000000002".to_string()),
+                    name: "Robert Paulson".to_string(),
+                    profile_key: Vec::new(),
+                    blocked: true,
+                },
+            );
+            app.sync_blocked(Blocked {
+                numbers: vec!["+0000000002".to_string()],
+                uuids: vec![marla_id.to_string()],
+                group_ids: vec![vec![42; 32]],
+            });
+            assert_eq!(
+                app.data.blocked,
+                [
+                    ChannelId::User(marla_id),
+                    ChannelId::User(bob_id),
+                    ChannelId::Group([42; 32]),
+                ]
+                .into_iter()
+                .collect()
+            );
+
+            let typing = |group_id: Option<Vec<u8>>| {
+                ContentBody::TypingMessage(TypingMessage {
+                    timestamp: Some(1),
+                    action: Some(0),
+                    group_id,
+                })
+            };
+            let tyler_id = Some(Uuid::new_v4());
+            assert!(app.is_blocked(Some(marla_id), &typing(None)));
+            assert!(app.is_blocked(tyler_id, &typing(Some(vec![42; 32]))));
+            assert!(!app.is_blocked(tyler_id, &typing(None)));
+
+            // receipts to blocked users are not sent
+            app.add_receipt_event(ReceiptEvent::new(marla_id, 1, Receipt::Delivered));
+            assert_eq!(app.receipt_handler, ReceiptHandler::new());
+
+            let channel_id = app.data.channels.items[0].id;
+            app.toggle_blocked(0);
+            assert!(app.data.blocked.contains(&channel_id));
+            app.toggle_blocked(0);
+            assert!(!app.data.blocked.contains(&channel_id));
+        })
+    }
+
+    #[test]
+    fn test_send_typing() {
+        run_local(async {
+            let signal_manager = SignalManagerMock::new();
+            let sent_typing = signal_manager.sent_typing.clone();
+            let mut app = test_app_with(signal_manager);
+            app.data.channels.filtered_items = vec![0];
+            let channel_id = app.data.channels.items[0].id;
+
+            app.get_input().put_char('h');
+            app.update_typing();
+            app.get_input().put_char('i');
+            app.update_typing();
+            // the start of typing is throttled
+            assert_eq!(*sent_typing.borrow(), [(channel_id, TypingAction::Started)]);
+
+            app.send_input(0).unwrap();
+            app.update_typing();
+            assert_eq!(
+                *sent_typing.borrow(),
+                [
+                    (channel_id, TypingAction::Started),
+                    (channel_id, TypingAction::Stopped)
+                ]
+            );
+
+            sent_typing.borrow_mut().clear();
+            app.get_input().put_char('h');
+            app.update_typing();
+            app.data.channels.state.select(None);
+            app.update_typing();
+            assert_eq!(
+                *sent_typing.borrow(),
+                [
+                    (channel_id, TypingAction::Started),
+                    (channel_id, TypingAction::Stopped)
+                ]
+            );
+
+            sent_typing.borrow_mut().clear();
+            app.config.send_typing = false;
+            app.data.channels.state.select(Some(0));
+            app.get_input().put_char('i');
+            app.update_typing();
+            assert!(sent_typing.borrow().is_empty());
+
+            // typing is not sent to large groups
+            app.config.send_typing = true;
+            let group_data = app.data.channels.items[0].group_data.as_mut().unwrap();
+            group_data
+                .members
+                .extend((0..MAX_TYPING_GROUP_SIZE).map(|_| Uuid::new_v4()));
+            app.get_input().put_char('!');
+            app.update_typing();
+            assert!(sent_typing.borrow().is_empty());
+        })
+    }
+
+    #[test]
+    fn test_group_changes() {
+        let (mut app, _) = test_app();
+        let marla_id = Uuid::new_v4();
+        app.data.channels.state.select(None);
+
+        let channel = &app.data.channels.items[0];
+        let resolved = ResolvedGroup {
+            name: "Project Mayhem".to_string(),
+            group_data: GroupData {
+                members: vec![marla_id],
+                revision: 2,
+                ..channel.group_data.clone().unwrap()
+            },
+            profile_keys: Vec::new(),
+            expire_timer: Some(60 * 60),
+        };
+        let changes = group_changes(channel, &resolved);
+        assert_eq!(
+            changes,
+            [
+                GroupChange::MemberAdded(marla_id),
+                GroupChange::MemberRemoved(app.user_id),
+                GroupChange::TitleChanged("Project Mayhem".to_string()),
+                GroupChange::ExpireTimerChanged(Some(60 * 60)),
+            ]
+        );
+
+        app.add_group_changes(0, changes);
+        let channel = &app.data.channels.items[0];
+        assert_eq!(channel.messages.items.len(), 5);
+        assert_eq!(
+            channel.messages.items[1].group_change,
+            Some(GroupChange::MemberAdded(marla_id))
+        );
+        // system messages are not unread
+        assert_eq!(channel.unread_messages, 1);
+    }
+
+    #[test]
+    fn test_resolved_groups_are_saved() {
+        run_local(async {
+            let dir = tempfile::tempdir().unwrap();
+            let db_path = dir.path().join("gurk.sqlite");
+            let signal_manager = SignalManagerMock::new();
+            let groups = signal_manager.groups.clone();
+            let storage = SqliteStorage::open(&db_path, None).unwrap();
+            let mut app =
+                App::try_new(test_config(), Box::new(signal_manager), Box::new(storage)).unwrap();
+            let master_key = [42; 32];
+            let user_id = app.user_id;
+            let resolved = |name: &str, revision| ResolvedGroup {
+                name: name.to_string(),
+                group_data: GroupData {
+                    master_key_bytes: master_key,
+                    members: vec![user_id],
+                    revision,
+                },
+                profile_keys: Vec::new(),
+                expire_timer: None,
+            };
+
+            groups
+                .borrow_mut()
+                .insert(master_key, resolved("Project Mayhem", 1));
+            let channel_idx = app
+                .ensure_group_channel_exists(master_key, 1)
+                .await
+                .unwrap();
+            groups
+                .borrow_mut()
+                .insert(master_key, resolved("Paper Street", 2));
+            let refreshed_idx = app
+                .ensure_group_channel_exists(master_key, 2)
+                .await
+                .unwrap();
+            assert_eq!(refreshed_idx, channel_idx);
+
+            let storage = SqliteStorage::open(&db_path, None).unwrap();
+            let data = storage
+                .load_app_data(user_id, "Tyler Durden".to_string())
+                .unwrap();
+            let channel = &data.channels.items[0];
+            assert_eq!(channel.name, "Paper Street");
+            assert_eq!(channel.group_data.as_ref().unwrap().revision, 2);
+            assert_eq!(
+                channel.messages.items[0].group_change,
+                Some(GroupChange::TitleChanged("Paper Street".to_string()))
+            );
+        })
+    }
+
+    #[test]
+    fn test_remote_delete() {
+        let (mut app, _) = test_app();
+        let channel_id = app.data.channels.items[0].id;
+        let message = &mut app.data.channels.items[0].messages.items[0];
+        message.reactions.push((app.user_id, "👍".to_string()));
+
+        // only the author can delete a message
+        app.handle_remote_delete(channel_id, 0, Uuid::new_v4());
+        let message = &app.data.channels.items[0].messages.items[0];
+        assert!(!message.deleted);
+        assert_eq!(message.message.as_deref(), Some("First message"));
+
+        app.handle_remote_delete(channel_id, 0, app.user_id);
+        let message = &app.data.channels.items[0].messages.items[0];
+        assert!(message.deleted);
+        assert_eq!(message.message, None);
+        assert!(message.reactions.is_empty());
+        assert!(!message.is_empty());
+    }
+
+    #[test]
+    fn test_delete_selected_message() {
+        run_local(async {
+            let (mut app, _) = test_app();
+            app.data.channels.items[0].messages.state.select(Some(0));
+
+            // too old to be deleted for everyone
+            app.delete_selected_message();
+            assert!(app.sends_rx.try_recv().is_err());
+
+            app.data.channels.items[0].messages.items[0].arrived_at =
+                util::utc_now_timestamp_msec();
+            app.delete_selected_message();
+            finish_sends(&mut app, 1).await;
+            let channel = &app.data.channels.items[0];
+            assert!(channel.messages.items[0].deleted);
+            assert_eq!(channel.messages.state.selected(), None);
+        })
+    }
+
+    #[test]
+    fn test_disappearing_messages() {
+        run_local(async {
+            let (mut app, _) = test_app();
+            app.data.channels.items[0].expire_timer = Some(60);
+            for c in "Hello?".chars() {
+                app.get_input().put_char(c);
+            }
+            app.send_input(0).unwrap();
+            let message = &app.data.channels.items[0].messages.items[1];
+            assert_eq!(message.expires_at, Some(message.arrived_at + 60 * 1000));
+
+            // unsent messages are kept
+            app.data.channels.items[0].messages.items[1].expires_at = Some(0);
+            app.remove_expired_messages().unwrap();
+            assert_eq!(app.data.channels.items[0].messages.items.len(), 2);
+
+            finish_sends(&mut app, 1).await;
+            app.remove_expired_messages().unwrap();
+            let messages = &app.data.channels.items[0].messages.items;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].message.as_deref(), Some("First message"));
+        })
+    }
+
+    #[test]
+    fn test_disappearing_messages_keep_shared_attachments() {
+        let (mut app, _) = test_app();
+        let dir = tempfile::tempdir().unwrap();
+        app.attachment_store = AttachmentStore::new(dir.path().to_path_buf(), None, None);
+        let channel_id = app.data.channels.items[0].id;
+        let (_, shared) = app
+            .attachment_store
+            .save(channel_id, b"shared", None, None)
+            .unwrap();
+        let (_, unshared) = app
+            .attachment_store
+            .save(channel_id, b"unshared", None, None)
+            .unwrap();
+        let message = |arrived_at, filename, expires_at| Message {
+            expires_at,
+            ..message_with_attachment(app.user_id, arrived_at, filename)
+        };
+        app.data.channels.items[0].messages = StatefulList::with_items(vec![
+            message(1, &shared, Some(2)),
+            message(2, &unshared, Some(3)),
+            message(3, &shared, None),
+        ]);
+
+        app.remove_expired_messages().unwrap();
+
+        assert_eq!(app.data.channels.items[0].messages.items.len(), 1);
+        assert!(shared.exists());
+        assert!(!unshared.exists());
+    }
+
+    #[test]
+    fn test_cycle_expire_timer() {
+        run_local(async {
+            let signal_manager = SignalManagerMock::new();
+            let send_error = signal_manager.send_error.clone();
+            let mut app = test_app_with(signal_manager);
+
+            app.cycle_expire_timer(0);
+            assert_eq!(app.data.channels.items[0].expire_timer, Some(30));
+            finish_sends(&mut app, 1).await;
+            assert_eq!(app.data.channels.items[0].expire_timer, Some(30));
+
+            // the timer is disabled after the longest preset
+            app.data.channels.items[0].expire_timer = EXPIRE_TIMER_PRESETS.last().copied();
+            app.cycle_expire_timer(0);
+            assert_eq!(app.data.channels.items[0].expire_timer, None);
+            finish_sends(&mut app, 1).await;
+
+            // a failed change is reverted
+            *send_error.borrow_mut() = Some("network is down".to_string());
+            app.cycle_expire_timer(0);
+            finish_sends(&mut app, 1).await;
+            assert_eq!(app.data.channels.items[0].expire_timer, None);
+
+            // the timer of groups is left unchanged
+            app.data.channels.items[0].id = ChannelId::Group([42; 32]);
+            app.cycle_expire_timer(0);
+            assert_eq!(app.data.channels.items[0].expire_timer, None);
+            assert!(app.sends_rx.try_recv().is_err());
+        })
+    }
+
+    #[test]
+    fn test_mentions_from_body_ranges() {
+        let uuid = Uuid::new_v4();
+        let body_range = |start, length| BodyRange {
+            start: Some(start),
+            length: Some(length),
+            associated_value: Some(AssociatedValue::MentionUuid(uuid.to_string())),
+        };
+        // the emoji counts as two UTF-16 code units
+        let body = Some("👻 \u{fffc}, hi!".to_string());
+        let (text, mentions) = mentions_from_body_ranges(body, &[body_range(3, 1)]);
+        assert_eq!(text.as_deref(), Some("👻 \u{fffc}, hi!"));
+        assert_eq!(mentions, [Mention { start: 5, uuid }]);
+
+        let message = Message {
+            mentions,
+            ..Message::new(uuid, text, 0, Vec::new())
+        };
+        assert_eq!(
+            message
+                .text_with_mentions(|_| "Tyler".to_string())
+                .as_deref(),
+            Some("👻 @Tyler, hi!")
+        );
 
-        assert_eq!(app.data.channels.items[0].unread_messages, 0);
+        // ranges out of the body are ignored
+        let body = Some("hi".to_string());
+        let (text, mentions) = mentions_from_body_ranges(body, &[body_range(5, 1)]);
+        assert_eq!(text.as_deref(), Some("hi"));
+        assert!(mentions.is_empty());
+    }
 
-        assert_eq!(app.get_input().data, "");
+    #[test]
+    fn test_complete_and_send_mention() {
+        run_local(async {
+            let (mut app, sent_messages) = test_app();
+            let marla_id = Uuid::new_v4();
+            app.data.names.insert(marla_id, "Marla Singer".to_string());
+            let channel = &mut app.data.channels.items[0];
+            channel.group_data.as_mut().unwrap().members.push(marla_id);
+
+            for c in "Hi @ma".chars() {
+                app.get_input().put_char(c);
+            }
+            assert!(app.complete_mention(0));
+            assert_eq!(app.get_input().data, "Hi @Marla Singer ");
+            for c in "and @nobody".chars() {
+                app.get_input().put_char(c);
+            }
+            assert!(!app.complete_mention(0));
+            app.send_input(0).unwrap();
+
+            let sent = sent_messages.borrow();
+            assert_eq!(sent[0].message.as_deref(), Some("Hi \u{fffc} and @nobody"));
+            assert_eq!(
+                sent[0].mentions,
+                [Mention {
+                    start: 3,
+                    uuid: marla_id
+                }]
+            );
+            assert!(app.get_input().mentions.is_empty());
+        })
     }
 
     #[test]
     fn test_send_input_with_emoji() {
-        let (mut app, sent_messages) = test_app();
-        let input = "👻";
-        for c in input.chars() {
-            app.get_input().put_char(c);
-        }
+        run_local(async {
+            let (mut app, sent_messages) = test_app();
+            let input = "👻";
+            for c in input.chars() {
+                app.get_input().put_char(c);
+            }
 
-        app.send_input(0).unwrap();
+            app.send_input(0).unwrap();
 
-        let sent = sent_messages.borrow();
-        assert_eq!(sent.len(), 1);
-        assert_eq!(sent[0].message.as_ref().unwrap(), input);
+            let sent = sent_messages.borrow();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].message.as_ref().unwrap(), input);
 
-        assert_eq!(app.get_input().data, "");
+            assert_eq!(app.get_input().data, "");
+        })
     }
 
     #[test]
     fn test_send_input_with_emoji_codepoint() {
-        let (mut app, sent_messages) = test_app();
-        let input = ":thumbsup:";
-        for c in input.chars() {
-            app.get_input().put_char(c);
-        }
+        run_local(async {
+            let (mut app, sent_messages) = test_app();
+            let input = ":thumbsup:";
+            for c in input.chars() {
+                app.get_input().put_char(c);
+            }
 
-        app.send_input(0).unwrap();
+            app.send_input(0).unwrap();
 
-        let sent = sent_messages.borrow();
-        assert_eq!(sent.len(), 1);
-        assert_eq!(sent[0].message.as_ref().unwrap(), "👍");
+            let sent = sent_messages.borrow();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].message.as_ref().unwrap(), "👍");
+        })
     }
 
     #[test]
     fn test_add_reaction_with_emoji() {
-        let (mut app, _sent_messages) = test_app();
+        run_local(async {
+            let (mut app, _sent_messages) = test_app();
 
-        app.data.channels.items[0].messages.state.select(Some(0));
+            app.data.channels.items[0].messages.state.select(Some(0));
 
-        app.get_input().put_char('👍');
-        app.add_reaction(0);
+            app.get_input().put_char('👍');
+            app.add_reaction(0);
 
-        let reactions = &app.data.channels.items[0].messages.items[0].reactions;
-        assert_eq!(reactions.len(), 1);
-        assert_eq!(reactions[0], (app.user_id, "👍".to_string()));
+            let reactions = &app.data.channels.items[0].messages.items[0].reactions;
+            assert_eq!(reactions.len(), 1);
+            assert_eq!(reactions[0], (app.user_id, "👍".to_string()));
+        })
     }
 
     #[test]
     fn test_add_reaction_with_emoji_codepoint() {
-        let (mut app, _sent_messages) = test_app();
+        run_local(async {
+            let (mut app, _sent_messages) = test_app();
 
-        app.data.channels.items[0].messages.state.select(Some(0));
+            app.data.channels.items[0].messages.state.select(Some(0));
 
-        for c in ":thumbsup:".chars() {
-            app.get_input().put_char(c);
-        }
-        app.add_reaction(0);
+            for c in ":thumbsup:".chars() {
+                app.get_input().put_char(c);
+            }
+            app.add_reaction(0);
 
-        let reactions = &app.data.channels.items[0].messages.items[0].reactions;
-        assert_eq!(reactions.len(), 1);
-        assert_eq!(reactions[0], (app.user_id, "👍".to_string()));
+            let reactions = &app.data.channels.items[0].messages.items[0].reactions;
+            assert_eq!(reactions.len(), 1);
+            assert_eq!(reactions[0], (app.user_id, "👍".to_string()));
+        })
     }
 
     #[test]
     fn test_remove_reaction() {
-        let (mut app, _sent_messages) = test_app();
+        run_local(async {
+            let (mut app, _sent_messages) = test_app();
 
-        app.data.channels.items[0].messages.state.select(Some(0));
-        let reactions = &mut app.data.channels.items[0].messages.items[0].reactions;
-        reactions.push((app.user_id, "👍".to_string()));
+            app.data.channels.items[0].messages.state.select(Some(0));
+            let reactions = &mut app.data.channels.items[0].messages.items[0].reactions;
+            reactions.push((app.user_id, "👍".to_string()));
 
-        app.add_reaction(0);
+            app.add_reaction(0);
 
-        let reactions = &app.data.channels.items[0].messages.items[0].reactions;
-        assert!(reactions.is_empty());
+            let reactions = &app.data.channels.items[0].messages.items[0].reactions;
+            assert!(reactions.is_empty());
+        })
     }
 
     #[test]
@@ -1777,6 +4326,9 @@ mod tests {
 
     #[test]
     fn test_receipt_order() {
+        assert!(Receipt::Queued < Receipt::Failed);
+        assert!(Receipt::Failed < Receipt::Pending);
+        assert!(Receipt::Pending < Receipt::Sent);
         assert!(Receipt::Nothing < Receipt::Sent);
         assert!(Receipt::Sent < Receipt::Delivered);
         assert!(Receipt::Delivered < Receipt::Read);
@@ -1794,4 +4346,240 @@ mod tests {
 
         Ok(())
     }
+
+    /// Storage which loads older messages from a fixed history.
+    struct HistoryStorage {
+        history: Vec<Message>,
+    }
+
+    impl Storage for HistoryStorage {
+        fn save_app_data(&self, _data: &AppData) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn load_app_data(&self, user_id: Uuid, user_name: String) -> anyhow::Result<AppData> {
+            InMemoryStorage::new().load_app_data(user_id, user_name)
+        }
+
+        fn load_older_messages(
+            &self,
+            _channel_id: ChannelId,
+            arrived_before: u64,
+            limit: usize,
+        ) -> anyhow::Result<Vec<Message>> {
+            let end = self
+                .history
+                .iter()
+                .position(|message| message.arrived_at == arrived_before)
+                .unwrap_or_default();
+            Ok(self.history[end.saturating_sub(limit)..end].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_pgup_loads_older_messages() {
+        let user_id = Uuid::new_v4();
+        let history: Vec<_> = (0..MESSAGES_PAGE_SIZE as u64 + 2)
+            .map(|arrived_at| Message::new(user_id, None, arrived_at, Default::default()))
+            .collect();
+        let mut app = App::try_new(
+            Config::with_user(User {
+                name: "Tyler Durden".to_string(),
+                phone_number: "+0000000000".to_string(),
+            }),
+            Box::new(SignalManagerMock::new()),
+            Box::new(HistoryStorage {
+                history: history.clone(),
+            }),
+        )
+        .unwrap();
+        app.data.channels.items.push(Channel {
+            id: ChannelId::User(user_id),
+            name: "test".to_string(),
+            group_data: None,
+            messages: StatefulList::with_items(history[history.len() - 1..].to_vec()),
+            unread_messages: 0,
+            typing: TypingSet::SingleTyping(false),
+            expire_timer: None,
+        });
+        app.data.channels.state.select(Some(0));
+
+        // selecting the only message loads the next page, and the newest message stays selected
+        app.on_pgup();
+        let channel = &app.data.channels.items[0];
+        assert_eq!(channel.messages.items.len(), MESSAGES_PAGE_SIZE + 1);
+        assert_eq!(
+            channel.selected_message().unwrap().arrived_at,
+            MESSAGES_PAGE_SIZE as u64 + 1
+        );
+
+        for _ in 0..MESSAGES_PAGE_SIZE {
+            app.on_pgup();
+        }
+        let channel = &app.data.channels.items[0];
+        assert_eq!(channel.messages.items, history);
+        assert_eq!(channel.selected_message().unwrap().arrived_at, 1);
+        assert!(app.fully_loaded_channels.contains(&channel.id));
+    }
+
+    #[test]
+    fn test_download_attachments() {
+        let (mut app, _) = test_app();
+        let dir = tempfile::tempdir().unwrap();
+        app.attachment_store = AttachmentStore::new(dir.path().to_path_buf(), None, None);
+        app.config.attachments_auto_download_mib = Some(1);
+        let attachment_pointer = |size| AttachmentPointer {
+            content_type: Some("text/plain".to_string()),
+            size: Some(size),
+            file_name: Some("notes.txt".to_string()),
+            ..Default::default()
+        };
+        let channel = &mut app.data.channels.items[0];
+        let channel_id = channel.id;
+        channel.messages.items[0].attachments = vec![
+            Attachment::pending(&attachment_pointer(2 * 1024 * 1024)),
+            Attachment::pending(&attachment_pointer(3 * 1024 * 1024)),
+        ];
+
+        // too large to be downloaded automatically
+        app.download_attachments(channel_id, 0, false);
+        let attachments = &app.data.channels.items[0].messages.items[0].attachments;
+        assert!(attachments.iter().all(|attachment| !attachment
+            .download
+            .as_ref()
+            .unwrap()
+            .in_progress));
+
+        app.on_download_finished(FinishedDownload {
+            channel_id,
+            arrived_at: 0,
+            attachment_idx: 0,
+            result: Err(anyhow!("connection lost")),
+        })
+        .unwrap();
+        app.on_download_finished(FinishedDownload {
+            channel_id,
+            arrived_at: 0,
+            attachment_idx: 1,
+            result: Ok(b"abc".to_vec()),
+        })
+        .unwrap();
+
+        let attachments = &app.data.channels.items[0].messages.items[0].attachments;
+        assert_eq!(
+            attachments[0].download.as_ref().unwrap().error.as_deref(),
+            Some("connection lost")
+        );
+        assert!(!attachments[1].is_pending());
+        assert_eq!(attachments[1].size, 3);
+        assert_eq!(std::fs::read(&attachments[1].filename).unwrap(), b"abc");
+        assert!(attachments[1]
+            .filename
+            .to_string_lossy()
+            .ends_with("-notes.txt"));
+
+        // messages which are gone are ignored
+        app.on_download_finished(FinishedDownload {
+            channel_id,
+            arrived_at: 42,
+            attachment_idx: 0,
+            result: Ok(b"abc".to_vec()),
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_apply_retention() {
+        let (mut app, _) = test_app();
+        let now = util::utc_now_timestamp_msec();
+        let foreign_attachment = tempfile::NamedTempFile::new().unwrap();
+        let message = |age_days: u64| Message {
+            attachments: vec![Attachment {
+                id: age_days.to_string(),
+                content_type: "image/jpeg".to_string(),
+                filename: foreign_attachment.path().to_path_buf(),
+                size: 0,
+                download: None,
+            }],
+            ..Message::new(app.user_id, None, now - age_days * MSEC_PER_DAY, Vec::new())
+        };
+        let messages: Vec<Message> = [40, 20, 3, 2, 1]
+            .iter()
+            .map(|&days| message(days))
+            .collect();
+        let channel = &mut app.data.channels.items[0];
+        channel.messages = StatefulList::with_items(messages.clone());
+        channel.messages.state.select(Some(4));
+        app.config.retention = Some(Retention {
+            default: RetentionPolicy {
+                max_age_days: Some(30),
+                max_messages: None,
+            },
+            channels: vec![ChannelRetention {
+                name: Some("test".to_string()),
+                id: None,
+                policy: RetentionPolicy {
+                    max_age_days: None,
+                    max_messages: Some(3),
+                },
+            }],
+        });
+
+        app.apply_retention().unwrap();
+
+        let channel = &app.data.channels.items[0];
+        assert_eq!(channel.messages.items, messages[2..]);
+        assert_eq!(channel.messages.state.selected(), Some(2));
+        // attachments not saved by gurk are kept
+        assert!(foreign_attachment.path().exists());
+    }
+
+    fn message_with_attachment(from_id: Uuid, arrived_at: u64, filename: &Path) -> Message {
+        Message {
+            attachments: vec![Attachment {
+                id: arrived_at.to_string(),
+                content_type: "text/plain".to_string(),
+                filename: filename.to_path_buf(),
+                size: 0,
+                download: None,
+            }],
+            ..Message::new(from_id, None, arrived_at, Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_apply_retention_keeps_shared_attachments() {
+        let (mut app, _) = test_app();
+        let dir = tempfile::tempdir().unwrap();
+        app.attachment_store = AttachmentStore::new(dir.path().to_path_buf(), None, None);
+        let channel_id = app.data.channels.items[0].id;
+        let (_, shared) = app
+            .attachment_store
+            .save(channel_id, b"shared", None, None)
+            .unwrap();
+        let (_, unshared) = app
+            .attachment_store
+            .save(channel_id, b"unshared", None, None)
+            .unwrap();
+        let message =
+            |arrived_at, filename| message_with_attachment(app.user_id, arrived_at, filename);
+        app.data.channels.items[0].messages = StatefulList::with_items(vec![
+            message(1, &shared),
+            message(2, &unshared),
+            message(3, &shared),
+        ]);
+        app.config.retention = Some(Retention {
+            default: RetentionPolicy {
+                max_age_days: None,
+                max_messages: Some(1),
+            },
+            channels: Vec::new(),
+        });
+
+        app.apply_retention().unwrap();
+
+        assert_eq!(app.data.channels.items[0].messages.items.len(), 1);
+        assert!(shared.exists());
+        assert!(!unshared.exists());
+    }
 }
@@ -0,0 +1,426 @@
+//! Store of the received attachments
+
+use crate::app::ChannelId;
+use crate::config::Config;
+
+use anyhow::{anyhow, bail, Context};
+use sha2::{Digest, Sha256};
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the attachment files saved directly into the data directory by older versions
+const LEGACY_FILE_PREFIX: &str = "signal-";
+
+/// Maximum length in bytes of the file name given by the sender, which is kept
+const MAX_FILE_NAME_LEN: usize = 128;
+
+/// Extensions of common MIME types, for which the alphabetically first one known to `mime_guess`
+/// is unusual (e.g. `jfif` for `image/jpeg`)
+const PREFERRED_EXTENSIONS: &[(&str, &str)] = &[
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("image/jpeg", "jpg"),
+    ("text/plain", "txt"),
+    ("video/quicktime", "mov"),
+];
+
+/// Store of the attachment files
+///
+/// The files are stored in a subdirectory per channel, and are named by the SHA-256 hash of
+/// their content. Hence, the same attachment is stored only once per channel, and different
+/// attachments never overwrite each other.
+pub struct AttachmentStore {
+    dir: PathBuf,
+    /// Directory containing the attachment files saved by older versions
+    legacy_dir: Option<PathBuf>,
+    /// Maximum total size of the attachment files in bytes
+    quota: Option<u64>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub deleted_files: usize,
+    pub freed_bytes: u64,
+}
+
+impl AttachmentStore {
+    pub fn new(dir: PathBuf, legacy_dir: Option<PathBuf>, quota: Option<u64>) -> Self {
+        Self {
+            dir,
+            legacy_dir,
+            quota,
+        }
+    }
+
+    /// Store in the default data directory with the configured quota
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let data_dir = dirs::data_dir()
+            .ok_or_else(|| anyhow!("could not find data directory"))?
+            .join("gurk");
+        Ok(Self::new(
+            data_dir.join("attachments"),
+            Some(data_dir),
+            config
+                .attachments_quota_mib
+                .map(|quota_mib| quota_mib * 1024 * 1024),
+        ))
+    }
+
+    /// Saves the attachment of the channel and returns the hash of its content together with its
+    /// path.
+    ///
+    /// The file is named by the hash, followed by the sanitized file name given by the sender, if
+    /// any. Without a file name, or if it has no extension, the extension is derived from the
+    /// content type.
+    ///
+    /// Fails if saving the attachment would exceed the quota.
+    pub fn save(
+        &self,
+        channel_id: ChannelId,
+        data: &[u8],
+        file_name: Option<&str>,
+        content_type: Option<&str>,
+    ) -> anyhow::Result<(String, PathBuf)> {
+        let hash = hex::encode(Sha256::digest(data));
+        let channel_dir = self.dir.join(channel_dir_name(channel_id));
+        let mut name = match file_name.and_then(sanitize_file_name) {
+            Some(file_name) => format!("{}-{}", hash, file_name),
+            None => hash.clone(),
+        };
+        if Path::new(&name).extension().is_none() {
+            if let Some(extension) = content_type.and_then(extension_of) {
+                name.push('.');
+                name.push_str(extension);
+            }
+        }
+        let path = channel_dir.join(name);
+        if path.exists() {
+            return Ok((hash, path));
+        }
+
+        if let Some(quota) = self.quota {
+            let used_bytes = self.used_bytes()?;
+            if used_bytes + data.len() as u64 > quota {
+                bail!(
+                    "attachment quota of {} bytes exceeded: {} bytes are used already",
+                    quota,
+                    used_bytes
+                );
+            }
+        }
+
+        fs::create_dir_all(&channel_dir).with_context(|| {
+            format!("failed to create attachment dir: {}", channel_dir.display())
+        })?;
+        let tmp_path = channel_dir.join(format!("{}.tmp", hash));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok((hash, path))
+    }
+
+    /// Whether the file at the path is managed by this store
+    pub fn contains(&self, path: &Path) -> bool {
+        let is_legacy_file = match self.legacy_dir.as_deref() {
+            Some(legacy_dir) => path.parent() == Some(legacy_dir) && is_legacy_file_name(path),
+            None => false,
+        };
+        path.starts_with(&self.dir) || is_legacy_file
+    }
+
+    /// Deletes the file, if it is managed by this store and exists.
+    pub fn delete(&self, path: &Path) -> anyhow::Result<()> {
+        if !self.contains(path) {
+            return Ok(());
+        }
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to delete {}", path.display())),
+        }
+    }
+
+    /// Total size of the stored files in bytes
+    pub fn used_bytes(&self) -> anyhow::Result<u64> {
+        Ok(self.stored_files()?.iter().map(|(_, size)| size).sum())
+    }
+
+    /// Deletes all stored files which are not referenced.
+    pub fn gc(&self, referenced: &HashSet<PathBuf>) -> anyhow::Result<GcStats> {
+        let mut stats = GcStats::default();
+        for (path, size) in self.stored_files()? {
+            if !referenced.contains(&path) {
+                fs::remove_file(&path)
+                    .with_context(|| format!("failed to delete {}", path.display()))?;
+                stats.deleted_files += 1;
+                stats.freed_bytes += size;
+            }
+        }
+        // remove channel directories which became empty (fails for non-empty ones)
+        for entry in read_dir_if_exists(&self.dir)? {
+            let _ = fs::remove_dir(entry?.path());
+        }
+        Ok(stats)
+    }
+
+    /// Paths and sizes of all stored files
+    fn stored_files(&self) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for channel_dir in read_dir_if_exists(&self.dir)? {
+            let channel_dir = channel_dir?;
+            if !channel_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(channel_dir.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    files.push((entry.path(), metadata.len()));
+                }
+            }
+        }
+        if let Some(legacy_dir) = self.legacy_dir.as_ref() {
+            for entry in read_dir_if_exists(legacy_dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() && is_legacy_file_name(&entry.path()) {
+                    files.push((entry.path(), metadata.len()));
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn channel_dir_name(channel_id: ChannelId) -> String {
+    match channel_id {
+        ChannelId::User(id) => id.to_string(),
+        ChannelId::Group(id) => hex::encode(id),
+    }
+}
+
+/// Makes the file name given by the sender safe to use as the name of a file in the store.
+///
+/// Only the last path component is kept, control characters are removed, leading dots are
+/// stripped (no hidden files, no `..`), and the name is truncated to `MAX_FILE_NAME_LEN` bytes.
+/// Returns `None` if nothing usable is left.
+pub fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let base_name = file_name.rsplit(&['/', '\\'][..]).next()?;
+    let mut sanitized: String = base_name
+        .chars()
+        .filter(|c| !c.is_control())
+        .skip_while(|&c| c == '.' || c.is_whitespace())
+        .collect();
+    if sanitized.len() > MAX_FILE_NAME_LEN {
+        // keep the extension when truncating
+        let extension = match Path::new(&sanitized).extension().and_then(|e| e.to_str()) {
+            Some(extension) if extension.len() < MAX_FILE_NAME_LEN / 2 => format!(".{}", extension),
+            _ => String::new(),
+        };
+        let mut end = MAX_FILE_NAME_LEN - extension.len();
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+        sanitized.push_str(&extension);
+    }
+    let sanitized = sanitized.trim_end().to_string();
+    if sanitized.is_empty() {
+        None
+    } else {
+        Some(sanitized)
+    }
+}
+
+/// Usual file extension of the MIME type
+pub fn extension_of(content_type: &str) -> Option<&'static str> {
+    let mime_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    if let Some((_, extension)) = PREFERRED_EXTENSIONS
+        .iter()
+        .find(|(preferred_type, _)| *preferred_type == mime_type)
+    {
+        return Some(extension);
+    }
+    let extensions = mime_guess::get_mime_extensions_str(&mime_type)?;
+    // e.g. `png` for `image/png`
+    let subtype = mime_type.split('/').nth(1)?;
+    extensions
+        .iter()
+        .find(|&&extension| extension == subtype)
+        .or_else(|| extensions.first())
+        .copied()
+}
+
+fn is_legacy_file_name(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with(LEGACY_FILE_PREFIX),
+        None => false,
+    }
+}
+
+fn read_dir_if_exists(dir: &Path) -> anyhow::Result<Vec<io::Result<fs::DirEntry>>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", dir.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_save_deduplicates_by_content() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = AttachmentStore::new(dir.path().join("attachments"), None, None);
+        let channel_id = ChannelId::User(Uuid::new_v4());
+
+        let (hash, path) = store.save(channel_id, b"data", None, Some("image/jpeg"))?;
+        let (same_hash, same_path) = store.save(channel_id, b"data", None, Some("image/jpeg"))?;
+        let (other_hash, other_path) =
+            store.save(channel_id, b"other data", None, Some("image/jpeg"))?;
+
+        assert_eq!(hash, same_hash);
+        assert_eq!(path, same_path);
+        assert_ne!(path, other_path);
+        assert_ne!(hash, other_hash);
+        assert_eq!(
+            path,
+            dir.path()
+                .join("attachments")
+                .join(channel_dir_name(channel_id))
+                .join(format!("{}.jpg", hash))
+        );
+        assert_eq!(fs::read(&path)?, b"data");
+        assert_eq!(store.used_bytes()?, 14);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_keeps_file_name() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = AttachmentStore::new(dir.path().to_path_buf(), None, None);
+        let channel_id = ChannelId::User(Uuid::new_v4());
+
+        let (hash, path) = store.save(
+            channel_id,
+            b"data",
+            Some("../../.bashrc/report.pdf"),
+            Some("application/pdf"),
+        )?;
+        assert_eq!(
+            path,
+            dir.path()
+                .join(channel_dir_name(channel_id))
+                .join(format!("{}-report.pdf", hash))
+        );
+
+        let (hash, path) = store.save(channel_id, b"data", Some("notes"), Some("text/plain"))?;
+        assert_eq!(
+            path.file_name().unwrap(),
+            format!("{}-notes.txt", hash).as_str()
+        );
+
+        let (hash, path) = store.save(channel_id, b"data", Some(".."), Some("unknown/type"))?;
+        assert_eq!(path.file_name().unwrap(), hash.as_str());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(
+            sanitize_file_name("photo.jpg").as_deref(),
+            Some("photo.jpg")
+        );
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_file_name("C:\\evil\\a.exe").as_deref(),
+            Some("a.exe")
+        );
+        assert_eq!(sanitize_file_name(".hidden").as_deref(), Some("hidden"));
+        assert_eq!(sanitize_file_name("a\nb\0.txt").as_deref(), Some("ab.txt"));
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("dir/"), None);
+        assert_eq!(sanitize_file_name(""), None);
+
+        let long_name = format!("{}.tar.gz", "ä".repeat(100));
+        let sanitized = sanitize_file_name(&long_name).unwrap();
+        assert!(sanitized.len() <= MAX_FILE_NAME_LEN);
+        assert!(sanitized.ends_with("ä.gz"));
+    }
+
+    #[test]
+    fn test_extension_of() {
+        assert_eq!(extension_of("image/jpeg"), Some("jpg"));
+        assert_eq!(extension_of("image/png"), Some("png"));
+        assert_eq!(extension_of("audio/mpeg"), Some("mp3"));
+        assert_eq!(extension_of("video/mp4"), Some("mp4"));
+        assert_eq!(extension_of("application/pdf"), Some("pdf"));
+        assert_eq!(extension_of("Text/Plain; charset=utf-8"), Some("txt"));
+        assert_eq!(extension_of("unknown/type"), None);
+        assert_eq!(extension_of("garbage"), None);
+    }
+
+    #[test]
+    fn test_save_enforces_quota() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = AttachmentStore::new(dir.path().to_path_buf(), None, Some(10));
+        let channel_id = ChannelId::Group([42; 32]);
+
+        store.save(channel_id, b"12345678", None, None)?;
+        // already stored files do not count twice
+        store.save(channel_id, b"12345678", None, None)?;
+        assert!(store.save(channel_id, b"123", None, None).is_err());
+        assert_eq!(store.used_bytes()?, 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = AttachmentStore::new(
+            dir.path().join("attachments"),
+            Some(dir.path().to_path_buf()),
+            None,
+        );
+        let channel_id = ChannelId::User(Uuid::new_v4());
+        let (_, referenced) = store.save(channel_id, b"referenced", None, None)?;
+        let (_, unreferenced) =
+            store.save(ChannelId::Group([42; 32]), b"unreferenced", None, None)?;
+        let legacy = dir
+            .path()
+            .join("signal-2022-01-16T11:59:58.405665+00:00.jpg");
+        fs::write(&legacy, b"legacy")?;
+        let other = dir.path().join("gurk.data.json");
+        fs::write(&other, b"{}")?;
+
+        let stats = store.gc(&[referenced.clone()].iter().cloned().collect())?;
+
+        assert_eq!(
+            stats,
+            GcStats {
+                deleted_files: 2,
+                freed_bytes: 18,
+            }
+        );
+        assert!(referenced.exists());
+        assert!(!unreferenced.exists());
+        assert!(!unreferenced.parent().unwrap().exists());
+        assert!(!legacy.exists());
+        assert!(other.exists());
+
+        Ok(())
+    }
+}
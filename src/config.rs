@@ -1,5 +1,8 @@
+use crate::app::{Channel, ChannelId};
+
 use anyhow::{anyhow, bail, Context};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -12,14 +15,67 @@ pub struct Config {
     /// Path to the Signal database containing the linked device data.
     #[serde(default = "default_signal_db_path")]
     pub signal_db_path: PathBuf,
+    /// Storage backend for channels and messages: `json` (default) or `sqlite`
+    #[serde(default)]
+    pub storage: StorageBackend,
+    /// Path to the SQLite database (incl. filename), used by the `sqlite` storage backend.
+    ///
+    /// On the first start, the data from `data_path` is migrated into the database.
+    #[serde(default = "default_sqlite_path")]
+    pub sqlite_path: PathBuf,
+    /// Number of timestamped backups of the JSON data file to keep (0 disables backups)
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    /// Maximum disk space used by the received attachments in MiB (unlimited if not set)
+    ///
+    /// Attachments which would exceed the quota are not saved.
+    #[serde(default)]
+    pub attachments_quota_mib: Option<u64>,
+    /// Maximum size of the attachments in MiB which are downloaded automatically (unlimited if not
+    /// set)
+    ///
+    /// Larger attachments are downloaded when their message is selected.
+    #[serde(default)]
+    pub attachments_auto_download_mib: Option<u64>,
     /// Whether only to show the first name of a contact
     #[serde(default)]
     pub first_name_only: bool,
     /// Whether to show receipts (sent, delivered, read) information next to your user name in UI
     #[serde(default = "default_true")]
     pub show_receipts: bool,
+    /// Whether to let your contacts know when you are typing
+    #[serde(default = "default_true")]
+    pub send_typing: bool,
     /// User configuration
     pub user: User,
+    /// Encryption of the stored data (`json` storage backend only)
+    ///
+    /// If this section is present, the data is encrypted with a key derived from a passphrase.
+    /// The `sqlite` storage backend does not support encryption: gurk refuses to start if this
+    /// section is present together with it.
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    /// Retention of the messages
+    ///
+    /// If this section is present, messages exceeding the maximum age or count are deleted
+    /// together with their attachments at startup and then once an hour.
+    #[serde(default)]
+    pub retention: Option<Retention>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Single JSON file at `data_path`
+    Json,
+    /// SQLite database at `sqlite_path`
+    Sqlite,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        Self::Json
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -30,6 +86,130 @@ pub struct User {
     pub phone_number: String,
 }
 
+/// Source of the passphrase used to encrypt the stored data
+///
+/// If neither a file nor a command is configured, the passphrase is prompted for on startup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Path to a file containing the passphrase
+    #[serde(default)]
+    pub passphrase_file: Option<PathBuf>,
+    /// Shell command printing the passphrase to stdout, e.g. `pass show gurk`
+    #[serde(default)]
+    pub passphrase_command: Option<String>,
+}
+
+impl Encryption {
+    /// Reads the passphrase from the configured file or command, or prompts for it.
+    ///
+    /// If `confirm` is set, a prompted passphrase has to be entered twice.
+    pub fn passphrase(&self, confirm: bool) -> anyhow::Result<String> {
+        let passphrase = if let Some(path) = self.passphrase_file.as_ref() {
+            fs::read_to_string(path)
+                .with_context(|| format!("failed to read passphrase from '{}'", path.display()))?
+        } else if let Some(command) = self.passphrase_command.as_ref() {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .context("failed to run passphrase command")?;
+            if !output.status.success() {
+                bail!(
+                    "passphrase command failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            String::from_utf8(output.stdout).context("passphrase is not valid UTF-8")?
+        } else {
+            let passphrase = rpassword::read_password_from_tty(Some("Passphrase: "))
+                .context("failed to read passphrase")?;
+            if confirm {
+                let repeated = rpassword::read_password_from_tty(Some("Repeat passphrase: "))
+                    .context("failed to read passphrase")?;
+                if passphrase != repeated {
+                    bail!("passphrases do not match");
+                }
+            }
+            passphrase
+        };
+        Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+    }
+}
+
+/// Default retention policy together with overrides for individual channels
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    /// Policy of the channels without an override
+    #[serde(flatten)]
+    pub default: RetentionPolicy,
+    /// Overrides of the default policy; the first matching override is used
+    #[serde(default)]
+    pub channels: Vec<ChannelRetention>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Maximum age of the messages in days
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+    /// Maximum number of messages in a channel
+    #[serde(default)]
+    pub max_messages: Option<usize>,
+}
+
+/// Retention policy of the channels with the given name or id
+///
+/// Limits which are not set are taken from the default policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelRetention {
+    /// Name of the channel
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Id of the channel: the UUID of the contact, or the base64 encoded group identifier
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub policy: RetentionPolicy,
+}
+
+impl Retention {
+    /// Policy of the given channel
+    pub fn policy(&self, channel: &Channel) -> RetentionPolicy {
+        match self.channels.iter().find(|c| c.matches(channel)) {
+            Some(channel_retention) => RetentionPolicy {
+                max_age_days: channel_retention
+                    .policy
+                    .max_age_days
+                    .or(self.default.max_age_days),
+                max_messages: channel_retention
+                    .policy
+                    .max_messages
+                    .or(self.default.max_messages),
+            },
+            None => self.default,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_messages.is_none()
+    }
+}
+
+impl ChannelRetention {
+    fn matches(&self, channel: &Channel) -> bool {
+        let name_matches = self.name.as_ref() == Some(&channel.name);
+        let id_matches = match (self.id.as_ref(), channel.id) {
+            (Some(id), ChannelId::User(uuid)) => Uuid::parse_str(id).ok() == Some(uuid),
+            (Some(id), ChannelId::Group(group_id)) => *id == base64::encode(group_id),
+            (None, _) => false,
+        };
+        name_matches || id_matches
+    }
+}
+
 impl Config {
     /// Create new config with default paths from the given user.
     pub fn with_user(user: User) -> Self {
@@ -37,8 +217,16 @@ impl Config {
             user,
             data_path: default_data_path(),
             signal_db_path: default_signal_db_path(),
+            storage: Default::default(),
+            sqlite_path: default_sqlite_path(),
+            backups: default_backups(),
+            attachments_quota_mib: None,
+            attachments_auto_download_mib: None,
             first_name_only: false,
             show_receipts: true,
+            send_typing: true,
+            encryption: None,
+            retention: None,
         }
     }
 
@@ -153,6 +341,14 @@ fn default_data_path() -> PathBuf {
     default_data_dir().join("gurk.data.json")
 }
 
+fn default_sqlite_path() -> PathBuf {
+    default_data_dir().join("gurk.sqlite")
+}
+
+fn default_backups() -> usize {
+    3
+}
+
 fn default_true() -> bool {
     true
 }
@@ -160,6 +356,8 @@ fn default_true() -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::app::TypingSet;
+
     use tempfile::{tempdir, NamedTempFile, TempDir};
 
     fn example_user() -> User {
@@ -211,4 +409,99 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_load_encryption() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let passphrase_file = dir.path().join("passphrase");
+        fs::write(&passphrase_file, "secret\n")?;
+
+        let config = Config {
+            encryption: Some(Encryption {
+                passphrase_file: Some(passphrase_file),
+                passphrase_command: None,
+            }),
+            ..example_config_with_random_paths(&dir)
+        };
+        let config_path = dir.path().join("gurk.toml");
+        config.save_new_at(&config_path)?;
+        let loaded_config = Config::load(config_path)?;
+        assert_eq!(config, loaded_config);
+        assert_eq!(
+            loaded_config.encryption.unwrap().passphrase(true)?,
+            "secret"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_retention() -> anyhow::Result<()> {
+        let content = r#"
+            [user]
+            name = "Tyler Durden"
+            phone_number = "+0000000000"
+
+            [retention]
+            max_age_days = 365
+
+            [[retention.channels]]
+            name = "Project Mayhem"
+            max_messages = 1000
+
+            [[retention.channels]]
+            id = "e0e86a8e-8a2e-4ab7-a3b4-4e6d3d3f6c0a"
+            max_age_days = 7
+        "#;
+        let config: Config = toml::de::from_str(content)?;
+        let retention = config.retention.unwrap();
+
+        let channel = |id: ChannelId, name: &str| Channel {
+            id,
+            name: name.to_string(),
+            group_data: None,
+            messages: Default::default(),
+            unread_messages: 0,
+            typing: TypingSet::SingleTyping(false),
+            expire_timer: None,
+        };
+        let group = channel(ChannelId::Group([42; 32]), "Project Mayhem");
+        assert_eq!(
+            retention.policy(&group),
+            RetentionPolicy {
+                max_age_days: Some(365),
+                max_messages: Some(1000),
+            }
+        );
+        let contact_id = Uuid::parse_str("e0e86a8e-8a2e-4ab7-a3b4-4e6d3d3f6c0a")?;
+        let contact = channel(contact_id.into(), "Marla Singer");
+        assert_eq!(
+            retention.policy(&contact),
+            RetentionPolicy {
+                max_age_days: Some(7),
+                max_messages: None,
+            }
+        );
+        let other = channel(Uuid::new_v4().into(), "Bob");
+        assert_eq!(retention.policy(&other), retention.default);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_passphrase_command() -> anyhow::Result<()> {
+        let encryption = Encryption {
+            passphrase_file: None,
+            passphrase_command: Some("echo secret".to_string()),
+        };
+        assert_eq!(encryption.passphrase(false)?, "secret");
+
+        let encryption = Encryption {
+            passphrase_file: None,
+            passphrase_command: Some("false".to_string()),
+        };
+        assert!(encryption.passphrase(false).is_err());
+
+        Ok(())
+    }
 }
@@ -39,7 +39,6 @@ impl Cursor {
         }
     }
 
-    #[cfg(test)]
     pub fn at(text: &str, idx: usize) -> Self {
         let idx = snap_to_char(text, idx);
         let (line, col) = calc_line_column(text, idx);
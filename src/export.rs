@@ -0,0 +1,627 @@
+//! Export of the channel history to Markdown, HTML or JSON Lines
+
+use crate::app::{name_by_id, AppData, Channel, Message, Receipt};
+use crate::storage::{load_all_messages, Storage};
+use crate::ui::display_group_change;
+use crate::util::utc_timestamp_msec_to_local;
+
+use anyhow::{bail, Context};
+use log::warn;
+use serde_json::json;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Directory inside the export directory, where the attachments are copied to
+const ATTACHMENTS_DIR: &str = "attachments";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    JsonLines,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            "jsonl" | "json-lines" => Ok(Self::JsonLines),
+            _ => bail!("unknown export format '{}': use markdown, html or jsonl", s),
+        }
+    }
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+            Self::JsonLines => "jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Name of the channel to export; all channels are exported if not set
+    pub channel_name: Option<String>,
+    /// Directory the export is written into, one file per channel
+    pub output_dir: PathBuf,
+    /// Whether to copy the attachments into the export directory
+    pub copy_attachments: bool,
+}
+
+/// Exports the complete history of the selected channels, and returns the paths of the written
+/// files.
+///
+/// Messages which were not loaded with the app data are loaded from the storage.
+pub fn export(
+    storage: &dyn Storage,
+    data: &AppData,
+    user_id: Uuid,
+    options: &ExportOptions,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let channels: Vec<&Channel> = data
+        .channels
+        .items
+        .iter()
+        .filter(|channel| match options.channel_name.as_ref() {
+            Some(name) => &channel.name == name,
+            None => true,
+        })
+        .collect();
+    if channels.is_empty() {
+        if let Some(name) = options.channel_name.as_ref() {
+            bail!("no channel with name '{}'", name);
+        }
+    }
+
+    fs::create_dir_all(&options.output_dir).with_context(|| {
+        format!(
+            "failed to create export dir '{}'",
+            options.output_dir.display()
+        )
+    })?;
+
+    let mut file_names: HashMap<String, usize> = HashMap::new();
+    let mut paths = Vec::new();
+    for channel in channels {
+        let messages = load_all_messages(storage, channel)?;
+        let attachment_paths = if options.copy_attachments {
+            copy_attachments(&messages, &options.output_dir)?
+        } else {
+            Default::default()
+        };
+        let exporter = Exporter {
+            names: &data.names,
+            user_id,
+            channel,
+            attachment_paths,
+        };
+        let content = match options.format {
+            ExportFormat::Markdown => exporter.markdown(&messages),
+            ExportFormat::Html => exporter.html(&messages),
+            ExportFormat::JsonLines => exporter.json_lines(&messages)?,
+        };
+
+        // channels might have the same name
+        let mut file_name = sanitize_file_name(&channel.name);
+        let count = file_names.entry(file_name.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            write!(file_name, "-{}", count).unwrap();
+        }
+        write!(file_name, ".{}", options.format.extension()).unwrap();
+        let path = options.output_dir.join(file_name);
+        fs::write(&path, content)
+            .with_context(|| format!("failed to write export '{}'", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Copies the attachments into the attachments directory of the export and returns the relative
+/// paths of the copies by original path.
+///
+/// Attachments which do not exist anymore are skipped with a warning.
+fn copy_attachments(
+    messages: &[Message],
+    output_dir: &Path,
+) -> anyhow::Result<HashMap<PathBuf, PathBuf>> {
+    let attachments_dir = output_dir.join(ATTACHMENTS_DIR);
+    let mut paths = HashMap::new();
+    for attachment in messages.iter().flat_map(|message| &message.attachments) {
+        let file_name = match attachment.filename.file_name() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        if !attachment.filename.exists() {
+            warn!(
+                "skipping non-existent attachment: {}",
+                attachment.filename.display()
+            );
+            continue;
+        }
+        fs::create_dir_all(&attachments_dir)?;
+        fs::copy(&attachment.filename, attachments_dir.join(file_name)).with_context(|| {
+            format!(
+                "failed to copy attachment '{}'",
+                attachment.filename.display()
+            )
+        })?;
+        paths.insert(
+            attachment.filename.clone(),
+            Path::new(ATTACHMENTS_DIR).join(file_name),
+        );
+    }
+    Ok(paths)
+}
+
+struct Exporter<'a> {
+    names: &'a HashMap<Uuid, String>,
+    user_id: Uuid,
+    channel: &'a Channel,
+    /// Paths of the copied attachments (relative to the export) by original path
+    attachment_paths: HashMap<PathBuf, PathBuf>,
+}
+
+impl<'a> Exporter<'a> {
+    fn name(&self, id: Uuid) -> &'a str {
+        name_by_id(self.names, id)
+    }
+
+    /// Text of the message with the names of the mentioned users, or the description of the
+    /// group change of a system message
+    fn text(&self, message: &Message) -> Option<String> {
+        if let Some(change) = message.group_change.as_ref() {
+            return Some(display_group_change(change, |uuid| {
+                self.name(uuid).to_string()
+            }));
+        }
+        message.text_with_mentions(|uuid| self.name(uuid).to_string())
+    }
+
+    fn attachment_path<'b>(&'b self, path: &'b Path) -> &'b Path {
+        self.attachment_paths
+            .get(path)
+            .map(|path| path.as_path())
+            .unwrap_or(path)
+    }
+
+    /// Receipt of our own messages
+    fn receipt(&self, message: &Message) -> Option<&'static str> {
+        if message.from_id != self.user_id {
+            return None;
+        }
+        match message.receipt {
+            Receipt::Queued => Some("queued"),
+            Receipt::Failed => Some("failed"),
+            Receipt::Pending => Some("pending"),
+            Receipt::Sent => Some("sent"),
+            Receipt::Delivered => Some("delivered"),
+            Receipt::Read => Some("read"),
+            Receipt::Nothing => None,
+        }
+    }
+
+    fn markdown(&self, messages: &[Message]) -> String {
+        let mut out = format!("# {}\n", self.channel.name);
+        for message in messages {
+            if message.group_change.is_some() {
+                let text = self.text(message).unwrap_or_default();
+                let time = format_timestamp(message.arrived_at);
+                writeln!(out, "\n_{}_ {}", text, time).unwrap();
+                continue;
+            }
+            write!(
+                out,
+                "\n**{}** {}",
+                self.name(message.from_id),
+                format_timestamp(message.arrived_at)
+            )
+            .unwrap();
+            if let Some(receipt) = self.receipt(message) {
+                write!(out, " ({})", receipt).unwrap();
+            }
+            out.push('\n');
+            if let Some(quote) = message.quote.as_ref() {
+                let text = self.text(quote).unwrap_or_default();
+                write!(out, "> **{}**:", self.name(quote.from_id)).unwrap();
+                for line in text.lines() {
+                    write!(out, "\n> {}", line).unwrap();
+                }
+                out.push('\n');
+            }
+            if message.deleted {
+                writeln!(out, "_This message was deleted._").unwrap();
+            }
+            if let Some(text) = self.text(message) {
+                // hard line breaks
+                writeln!(out, "{}", text.replace('\n', "  \n")).unwrap();
+            }
+            for attachment in &message.attachments {
+                if attachment.is_pending() {
+                    writeln!(out, "- attachment: not downloaded").unwrap();
+                    continue;
+                }
+                let path = self.attachment_path(&attachment.filename);
+                writeln!(
+                    out,
+                    "- attachment: [{}]({})",
+                    file_name(path),
+                    path.display().to_string().replace(' ', "%20")
+                )
+                .unwrap();
+            }
+            if !message.reactions.is_empty() {
+                let reactions: Vec<_> = message
+                    .reactions
+                    .iter()
+                    .map(|(from_id, emoji)| format!("{} {}", emoji, self.name(*from_id)))
+                    .collect();
+                writeln!(out, "- reactions: {}", reactions.join(", ")).unwrap();
+            }
+        }
+        out
+    }
+
+    fn html(&self, messages: &[Message]) -> String {
+        let title = escape_html(&self.channel.name);
+        let mut out = format!(
+            "<!DOCTYPE html>\n\
+            <html>\n\
+            <head>\n\
+            <meta charset=\"utf-8\">\n\
+            <title>{}</title>\n\
+            <style>\n\
+            body {{ font-family: sans-serif; max-width: 50em; margin: auto; }}\n\
+            .message {{ margin: 1em 0; }}\n\
+            .meta {{ color: #666; font-size: small; }}\n\
+            .text {{ white-space: pre-wrap; }}\n\
+            blockquote {{ border-left: 3px solid #ccc; margin: 0.3em 0; padding-left: 0.5em; }}\n\
+            </style>\n\
+            </head>\n\
+            <body>\n\
+            <h1>{}</h1>\n",
+            title, title
+        );
+        for message in messages {
+            if message.group_change.is_some() {
+                writeln!(
+                    out,
+                    "<div class=\"message meta\"><i>{}</i> {}</div>",
+                    escape_html(&self.text(message).unwrap_or_default()),
+                    format_timestamp(message.arrived_at)
+                )
+                .unwrap();
+                continue;
+            }
+            out.push_str("<div class=\"message\">\n");
+            write!(
+                out,
+                "<div><b>{}</b> <span class=\"meta\">{}",
+                escape_html(self.name(message.from_id)),
+                format_timestamp(message.arrived_at)
+            )
+            .unwrap();
+            if let Some(receipt) = self.receipt(message) {
+                write!(out, " ({})", receipt).unwrap();
+            }
+            out.push_str("</span></div>\n");
+            if let Some(quote) = message.quote.as_ref() {
+                writeln!(
+                    out,
+                    "<blockquote><b>{}</b>: <span class=\"text\">{}</span></blockquote>",
+                    escape_html(self.name(quote.from_id)),
+                    escape_html(&self.text(quote).unwrap_or_default())
+                )
+                .unwrap();
+            }
+            if message.deleted {
+                writeln!(out, "<div><i>This message was deleted.</i></div>").unwrap();
+            }
+            if let Some(text) = self.text(message) {
+                writeln!(out, "<div class=\"text\">{}</div>", escape_html(&text)).unwrap();
+            }
+            for attachment in &message.attachments {
+                if attachment.is_pending() {
+                    writeln!(out, "<div>attachment not downloaded</div>").unwrap();
+                    continue;
+                }
+                let path = self.attachment_path(&attachment.filename);
+                let href = escape_html(&path.display().to_string());
+                if attachment.content_type.starts_with("image/") {
+                    writeln!(
+                        out,
+                        "<div><a href=\"{}\"><img src=\"{}\" style=\"max-width: 20em\"></a></div>",
+                        href, href
+                    )
+                    .unwrap();
+                } else {
+                    writeln!(
+                        out,
+                        "<div><a href=\"{}\">{}</a></div>",
+                        href,
+                        escape_html(&file_name(path))
+                    )
+                    .unwrap();
+                }
+            }
+            if !message.reactions.is_empty() {
+                let reactions: Vec<_> = message
+                    .reactions
+                    .iter()
+                    .map(|(from_id, emoji)| {
+                        format!(
+                            "<span title=\"{}\">{}</span>",
+                            escape_html(self.name(*from_id)),
+                            escape_html(emoji)
+                        )
+                    })
+                    .collect();
+                writeln!(out, "<div>{}</div>", reactions.join(" ")).unwrap();
+            }
+            out.push_str("</div>\n");
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    /// One JSON object per message
+    fn json_lines(&self, messages: &[Message]) -> anyhow::Result<String> {
+        let mut out = String::new();
+        for message in messages {
+            let quote = message.quote.as_ref().map(|quote| {
+                json!({
+                    "from_id": quote.from_id,
+                    "from": self.name(quote.from_id),
+                    "arrived_at": quote.arrived_at,
+                    "text": self.text(quote),
+                })
+            });
+            let attachments: Vec<_> = message
+                .attachments
+                .iter()
+                .map(|attachment| {
+                    json!({
+                        "path": self.attachment_path(&attachment.filename),
+                        "content_type": attachment.content_type,
+                        "size": attachment.size,
+                    })
+                })
+                .collect();
+            let reactions: Vec<_> = message
+                .reactions
+                .iter()
+                .map(|(from_id, emoji)| {
+                    json!({
+                        "from_id": from_id,
+                        "from": self.name(*from_id),
+                        "emoji": emoji,
+                    })
+                })
+                .collect();
+            let line = json!({
+                "channel": self.channel.name,
+                "from_id": message.from_id,
+                "from": self.name(message.from_id),
+                "arrived_at": message.arrived_at,
+                "time": utc_timestamp_msec_to_local(message.arrived_at).to_rfc3339(),
+                "text": self.text(message),
+                "quote": quote,
+                "attachments": attachments,
+                "reactions": reactions,
+                "receipt": self.receipt(message),
+            });
+            out.push_str(&serde_json::to_string(&line)?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    utc_timestamp_msec_to_local(timestamp)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Replaces the characters which are not allowed in file names on common platforms.
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        "channel".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{ChannelId, TypingSet};
+    use crate::signal::Attachment;
+    use crate::storage::test::InMemoryStorage;
+    use crate::util::{FilteredStatefulList, StatefulList};
+
+    use tempfile::tempdir;
+
+    fn example_app_data(user_id: Uuid, contact_id: Uuid, attachment: PathBuf) -> AppData {
+        let question = Message {
+            from_id: contact_id,
+            message: Some("<b>Who</b> are you?".to_string()),
+            arrived_at: 1642334397421,
+            quote: None,
+            attachments: vec![Attachment {
+                id: "1".to_string(),
+                content_type: "image/jpeg".to_string(),
+                filename: attachment,
+                size: 5,
+                download: None,
+            }],
+            reactions: vec![(user_id, "👍".to_string())],
+            receipt: Receipt::Nothing,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        let answer = Message {
+            from_id: user_id,
+            message: Some("Nobody".to_string()),
+            arrived_at: 1642334397422,
+            quote: Some(Box::new(question.clone())),
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Read,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        AppData {
+            channels: FilteredStatefulList::_with_items(vec![Channel {
+                id: ChannelId::User(contact_id),
+                name: "Marla Singer".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(vec![question, answer]),
+                unread_messages: 0,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            }]),
+            names: [
+                (user_id, "Tyler Durden".to_string()),
+                (contact_id, "Marla Singer".to_string()),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn export_to_string(format: ExportFormat, copy_attachments: bool) -> anyhow::Result<String> {
+        let dir = tempdir()?;
+        let attachment = dir.path().join("photo.jpg");
+        fs::write(&attachment, "image")?;
+
+        let user_id = Uuid::new_v4();
+        let data = example_app_data(user_id, Uuid::new_v4(), attachment);
+        let options = ExportOptions {
+            format,
+            channel_name: None,
+            output_dir: dir.path().join("export"),
+            copy_attachments,
+        };
+        let paths = export(&InMemoryStorage::new(), &data, user_id, &options)?;
+        assert_eq!(
+            paths,
+            [dir.path()
+                .join("export")
+                .join(format!("Marla Singer.{}", format.extension()))]
+        );
+        if copy_attachments {
+            let copy = dir.path().join("export/attachments/photo.jpg");
+            assert_eq!(fs::read_to_string(copy)?, "image");
+        }
+        Ok(fs::read_to_string(&paths[0])?)
+    }
+
+    #[test]
+    fn test_export_markdown() -> anyhow::Result<()> {
+        let markdown = export_to_string(ExportFormat::Markdown, true)?;
+        assert!(markdown.starts_with("# Marla Singer\n"));
+        assert!(markdown.contains("**Marla Singer** "));
+        assert!(markdown.contains("<b>Who</b> are you?\n"));
+        assert!(markdown.contains("- attachment: [photo.jpg](attachments/photo.jpg)\n"));
+        assert!(markdown.contains("- reactions: 👍 Tyler Durden\n"));
+        assert!(markdown.contains(" (read)\n> **Marla Singer**:\n> <b>Who</b> are you?\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_html() -> anyhow::Result<()> {
+        let html = export_to_string(ExportFormat::Html, false)?;
+        assert!(html.contains("<title>Marla Singer</title>"));
+        assert!(html.contains("&lt;b&gt;Who&lt;/b&gt; are you?"));
+        assert!(!html.contains("<b>Who</b>"));
+        assert!(html.contains("photo.jpg\"><img"));
+        assert!(html.ends_with("</html>\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_json_lines() -> anyhow::Result<()> {
+        let jsonl = export_to_string(ExportFormat::JsonLines, true)?;
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["from"], "Marla Singer");
+        assert_eq!(lines[0]["attachments"][0]["path"], "attachments/photo.jpg");
+        assert_eq!(lines[0]["reactions"][0]["from"], "Tyler Durden");
+        assert_eq!(lines[0]["receipt"], serde_json::Value::Null);
+        assert_eq!(lines[1]["quote"]["from"], "Marla Singer");
+        assert_eq!(lines[1]["receipt"], "read");
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_unknown_channel() {
+        let dir = tempdir().unwrap();
+        let data = example_app_data(Uuid::new_v4(), Uuid::new_v4(), "photo.jpg".into());
+        let options = ExportOptions {
+            format: ExportFormat::Markdown,
+            channel_name: Some("Robert Paulson".to_string()),
+            output_dir: dir.path().to_owned(),
+            copy_attachments: false,
+        };
+        assert!(export(&InMemoryStorage::new(), &data, Uuid::new_v4(), &options).is_err());
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(sanitize_file_name("Fight Club"), "Fight Club");
+        assert_eq!(sanitize_file_name("a/b: c?"), "a_b_ c_");
+        assert_eq!(sanitize_file_name("..."), "channel");
+    }
+}
@@ -0,0 +1,909 @@
+//! Import of the history from signal-cli and Signal Desktop
+
+use crate::app::{AppData, Channel, ChannelId, GroupData, Message, Receipt, TypingSet};
+use crate::signal::{Attachment, GroupMasterKeyBytes};
+use crate::storage::{load_all_messages, Storage};
+use crate::util::StatefulList;
+
+use anyhow::{anyhow, bail, Context};
+use log::warn;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Output of `signal-cli --output=json receive`, one envelope per line
+    SignalCli,
+    /// JSON object with the arrays `conversations` and `messages`, which contain the `json`
+    /// columns of the tables of the same name of the decrypted Signal Desktop database
+    SignalDesktop,
+}
+
+impl FromStr for ImportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "signal-cli" => Ok(Self::SignalCli),
+            "signal-desktop" | "desktop" => Ok(Self::SignalDesktop),
+            _ => bail!(
+                "unknown import format '{}': use signal-cli or signal-desktop",
+                s
+            ),
+        }
+    }
+}
+
+impl ImportFormat {
+    /// Directory where the client stores the attachments by default
+    pub fn default_attachments_dir(self) -> Option<PathBuf> {
+        match self {
+            Self::SignalCli => dirs::data_dir().map(|dir| dir.join("signal-cli/attachments")),
+            Self::SignalDesktop => {
+                dirs::config_dir().map(|dir| dir.join("Signal/attachments.noindex"))
+            }
+        }
+    }
+}
+
+/// Channels and names read from an export, which are not merged into the app data yet
+#[derive(Debug, Default)]
+pub struct ImportedHistory {
+    channels: Vec<Channel>,
+    names: HashMap<Uuid, String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Number of channels which did not exist before
+    pub new_channels: usize,
+    /// Number of messages which did not exist before
+    pub new_messages: usize,
+}
+
+/// Reads the export at `path` of a Signal client linked to the account `user_id`.
+///
+/// Attachments are expected to be stored in `attachments_dir`.
+pub fn read(
+    format: ImportFormat,
+    path: &Path,
+    attachments_dir: &Path,
+    user_id: Uuid,
+) -> anyhow::Result<ImportedHistory> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read export: {}", path.display()))?;
+    match format {
+        ImportFormat::SignalCli => read_signal_cli(&content, attachments_dir, user_id),
+        ImportFormat::SignalDesktop => read_signal_desktop(&content, attachments_dir, user_id),
+    }
+}
+
+/// Merges the imported history into the app data and saves the app data.
+///
+/// Messages are identified by sender and timestamp; messages which already exist are skipped.
+/// Existing names and group data are kept.
+pub fn import(
+    storage: &dyn Storage,
+    data: &mut AppData,
+    history: ImportedHistory,
+) -> anyhow::Result<ImportStats> {
+    let mut stats = ImportStats::default();
+
+    for (id, name) in history.names {
+        data.names.entry(id).or_insert(name);
+    }
+
+    for imported_channel in history.channels {
+        let Channel {
+            id,
+            name,
+            group_data,
+            messages,
+            ..
+        } = imported_channel;
+        let channel_idx = data
+            .channels
+            .items
+            .iter()
+            .position(|channel| channel.id == id);
+        let channel = match channel_idx {
+            Some(idx) => {
+                let channel = &mut data.channels.items[idx];
+                // all messages have to be loaded, otherwise saving the app data would drop the
+                // stored messages between the imported and the loaded ones
+                channel.messages.items = load_all_messages(storage, channel)?;
+                if channel.group_data.is_none() {
+                    channel.group_data = group_data;
+                }
+                channel
+            }
+            None => {
+                stats.new_channels += 1;
+                data.channels
+                    .items
+                    .push(new_channel(id, name, group_data, Vec::new()));
+                data.channels.items.last_mut().unwrap()
+            }
+        };
+        stats.new_messages += merge_messages(channel, messages.items);
+    }
+
+    storage.save_app_data(data)?;
+    Ok(stats)
+}
+
+fn new_channel(
+    id: ChannelId,
+    name: String,
+    group_data: Option<GroupData>,
+    messages: Vec<Message>,
+) -> Channel {
+    let typing = match id {
+        ChannelId::User(_) => TypingSet::SingleTyping(false),
+        ChannelId::Group(_) => TypingSet::GroupTyping(HashSet::new()),
+    };
+    Channel {
+        id,
+        name,
+        group_data,
+        messages: StatefulList::with_items(messages),
+        unread_messages: 0,
+        typing,
+        expire_timer: None,
+    }
+}
+
+/// Adds the messages which are not in the channel yet, and keeps the messages in chronological
+/// order. Returns the number of added messages.
+fn merge_messages(channel: &mut Channel, messages: Vec<Message>) -> usize {
+    let existing = &mut channel.messages.items;
+    let mut known: HashSet<(Uuid, u64)> = existing
+        .iter()
+        .map(|message| (message.from_id, message.arrived_at))
+        .collect();
+    let len_before = existing.len();
+    existing.extend(
+        messages
+            .into_iter()
+            .filter(|message| known.insert((message.from_id, message.arrived_at))),
+    );
+    let added = existing.len() - len_before;
+    if added > 0 {
+        existing.sort_by_key(|message| message.arrived_at);
+    }
+    added
+}
+
+/// Collects the channels and names while reading an export
+#[derive(Default)]
+struct HistoryBuilder {
+    history: ImportedHistory,
+    channel_indices: HashMap<ChannelId, usize>,
+}
+
+impl HistoryBuilder {
+    fn channel_mut(&mut self, id: ChannelId, name: impl FnOnce() -> String) -> &mut Channel {
+        let channels = &mut self.history.channels;
+        let idx = *self.channel_indices.entry(id).or_insert_with(|| {
+            channels.push(new_channel(id, name(), None, Vec::new()));
+            channels.len() - 1
+        });
+        &mut channels[idx]
+    }
+
+    fn add_name(&mut self, id: Uuid, name: Option<&str>) {
+        if let Some(name) = name.filter(|name| !name.is_empty()) {
+            self.history.names.insert(id, name.to_string());
+        }
+    }
+
+    fn name(&self, id: Uuid) -> Option<String> {
+        self.history.names.get(&id).cloned()
+    }
+
+    /// Sets or removes the reaction of the sender to the target message, given by its author and
+    /// timestamp. Reactions to messages which were not imported before are dropped.
+    fn react(
+        &mut self,
+        channel_id: ChannelId,
+        target: (Uuid, u64),
+        sender: Uuid,
+        emoji: Option<String>,
+    ) {
+        let message = self.channel_indices.get(&channel_id).and_then(|&idx| {
+            self.history.channels[idx]
+                .messages
+                .items
+                .iter_mut()
+                .find(|message| (message.from_id, message.arrived_at) == target)
+        });
+        if let Some(message) = message {
+            message.reactions.retain(|(from_id, _)| *from_id != sender);
+            if let Some(emoji) = emoji {
+                message.reactions.push((sender, emoji));
+            }
+        }
+    }
+
+    fn finish(self) -> ImportedHistory {
+        self.history
+    }
+}
+
+fn imported_message(from_id: Uuid, text: Option<String>, arrived_at: u64) -> Message {
+    Message {
+        from_id,
+        message: text.filter(|text| !text.is_empty()),
+        arrived_at,
+        quote: None,
+        attachments: Vec::new(),
+        reactions: Vec::new(),
+        // do not send any receipts for the imported messages
+        receipt: Receipt::Nothing,
+        deleted: false,
+        expires_at: None,
+        mentions: Default::default(),
+        group_change: None,
+    }
+}
+
+fn decode_base64<const N: usize>(encoded: &str) -> anyhow::Result<[u8; N]> {
+    base64::decode(encoded)?
+        .try_into()
+        .map_err(|bytes: Vec<u8>| anyhow!("expected {} bytes, got {}", N, bytes.len()))
+}
+
+// signal-cli
+
+#[derive(Deserialize)]
+struct CliLine {
+    envelope: CliEnvelope,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliEnvelope {
+    source_uuid: Option<Uuid>,
+    source_name: Option<String>,
+    source_number: Option<String>,
+    data_message: Option<CliDataMessage>,
+    sync_message: Option<CliSyncMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliSyncMessage {
+    sent_message: Option<CliSentMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliSentMessage {
+    destination_uuid: Option<Uuid>,
+    destination_number: Option<String>,
+    #[serde(flatten)]
+    message: CliDataMessage,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliDataMessage {
+    timestamp: u64,
+    message: Option<String>,
+    #[serde(default)]
+    attachments: Vec<CliAttachment>,
+    group_info: Option<CliGroupInfo>,
+    quote: Option<CliQuote>,
+    reaction: Option<CliReaction>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliGroupInfo {
+    group_id: String,
+    group_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliQuote {
+    id: u64,
+    author_uuid: Option<Uuid>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliReaction {
+    emoji: String,
+    target_author_uuid: Option<Uuid>,
+    target_sent_timestamp: u64,
+    #[serde(default)]
+    is_remove: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliAttachment {
+    id: String,
+    content_type: String,
+    #[serde(default)]
+    size: u32,
+}
+
+/// Reads the JSON lines written by signal-cli.
+///
+/// Envelopes without a data message or without a known sender are skipped, as are messages in
+/// groups of the deprecated version 1. The master keys of the groups are not part of the output of
+/// signal-cli; they are set as soon as the next message is received in the group.
+fn read_signal_cli(
+    content: &str,
+    attachments_dir: &Path,
+    user_id: Uuid,
+) -> anyhow::Result<ImportedHistory> {
+    let mut builder = HistoryBuilder::default();
+    for (line_idx, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: CliLine = serde_json::from_str(line)
+            .with_context(|| format!("invalid signal-cli output in line {}", line_idx + 1))?;
+        let envelope = line.envelope;
+
+        let source_id = match envelope.source_uuid {
+            Some(id) => id,
+            None => continue,
+        };
+        builder.add_name(
+            source_id,
+            envelope
+                .source_name
+                .as_deref()
+                .or(envelope.source_number.as_deref()),
+        );
+
+        let (from_id, contact, data_message) = match (envelope.data_message, envelope.sync_message)
+        {
+            (Some(data_message), _) => (
+                source_id,
+                Some((source_id, envelope.source_number)),
+                data_message,
+            ),
+            (
+                None,
+                Some(CliSyncMessage {
+                    sent_message: Some(sent_message),
+                }),
+            ) => (
+                user_id,
+                sent_message
+                    .destination_uuid
+                    .map(|id| (id, sent_message.destination_number)),
+                sent_message.message,
+            ),
+            _ => continue,
+        };
+
+        let channel_id = match (data_message.group_info.as_ref(), contact.as_ref()) {
+            (Some(group_info), _) => match decode_base64(&group_info.group_id) {
+                Ok(group_id) => ChannelId::Group(group_id),
+                Err(e) => {
+                    warn!("skipping message in unsupported group: {}", e);
+                    continue;
+                }
+            },
+            (None, Some((contact_id, _))) => ChannelId::User(*contact_id),
+            (None, None) => continue,
+        };
+        let channel_name = match (data_message.group_info.as_ref(), contact) {
+            (Some(group_info), _) => group_info
+                .group_name
+                .clone()
+                .unwrap_or_else(|| group_info.group_id.clone()),
+            (None, Some((contact_id, number))) => builder
+                .name(contact_id)
+                .or(number)
+                .unwrap_or_else(|| contact_id.to_string()),
+            (None, None) => unreachable!(),
+        };
+
+        if let Some(reaction) = data_message.reaction {
+            if let Some(target_author_id) = reaction.target_author_uuid {
+                builder.react(
+                    channel_id,
+                    (target_author_id, reaction.target_sent_timestamp),
+                    from_id,
+                    if reaction.is_remove {
+                        None
+                    } else {
+                        Some(reaction.emoji)
+                    },
+                );
+            }
+            continue;
+        }
+
+        let mut message = imported_message(from_id, data_message.message, data_message.timestamp);
+        message.quote = data_message.quote.and_then(|quote| {
+            let author_id = quote.author_uuid?;
+            Some(Box::new(imported_message(author_id, quote.text, quote.id)))
+        });
+        message.attachments = data_message
+            .attachments
+            .into_iter()
+            .map(|attachment| Attachment {
+                filename: attachments_dir.join(&attachment.id),
+                id: attachment.id,
+                content_type: attachment.content_type,
+                size: attachment.size,
+                download: None,
+            })
+            .collect();
+        builder
+            .channel_mut(channel_id, || channel_name)
+            .messages
+            .items
+            .push(message);
+    }
+    Ok(builder.finish())
+}
+
+// Signal Desktop
+
+#[derive(Deserialize)]
+struct DesktopExport {
+    conversations: Vec<DesktopConversation>,
+    messages: Vec<DesktopMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopConversation {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    uuid: Option<Uuid>,
+    e164: Option<String>,
+    name: Option<String>,
+    profile_full_name: Option<String>,
+    profile_name: Option<String>,
+    group_id: Option<String>,
+    master_key: Option<String>,
+    #[serde(default)]
+    members_v2: Vec<DesktopMember>,
+    #[serde(default)]
+    revision: u32,
+}
+
+#[derive(Deserialize)]
+struct DesktopMember {
+    uuid: Uuid,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopMessage {
+    conversation_id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "sent_at")]
+    sent_at: u64,
+    #[serde(alias = "sourceServiceId")]
+    source_uuid: Option<Uuid>,
+    body: Option<String>,
+    quote: Option<DesktopQuote>,
+    #[serde(default)]
+    attachments: Vec<DesktopAttachment>,
+    #[serde(default)]
+    reactions: Vec<DesktopReaction>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopQuote {
+    id: u64,
+    #[serde(alias = "authorAci")]
+    author_uuid: Option<Uuid>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopAttachment {
+    content_type: String,
+    file_name: Option<String>,
+    /// Relative to the attachments directory; missing if the attachment was not downloaded
+    path: Option<PathBuf>,
+    #[serde(default)]
+    size: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopReaction {
+    emoji: String,
+    /// Id of the conversation of the reacting user
+    from_id: String,
+}
+
+/// Reads the conversations and messages of Signal Desktop.
+///
+/// Only incoming and outgoing messages are imported; notifications like group changes are
+/// skipped, as are the groups of the deprecated version 1.
+fn read_signal_desktop(
+    content: &str,
+    attachments_dir: &Path,
+    user_id: Uuid,
+) -> anyhow::Result<ImportedHistory> {
+    let export: DesktopExport =
+        serde_json::from_str(content).context("invalid Signal Desktop export")?;
+
+    let mut builder = HistoryBuilder::default();
+    let mut channel_ids: HashMap<String, ChannelId> = HashMap::new();
+    let mut user_ids: HashMap<String, Uuid> = HashMap::new();
+    for conversation in export.conversations {
+        let name = conversation
+            .name
+            .clone()
+            .or_else(|| conversation.profile_full_name.clone())
+            .or_else(|| conversation.profile_name.clone())
+            .or_else(|| conversation.e164.clone());
+        match conversation.kind.as_str() {
+            "private" => {
+                let id = match conversation.uuid {
+                    Some(id) => id,
+                    None => continue,
+                };
+                builder.add_name(id, name.as_deref());
+                user_ids.insert(conversation.id.clone(), id);
+                channel_ids.insert(conversation.id, id.into());
+            }
+            "group" => {
+                let (channel_id, group_data) = match desktop_group(&conversation) {
+                    Ok(group) => group,
+                    Err(e) => {
+                        warn!("skipping unsupported group {}: {}", conversation.id, e);
+                        continue;
+                    }
+                };
+                let channel = builder.channel_mut(channel_id, || {
+                    name.unwrap_or_else(|| conversation.id.clone())
+                });
+                channel.group_data = group_data;
+                channel_ids.insert(conversation.id, channel_id);
+            }
+            _ => continue,
+        }
+    }
+
+    for desktop_message in export.messages {
+        let from_id = match desktop_message.kind.as_str() {
+            "incoming" => match desktop_message.source_uuid {
+                Some(id) => id,
+                None => continue,
+            },
+            "outgoing" => user_id,
+            _ => continue,
+        };
+        let channel_id = match channel_ids.get(&desktop_message.conversation_id) {
+            Some(&channel_id) => channel_id,
+            None => continue,
+        };
+
+        let mut message = imported_message(from_id, desktop_message.body, desktop_message.sent_at);
+        message.quote = desktop_message.quote.and_then(|quote| {
+            let author_id = quote.author_uuid?;
+            Some(Box::new(imported_message(author_id, quote.text, quote.id)))
+        });
+        message.attachments = desktop_message
+            .attachments
+            .into_iter()
+            .filter_map(|attachment| {
+                let path = attachment.path?;
+                Some(Attachment {
+                    id: attachment
+                        .file_name
+                        .unwrap_or_else(|| path.display().to_string()),
+                    content_type: attachment.content_type,
+                    filename: attachments_dir.join(path),
+                    size: attachment.size,
+                    download: None,
+                })
+            })
+            .collect();
+        message.reactions = desktop_message
+            .reactions
+            .into_iter()
+            .filter_map(|reaction| Some((*user_ids.get(&reaction.from_id)?, reaction.emoji)))
+            .collect();
+
+        // group channels were already added with the conversations
+        let name = match channel_id {
+            ChannelId::User(id) => builder.name(id).unwrap_or_else(|| id.to_string()),
+            ChannelId::Group(_) => desktop_message.conversation_id.clone(),
+        };
+        builder
+            .channel_mut(channel_id, || name)
+            .messages
+            .items
+            .push(message);
+    }
+    Ok(builder.finish())
+}
+
+/// Channel id and group data of a group conversation
+///
+/// The group data is only available if the conversation contains the master key of the group.
+fn desktop_group(
+    conversation: &DesktopConversation,
+) -> anyhow::Result<(ChannelId, Option<GroupData>)> {
+    match (
+        conversation.master_key.as_deref(),
+        conversation.group_id.as_deref(),
+    ) {
+        (Some(master_key), _) => {
+            let master_key_bytes: GroupMasterKeyBytes = decode_base64(master_key)?;
+            let group_data = GroupData {
+                master_key_bytes,
+                members: conversation
+                    .members_v2
+                    .iter()
+                    .map(|member| member.uuid)
+                    .collect(),
+                revision: conversation.revision,
+            };
+            Ok((
+                ChannelId::from_master_key_bytes(master_key_bytes)?,
+                Some(group_data),
+            ))
+        }
+        (None, Some(group_id)) => Ok((ChannelId::Group(decode_base64(group_id)?), None)),
+        (None, None) => bail!("missing group id"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test::InMemoryStorage;
+
+    use serde_json::json;
+
+    fn signal_cli_output(user_id: Uuid, contact_id: Uuid, group_id: &str) -> String {
+        let lines = [
+            json!({"envelope": {
+                "sourceUuid": contact_id,
+                "sourceName": "Marla Singer",
+                "sourceNumber": "+4915112345678",
+                "timestamp": 1642334397421u64,
+                "dataMessage": {
+                    "timestamp": 1642334397421u64,
+                    "message": "Who are you?",
+                    "attachments": [
+                        {"id": "abc", "contentType": "image/jpeg", "size": 5},
+                    ],
+                },
+            }}),
+            json!({"envelope": {
+                "sourceUuid": user_id,
+                "timestamp": 1642334397422u64,
+                "syncMessage": {"sentMessage": {
+                    "destinationUuid": contact_id,
+                    "timestamp": 1642334397422u64,
+                    "message": "Nobody",
+                    "quote": {
+                        "id": 1642334397421u64,
+                        "authorUuid": contact_id,
+                        "text": "Who are you?",
+                    },
+                }},
+            }}),
+            json!({"envelope": {
+                "sourceUuid": contact_id,
+                "timestamp": 1642334397423u64,
+                "dataMessage": {
+                    "timestamp": 1642334397423u64,
+                    "reaction": {
+                        "emoji": "👍",
+                        "targetAuthorUuid": user_id,
+                        "targetSentTimestamp": 1642334397422u64,
+                        "isRemove": false,
+                    },
+                },
+            }}),
+            json!({"envelope": {
+                "sourceUuid": contact_id,
+                "timestamp": 1642334397424u64,
+                "dataMessage": {
+                    "timestamp": 1642334397424u64,
+                    "message": "Hello group",
+                    "groupInfo": {"groupId": group_id, "groupName": "Project Mayhem"},
+                },
+            }}),
+            json!({"envelope": {
+                "sourceUuid": contact_id,
+                "timestamp": 1642334397425u64,
+                "receiptMessage": {"when": 1642334397425u64, "isRead": true},
+            }}),
+        ];
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    #[test]
+    fn test_read_signal_cli() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let contact_id = Uuid::new_v4();
+        let group_id = [42; 32];
+        let output = signal_cli_output(user_id, contact_id, &base64::encode(group_id));
+
+        let history = read_signal_cli(&output, Path::new("/attachments"), user_id)?;
+
+        assert_eq!(history.names[&contact_id], "Marla Singer");
+        assert_eq!(history.channels.len(), 2);
+
+        let contact_channel = &history.channels[0];
+        assert_eq!(contact_channel.id, ChannelId::User(contact_id));
+        assert_eq!(contact_channel.name, "Marla Singer");
+        let messages = &contact_channel.messages.items;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].from_id, contact_id);
+        assert_eq!(
+            messages[0].attachments[0].filename,
+            Path::new("/attachments/abc")
+        );
+        assert_eq!(messages[1].from_id, user_id);
+        assert_eq!(messages[1].message.as_deref(), Some("Nobody"));
+        assert_eq!(
+            messages[1].quote.as_ref().unwrap().arrived_at,
+            messages[0].arrived_at
+        );
+        assert_eq!(messages[1].reactions, vec![(contact_id, "👍".to_string())]);
+
+        let group_channel = &history.channels[1];
+        assert_eq!(group_channel.id, ChannelId::Group(group_id));
+        assert_eq!(group_channel.name, "Project Mayhem");
+        assert_eq!(group_channel.group_data, None);
+        assert_eq!(group_channel.messages.items.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_signal_desktop() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let contact_id = Uuid::new_v4();
+        let master_key = [42; 32];
+        let export = json!({
+            "conversations": [
+                {"id": "c1", "type": "private", "uuid": contact_id, "name": "Marla Singer"},
+                {"id": "c2", "type": "private", "uuid": user_id},
+                {
+                    "id": "c3",
+                    "type": "group",
+                    "name": "Project Mayhem",
+                    "masterKey": base64::encode(master_key),
+                    "membersV2": [{"uuid": contact_id}, {"uuid": user_id}],
+                    "revision": 3,
+                },
+            ],
+            "messages": [
+                {
+                    "conversationId": "c1",
+                    "type": "incoming",
+                    "sent_at": 1642334397421u64,
+                    "sourceUuid": contact_id,
+                    "body": "Who are you?",
+                    "reactions": [{"emoji": "👍", "fromId": "c2"}],
+                },
+                {
+                    "conversationId": "c1",
+                    "type": "outgoing",
+                    "sent_at": 1642334397422u64,
+                    "body": "Nobody",
+                    "attachments": [{
+                        "contentType": "image/jpeg",
+                        "fileName": "photo.jpg",
+                        "path": "ab/abc",
+                        "size": 5,
+                    }],
+                },
+                {"conversationId": "c3", "type": "group-v2-change", "sent_at": 1642334397423u64},
+                {
+                    "conversationId": "c3",
+                    "type": "incoming",
+                    "sent_at": 1642334397424u64,
+                    "sourceUuid": contact_id,
+                    "body": "Hello group",
+                },
+            ],
+        });
+
+        let history = read_signal_desktop(&export.to_string(), Path::new("/attachments"), user_id)?;
+
+        assert_eq!(history.channels.len(), 2);
+
+        let group_channel = &history.channels[0];
+        assert_eq!(
+            group_channel.id,
+            ChannelId::from_master_key_bytes(master_key)?
+        );
+        assert_eq!(group_channel.name, "Project Mayhem");
+        assert_eq!(
+            group_channel.group_data,
+            Some(GroupData {
+                master_key_bytes: master_key,
+                members: vec![contact_id, user_id],
+                revision: 3,
+            })
+        );
+        assert_eq!(group_channel.messages.items.len(), 1);
+
+        let contact_channel = &history.channels[1];
+        assert_eq!(contact_channel.id, ChannelId::User(contact_id));
+        assert_eq!(contact_channel.name, "Marla Singer");
+        let messages = &contact_channel.messages.items;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].reactions, vec![(user_id, "👍".to_string())]);
+        assert_eq!(messages[1].from_id, user_id);
+        assert_eq!(
+            messages[1].attachments[0].filename,
+            Path::new("/attachments/ab/abc")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_skips_existing_messages() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let contact_id = Uuid::new_v4();
+        let group_id = base64::encode([42; 32]);
+        let storage = InMemoryStorage::new();
+        let mut data = storage.load_app_data(user_id, "Tyler Durden".to_string())?;
+        data.channels.items.push(new_channel(
+            contact_id.into(),
+            "Marla".to_string(),
+            None,
+            vec![
+                imported_message(user_id, Some("Nobody".to_string()), 1642334397422),
+                imported_message(contact_id, Some("Later".to_string()), 1642334397430),
+            ],
+        ));
+        let output = signal_cli_output(user_id, contact_id, &group_id);
+
+        let history = read_signal_cli(&output, Path::new("/attachments"), user_id)?;
+        let stats = import(&storage, &mut data, history)?;
+        assert_eq!(
+            stats,
+            ImportStats {
+                new_channels: 1,
+                new_messages: 2,
+            }
+        );
+        let channel = &data.channels.items[0];
+        assert_eq!(channel.name, "Marla");
+        let timestamps: Vec<u64> = channel
+            .messages
+            .items
+            .iter()
+            .map(|message| message.arrived_at)
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![1642334397421, 1642334397422, 1642334397430]
+        );
+        assert_eq!(data.names[&contact_id], "Marla Singer");
+
+        let history = read_signal_cli(&output, Path::new("/attachments"), user_id)?;
+        let stats = import(&storage, &mut data, history)?;
+        assert_eq!(stats, ImportStats::default());
+
+        Ok(())
+    }
+}
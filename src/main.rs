@@ -1,8 +1,11 @@
 //! Signal Messenger client for terminal
 
 mod app;
+mod attachments;
 mod config;
 mod cursor;
+mod export;
+mod import;
 mod shortcuts;
 mod signal;
 mod storage;
@@ -10,7 +13,10 @@ mod ui;
 mod util;
 
 use app::{App, Event};
+use export::{ExportFormat, ExportOptions};
+use import::ImportFormat;
 
+use anyhow::{anyhow, bail};
 use crossterm::{
     event::{
         DisableMouseCapture, EnableMouseCapture, Event as CEvent, EventStream, KeyCode,
@@ -24,11 +30,16 @@ use structopt::StructOpt;
 use tokio_stream::StreamExt;
 use tui::{backend::CrosstermBackend, Terminal};
 
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crate::{signal::PresageManager, storage::JsonStorage};
+use crate::attachments::AttachmentStore;
+use crate::config::{Config, StorageBackend};
+use crate::signal::PresageManager;
+use crate::storage::{load_all_messages, JsonStorage, SqliteStorage, Storage};
 
 const TARGET_FPS: u64 = 144;
 const RECEIPT_TICK_PERIOD: u64 = 144;
@@ -44,6 +55,55 @@ struct Args {
     /// Relinks the device (helpful when device was unlinked)
     #[structopt(long)]
     relink: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Encrypts the existing plaintext data
+    ///
+    /// Requires an `[encryption]` section in the config.
+    Encrypt,
+    /// Exports the history of one or all channels
+    Export {
+        /// Format of the export: markdown, html or jsonl
+        #[structopt(short, long, default_value = "markdown")]
+        format: ExportFormat,
+        /// Name of the channel to export (default: all channels)
+        #[structopt(short, long)]
+        channel: Option<String>,
+        /// Copies the attachments into the export directory
+        #[structopt(long)]
+        copy_attachments: bool,
+        /// Directory to write the export into, one file per channel
+        output_dir: PathBuf,
+    },
+    /// Imports the history from signal-cli or Signal Desktop
+    ///
+    /// Messages which already exist are skipped. Do not run gurk while importing.
+    Import {
+        /// Format of the import: signal-cli (output of `signal-cli --output=json receive`) or
+        /// signal-desktop (JSON object with the `conversations` and `messages` of the decrypted
+        /// database)
+        #[structopt(short, long)]
+        format: ImportFormat,
+        /// Directory containing the attachments (default: the one of the client)
+        #[structopt(long)]
+        attachments_dir: Option<PathBuf>,
+        /// File to import
+        input: PathBuf,
+    },
+    /// Deletes the attachment files which are not referenced by any message
+    ///
+    /// Do not run gurk while collecting garbage.
+    Gc,
+    /// Salvages a corrupted data file of the json storage backend
+    ///
+    /// Channels and messages which can not be loaded are dropped. The corrupted file is kept, and
+    /// the dropped entries are written to a quarantine file next to it. Do not run gurk while
+    /// repairing.
+    Repair,
 }
 
 fn init_file_logger(verbosity: u8) -> anyhow::Result<()> {
@@ -76,9 +136,157 @@ async fn main() -> anyhow::Result<()> {
     }
     log_panics::init();
 
-    tokio::task::LocalSet::new()
-        .run_until(run_single_threaded(args.relink))
-        .await
+    match args.command {
+        Some(Command::Encrypt) => encrypt_storage(),
+        Some(Command::Export {
+            format,
+            channel,
+            copy_attachments,
+            output_dir,
+        }) => export_history(ExportOptions {
+            format,
+            channel_name: channel,
+            output_dir,
+            copy_attachments,
+        }),
+        Some(Command::Import {
+            format,
+            attachments_dir,
+            input,
+        }) => import_history(format, attachments_dir, input),
+        Some(Command::Gc) => collect_garbage(),
+        Some(Command::Repair) => repair_storage(),
+        None => {
+            tokio::task::LocalSet::new()
+                .run_until(run_single_threaded(args.relink))
+                .await
+        }
+    }
+}
+
+fn load_installed_config() -> anyhow::Result<Config> {
+    Config::load_installed()?
+        .ok_or_else(|| anyhow!("no config found: please run gurk to link the device first"))
+}
+
+fn encrypt_storage() -> anyhow::Result<()> {
+    let config = load_installed_config()?;
+    if config.storage != StorageBackend::Json {
+        bail!("encryption is only supported by the json storage backend");
+    }
+    let encryption = config.encryption.as_ref().ok_or_else(|| {
+        anyhow!("please add an `[encryption]` section to the config before encrypting the data")
+    })?;
+    let passphrase = encryption.passphrase(true)?;
+    let storage = JsonStorage::encrypted(
+        config.data_path.clone(),
+        config::fallback_data_path(),
+        &passphrase,
+    )?
+    .with_backups(config.backups);
+    storage.encrypt_plaintext_data()?;
+    println!("Encrypted data at: {}", config.data_path.display());
+    Ok(())
+}
+
+fn export_history(options: ExportOptions) -> anyhow::Result<()> {
+    let config = load_installed_config()?;
+    let user_id = signal::linked_user_id(config.signal_db_path.clone())?;
+    let storage = open_storage(&config)?;
+    let data = storage.load_app_data(user_id, config.user.name.clone())?;
+    for path in export::export(storage.as_ref(), &data, user_id, &options)? {
+        println!("Exported: {}", path.display());
+    }
+    Ok(())
+}
+
+fn import_history(
+    format: ImportFormat,
+    attachments_dir: Option<PathBuf>,
+    input: PathBuf,
+) -> anyhow::Result<()> {
+    let attachments_dir = attachments_dir
+        .or_else(|| format.default_attachments_dir())
+        .ok_or_else(|| anyhow!("could not find attachments directory: please specify it"))?;
+    let config = load_installed_config()?;
+    let user_id = signal::linked_user_id(config.signal_db_path.clone())?;
+    let history = import::read(format, &input, &attachments_dir, user_id)?;
+    let storage = open_storage(&config)?;
+    let mut data = storage.load_app_data(user_id, config.user.name.clone())?;
+    let stats = import::import(storage.as_ref(), &mut data, history)?;
+    println!(
+        "Imported {} new messages, {} new channels",
+        stats.new_messages, stats.new_channels
+    );
+    Ok(())
+}
+
+fn repair_storage() -> anyhow::Result<()> {
+    let config = load_installed_config()?;
+    if config.storage != StorageBackend::Json {
+        bail!("repairing is only supported by the json storage backend");
+    }
+    match open_json_storage(&config)?.repair()? {
+        Some(report) => print!("{}", report),
+        None => println!(
+            "Data at {} loads fine: nothing to repair",
+            config.data_path.display()
+        ),
+    }
+    Ok(())
+}
+
+fn collect_garbage() -> anyhow::Result<()> {
+    let config = load_installed_config()?;
+    let user_id = signal::linked_user_id(config.signal_db_path.clone())?;
+    let storage = open_storage(&config)?;
+    let data = storage.load_app_data(user_id, config.user.name.clone())?;
+    if data.channels.items.is_empty() {
+        bail!("no channels found: refusing to delete all attachments");
+    }
+    let mut referenced = HashSet::new();
+    for channel in &data.channels.items {
+        for message in load_all_messages(storage.as_ref(), channel)? {
+            referenced.extend(
+                message
+                    .attachments
+                    .into_iter()
+                    .map(|attachment| attachment.filename),
+            );
+        }
+    }
+    let stats = AttachmentStore::from_config(&config)?.gc(&referenced)?;
+    println!(
+        "Deleted {} unreferenced attachments, freed {} bytes",
+        stats.deleted_files, stats.freed_bytes
+    );
+    Ok(())
+}
+
+fn open_storage(config: &Config) -> anyhow::Result<Box<dyn Storage>> {
+    if config.encryption.is_some() && config.storage != StorageBackend::Json {
+        bail!("encryption is only supported by the json storage backend");
+    }
+    let json_storage = open_json_storage(config)?;
+    Ok(match config.storage {
+        StorageBackend::Json => Box::new(json_storage),
+        StorageBackend::Sqlite => Box::new(SqliteStorage::open(
+            &config.sqlite_path,
+            Some(json_storage),
+        )?),
+    })
+}
+
+fn open_json_storage(config: &Config) -> anyhow::Result<JsonStorage> {
+    Ok(match config.encryption.as_ref() {
+        Some(encryption) => JsonStorage::encrypted(
+            config.data_path.clone(),
+            config::fallback_data_path(),
+            &encryption.passphrase(false)?,
+        )?,
+        None => JsonStorage::new(config.data_path.clone(), config::fallback_data_path()),
+    }
+    .with_backups(config.backups))
 }
 
 async fn is_online() -> bool {
@@ -89,11 +297,12 @@ async fn is_online() -> bool {
 
 async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
     let (signal_manager, config) = signal::ensure_linked_device(relink).await?;
-    let storage = JsonStorage::new(config.data_path.clone(), config::fallback_data_path());
+    let storage = open_storage(&config)?;
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(100);
     let mut app = App::try_new(
         config,
         Box::new(PresageManager::new(signal_manager.clone())),
-        Box::new(storage),
+        storage,
     )?;
 
     enable_raw_mode()?;
@@ -104,7 +313,6 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
     let mut stdout = std::io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(100);
     tokio::spawn({
         let tx = tx.clone();
         async move {
@@ -136,6 +344,10 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
                 match signal_manager.receive_messages().await {
                     Ok(messages) => {
                         info!("connected and listening for incoming messages");
+                        inner_tx
+                            .send(Event::Connected)
+                            .await
+                            .expect("logic error: events channel closed");
                         messages
                     }
                     Err(e) => {
@@ -159,7 +371,11 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
                     .await
                     .expect("logic error: events channel closed")
             }
-            info!("messages channel disconnected. trying to reconnect.")
+            info!("messages channel disconnected. trying to reconnect.");
+            inner_tx
+                .send(Event::Disconnected)
+                .await
+                .expect("logic error: events channel closed");
         }
     });
 
@@ -209,6 +425,17 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
         match rx.recv().await {
             Some(Event::Tick) => {
                 let _ = app.step_receipts();
+                app.step_sends();
+                app.step_downloads();
+                if let Err(e) = app.step_outbox() {
+                    error!("failed to send the outbox: {:#}", e);
+                }
+                if let Err(e) = app.step_retention() {
+                    error!("failed to apply retention policies: {:#}", e);
+                }
+                if let Err(e) = app.remove_expired_messages() {
+                    error!("failed to remove disappearing messages: {:#}", e);
+                }
             }
             Some(Event::Click(event)) => match event.kind {
                 MouseEventKind::Down(MouseButton::Left) => {
@@ -221,7 +448,7 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
                     {
                         app.data.channels.state.select(Some(channel_idx));
                         if app.reset_unread_messages() {
-                            app.save().unwrap();
+                            app.save_selected_channel().unwrap();
                         }
                     }
                 }
@@ -332,6 +559,12 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
                     error!("failed on incoming message: {}", e);
                 }
             }
+            Some(Event::Connected) => {
+                if let Err(e) = app.on_connected() {
+                    error!("failed to send queued messages: {:#}", e);
+                }
+            }
+            Some(Event::Disconnected) => app.on_disconnected(),
             Some(Event::Resize { .. }) | Some(Event::Redraw) => {
                 // will just redraw the app
             }
@@ -348,6 +581,7 @@ async fn run_single_threaded(relink: bool) -> anyhow::Result<()> {
         if app.should_quit {
             break;
         }
+        app.update_typing();
     }
 
     execute!(
@@ -12,7 +12,7 @@ pub static SHORTCUTS: &[ShortCut] = &[
     },
     ShortCut {
         event: "tab",
-        description: "Sends emoji from input line as reaction on selected message.",
+        description: "Completes @name of a group member, otherwise sends emoji from input line as reaction on selected message.",
     },
     ShortCut {
         event: "alt+enter",
@@ -58,6 +58,27 @@ pub static SHORTCUTS: &[ShortCut] = &[
         event: "Esc",
         description: "Reset message selection.",
     },
+    ShortCut {
+        event: "ctrl+r",
+        description: "Retry sending the selected message, if it failed or is queued.",
+    },
+    ShortCut {
+        event: "ctrl+d",
+        description: "Discard the selected message, if it failed to send or is queued.",
+    },
+    ShortCut {
+        event: "alt+d",
+        description:
+            "Delete the selected message for everyone, if it is ours and at most a day old.",
+    },
+    ShortCut {
+        event: "alt+t",
+        description: "Switch to the next longer timer of disappearing messages of the contact, or disable it.",
+    },
+    ShortCut {
+        event: "alt+x",
+        description: "Block the selected channel, or unblock it if it is blocked.",
+    },
     ShortCut {
         event: "alt+Up / PgUp",
         description: "Select previous message.",
@@ -1,23 +1,27 @@
-use crate::app::{Channel, ChannelId, GroupData, Message, Receipt};
+use crate::app::{Channel, ChannelId, Contact, GroupData, Message, Receipt, TypingAction};
 use crate::config::{self, Config};
 use crate::util::utc_now_timestamp_msec;
 
-use anyhow::anyhow;
 use anyhow::{bail, Context as _};
 use async_trait::async_trait;
-use chrono::Utc;
-use gh_emoji::Replacer;
 use log::error;
 use presage::prelude::content::Reaction;
-use presage::prelude::proto::data_message::Quote;
-use presage::prelude::proto::{AttachmentPointer, ReceiptMessage};
+use presage::prelude::proto::attachment_pointer::AttachmentIdentifier;
+use presage::prelude::proto::data_message::body_range::AssociatedValue;
+use presage::prelude::proto::data_message::{BodyRange, Delete, Flags, Quote};
+use presage::prelude::proto::sync_message::{self, Blocked};
+use presage::prelude::proto::{AttachmentPointer, ReceiptMessage, SyncMessage, TypingMessage};
 use presage::prelude::{
     AttachmentSpec, ContentBody, DataMessage, GroupContextV2, GroupMasterKey, SignalServers,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
 
 pub const GROUP_MASTER_KEY_LEN: usize = 32;
 pub const GROUP_IDENTIFIER_LEN: usize = 32;
@@ -28,53 +32,185 @@ pub type GroupIdentifierBytes = [u8; GROUP_IDENTIFIER_LEN];
 /// Signal Manager backed by a `sled` store.
 pub type Manager = presage::Manager<presage::SledConfigStore>;
 
+/// Download of the data of an attachment, which does not borrow the signal manager
+pub type AttachmentDownload = Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>>>>;
+
+/// Sending of a message, a reaction or a receipt, which does not borrow the signal manager
+pub type Sending = Pin<Box<dyn Future<Output = anyhow::Result<()>>>>;
+
 #[async_trait(?Send)]
 pub trait SignalManager {
     fn user_id(&self) -> Uuid;
 
     async fn contact_name(&self, id: Uuid, profile_key: [u8; 32]) -> Option<String>;
 
+    /// Requests the contacts from the primary device.
+    ///
+    /// They arrive later in a sync message, after which they can be listed by `contacts`.
+    fn request_contacts_sync(&self) -> Sending;
+
+    /// Contacts synced from the primary device
+    fn contacts(&self) -> anyhow::Result<Vec<(Uuid, Contact)>>;
+
+    /// Requests the blocked users and groups from the primary device.
+    ///
+    /// They arrive later in a sync message.
+    fn request_blocked_sync(&self) -> Sending;
+
+    /// Sends the blocked users and groups to our other devices.
+    fn send_blocked(&self, blocked: &HashSet<ChannelId>) -> Sending;
+
     async fn resolve_group(
         &mut self,
         master_key_bytes: GroupMasterKeyBytes,
     ) -> anyhow::Result<ResolvedGroup>;
 
-    async fn save_attachment(
-        &mut self,
-        attachment_pointer: AttachmentPointer,
-    ) -> anyhow::Result<Attachment>;
+    /// Downloads the data of the attachment.
+    ///
+    /// The download can be awaited in the background while the manager is used otherwise.
+    fn download_attachment(&self, attachment_pointer: AttachmentPointer) -> AttachmentDownload;
 
-    fn send_receipt(&self, sender_uuid: Uuid, timestamps: Vec<u64>, receipt: Receipt);
+    /// Sends the receipt for the messages with the given timestamps to their sender.
+    fn send_receipt(&self, sender_uuid: Uuid, timestamps: Vec<u64>, receipt: Receipt) -> Sending;
 
+    /// Sends the message to the channel, with its arrival time as timestamp.
+    ///
+    /// Only the text and the quote of the message are sent, together with the attachments. The
+    /// message disappears after the expiration timer of the channel.
     fn send_text(
         &self,
         channel: &Channel,
-        text: String,
-        quote_message: Option<&Message>,
+        message: &Message,
         attachments: Vec<(AttachmentSpec, Vec<u8>)>,
-    ) -> Message;
+    ) -> Sending;
+
+    /// Sends the reaction to the message of the channel, or its removal.
+    fn send_reaction(
+        &self,
+        channel: &Channel,
+        message: &Message,
+        emoji: String,
+        remove: bool,
+    ) -> Sending;
+
+    /// Deletes our message of the channel for everyone.
+    fn send_delete(&self, channel: &Channel, message: &Message) -> Sending;
+
+    /// Sets the timer of disappearing messages of the contact channel in seconds, or disables it.
+    ///
+    /// Fails for groups, whose timer can only be changed by a group change.
+    fn send_expire_timer(&self, channel: &Channel, expire_timer: Option<u32>) -> Sending;
+
+    /// Lets the other members of the channel know that we started or stopped typing.
+    fn send_typing(&self, channel: &Channel, action: TypingAction) -> Sending;
+}
 
-    fn send_reaction(&self, channel: &Channel, message: &Message, emoji: String, remove: bool);
+/// Error of presage while talking to Signal, e.g. because the connection to it was lost
+///
+/// presage reports a lost connection as one of several websocket and service errors. Sends which
+/// fail with any of them may succeed later, in contrast to sends which gurk itself refuses.
+#[derive(Debug)]
+pub struct PresageError(anyhow::Error);
+
+impl fmt::Display for PresageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for PresageError {}
+
+impl From<presage::Error> for PresageError {
+    fn from(error: presage::Error) -> Self {
+        Self(error.into())
+    }
+}
+
+/// Recipients of a send to a channel
+enum Recipients {
+    User(Uuid),
+    /// Members of the group other than us
+    Group {
+        group_id: GroupIdentifierBytes,
+        context: GroupContextV2,
+        members: Vec<Uuid>,
+    },
 }
 
 pub struct ResolvedGroup {
     pub name: String,
     pub group_data: GroupData,
     pub profile_keys: Vec<Vec<u8>>,
+    /// Timer of disappearing messages in seconds, if enabled
+    pub expire_timer: Option<u32>,
 }
 
 pub struct PresageManager {
     manager: Manager,
-    emoji_replacer: Replacer,
 }
 
 impl PresageManager {
     pub fn new(manager: Manager) -> Self {
-        Self {
-            manager,
-            emoji_replacer: Replacer::new(),
+        Self { manager }
+    }
+
+    /// Recipients of the sends to the channel
+    fn recipients(&self, channel: &Channel) -> anyhow::Result<Recipients> {
+        match (channel.id, channel.group_data.as_ref()) {
+            (ChannelId::User(uuid), _) => Ok(Recipients::User(uuid)),
+            (ChannelId::Group(group_id), Some(group_data)) => {
+                let self_uuid = self.user_id();
+                Ok(Recipients::Group {
+                    group_id,
+                    context: GroupContextV2 {
+                        master_key: Some(group_data.master_key_bytes.to_vec()),
+                        revision: Some(group_data.revision),
+                        ..Default::default()
+                    },
+                    members: group_data
+                        .members
+                        .iter()
+                        .copied()
+                        .filter(|uuid| *uuid != self_uuid)
+                        .collect(),
+                })
+            }
+            (ChannelId::Group(_), None) => {
+                bail!("cannot send to broken channel without group data")
+            }
         }
     }
+
+    /// Sends the content to the contact or the other members of the group of the channel.
+    fn send_to_channel(
+        &self,
+        channel: &Channel,
+        content: ContentBody,
+        timestamp: u64,
+        error_context: String,
+    ) -> Sending {
+        let manager = self.manager.clone();
+        let recipients = self.recipients(channel);
+        Box::pin(async move {
+            send_to_recipients(&manager, recipients?, content, timestamp)
+                .await
+                .context(error_context)
+        })
+    }
+
+    /// Sends the sync message to our other devices.
+    fn send_sync_message(&self, sync_message: SyncMessage, error_context: &'static str) -> Sending {
+        let manager = self.manager.clone();
+        let user_id = self.user_id();
+        let timestamp = utc_now_timestamp_msec();
+        Box::pin(async move {
+            let body = ContentBody::SynchronizeMessage(sync_message);
+            manager
+                .send_message(user_id, body, timestamp)
+                .await
+                .context(error_context)
+        })
+    }
 }
 
 #[async_trait(?Send)]
@@ -83,7 +219,7 @@ impl SignalManager for PresageManager {
         self.manager.uuid()
     }
 
-    fn send_receipt(&self, _sender_uuid: Uuid, timestamps: Vec<u64>, receipt: Receipt) {
+    fn send_receipt(&self, sender_uuid: Uuid, timestamps: Vec<u64>, receipt: Receipt) -> Sending {
         let now_timestamp = utc_now_timestamp_msec();
         let data_message = ReceiptMessage {
             r#type: Some(receipt.to_i32()),
@@ -91,158 +227,135 @@ impl SignalManager for PresageManager {
         };
 
         let manager = self.manager.clone();
-        tokio::task::spawn_local(async move {
+        Box::pin(async move {
             let body = ContentBody::ReceiptMessage(data_message);
-            if let Err(e) = manager
-                .send_message(_sender_uuid, body, now_timestamp)
+            manager
+                .send_message(sender_uuid, body, now_timestamp)
                 .await
-            {
-                log::error!("Failed to send message to {}: {}", _sender_uuid, e);
-            }
-        });
+                .with_context(|| format!("failed to send receipt to {}", sender_uuid))
+        })
     }
 
     fn send_text(
         &self,
         channel: &Channel,
-        text: String,
-        quote_message: Option<&Message>,
+        message: &Message,
         attachments: Vec<(AttachmentSpec, Vec<u8>)>,
-    ) -> Message {
-        let mut message: String = self.emoji_replacer.replace_all(&text).into_owned();
-        let has_attachments = !attachments.is_empty();
-
-        let timestamp = utc_now_timestamp_msec();
-
-        let quote = quote_message.map(|message| Quote {
-            id: Some(message.arrived_at),
-            author_uuid: Some(message.from_id.to_string()),
-            text: message.message.clone(),
+    ) -> Sending {
+        let timestamp = message.arrived_at;
+        let quote = message.quote.as_ref().map(|quote| Quote {
+            id: Some(quote.arrived_at),
+            author_uuid: Some(quote.from_id.to_string()),
+            text: quote.message.clone(),
+            body_ranges: body_ranges(quote),
             ..Default::default()
         });
-        let quote_message = quote.clone().and_then(Message::from_quote).map(Box::new);
 
         let mut data_message = DataMessage {
-            body: Some(message.clone()),
+            body: message.message.clone(),
             timestamp: Some(timestamp),
             quote,
+            body_ranges: body_ranges(message),
+            expire_timer: channel.expire_timer,
             ..Default::default()
         };
 
-        match channel.id {
-            ChannelId::User(uuid) => {
-                let manager = self.manager.clone();
-                tokio::task::spawn_local(async move {
-                    upload_attachments(&manager, attachments, &mut data_message).await;
-
-                    let body = ContentBody::DataMessage(data_message);
-                    if let Err(e) = manager.send_message(uuid, body, timestamp).await {
-                        // TODO: Proper error handling
-                        log::error!("Failed to send message to {}: {}", uuid, e);
-                    }
-                });
-            }
-            ChannelId::Group(_) => {
-                if let Some(group_data) = channel.group_data.as_ref() {
-                    let manager = self.manager.clone();
-                    let self_uuid = self.user_id();
-
-                    data_message.group_v2 = Some(GroupContextV2 {
-                        master_key: Some(group_data.master_key_bytes.to_vec()),
-                        revision: Some(group_data.revision),
-                        ..Default::default()
-                    });
-
-                    let recipients = group_data.members.clone().into_iter();
-
-                    tokio::task::spawn_local(async move {
-                        upload_attachments(&manager, attachments, &mut data_message).await;
-
-                        let recipients =
-                            recipients.filter(|uuid| *uuid != self_uuid).map(Into::into);
-                        if let Err(e) = manager
-                            .send_message_to_group(recipients, data_message, timestamp)
-                            .await
-                        {
-                            // TODO: Proper error handling
-                            log::error!("Failed to send group message: {}", e);
-                        }
-                    });
-                } else {
-                    error!("cannot send to broken channel without group data");
-                }
-            }
-        }
-
-        if has_attachments && message.is_empty() {
-            // TODO: Temporary solution until we start rendering attachments
-            message = "<attachment>".to_string();
-        }
-
-        Message {
-            from_id: self.user_id(),
-            message: Some(message),
-            arrived_at: timestamp,
-            quote: quote_message,
-            attachments: Default::default(),
-            reactions: Default::default(),
-            receipt: Receipt::Sent,
-        }
+        // attachments are uploaded before the message referencing them is sent
+        let manager = self.manager.clone();
+        let recipients = self.recipients(channel);
+        let error_context = format!("failed to send message to {}", channel.name);
+        Box::pin(async move {
+            let recipients = recipients?;
+            upload_attachments(&manager, attachments, &mut data_message).await?;
+            let content = ContentBody::DataMessage(data_message);
+            send_to_recipients(&manager, recipients, content, timestamp)
+                .await
+                .context(error_context)
+        })
     }
 
-    fn send_reaction(&self, channel: &Channel, message: &Message, emoji: String, remove: bool) {
+    fn send_reaction(
+        &self,
+        channel: &Channel,
+        message: &Message,
+        emoji: String,
+        remove: bool,
+    ) -> Sending {
         let timestamp = utc_now_timestamp_msec();
-        let target_author_uuid = message.from_id;
-        let target_sent_timestamp = message.arrived_at;
-
-        let mut data_message = DataMessage {
+        let data_message = DataMessage {
             reaction: Some(Reaction {
                 emoji: Some(emoji.clone()),
                 remove: Some(remove),
-                target_author_uuid: Some(target_author_uuid.to_string()),
-                target_sent_timestamp: Some(target_sent_timestamp),
+                target_author_uuid: Some(message.from_id.to_string()),
+                target_sent_timestamp: Some(message.arrived_at),
             }),
             ..Default::default()
         };
 
-        match (channel.id, channel.group_data.as_ref()) {
-            (ChannelId::User(uuid), _) => {
-                let manager = self.manager.clone();
-                let body = ContentBody::DataMessage(data_message);
-                tokio::task::spawn_local(async move {
-                    if let Err(e) = manager.send_message(uuid, body, timestamp).await {
-                        // TODO: Proper error handling
-                        log::error!("failed to send reaction {} to {}: {}", &emoji, uuid, e);
-                    }
-                });
-            }
-            (ChannelId::Group(_), Some(group_data)) => {
-                let manager = self.manager.clone();
-                let self_uuid = self.user_id();
+        self.send_to_channel(
+            channel,
+            ContentBody::DataMessage(data_message),
+            timestamp,
+            format!("failed to send reaction {} to {}", emoji, channel.name),
+        )
+    }
 
-                data_message.group_v2 = Some(GroupContextV2 {
-                    master_key: Some(group_data.master_key_bytes.to_vec()),
-                    revision: Some(group_data.revision),
-                    ..Default::default()
-                });
-
-                let recipients = group_data.members.clone().into_iter();
-
-                tokio::task::spawn_local(async move {
-                    let recipients = recipients.filter(|uuid| *uuid != self_uuid).map(Into::into);
-                    if let Err(e) = manager
-                        .send_message_to_group(recipients, data_message, timestamp)
-                        .await
-                    {
-                        // TODO: Proper error handling
-                        log::error!("failed to send group reaction {}: {}", &emoji, e);
-                    }
-                });
-            }
-            _ => {
-                error!("cannot send to broken channel without group data");
-            }
+    fn send_delete(&self, channel: &Channel, message: &Message) -> Sending {
+        let timestamp = utc_now_timestamp_msec();
+        let data_message = DataMessage {
+            delete: Some(Delete {
+                target_sent_timestamp: Some(message.arrived_at),
+            }),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+
+        self.send_to_channel(
+            channel,
+            ContentBody::DataMessage(data_message),
+            timestamp,
+            format!("failed to delete message for {}", channel.name),
+        )
+    }
+
+    fn send_expire_timer(&self, channel: &Channel, expire_timer: Option<u32>) -> Sending {
+        // v2 groups ignore the expiration timer update, it is part of the group state instead
+        if let ChannelId::Group(_) = channel.id {
+            return Box::pin(async {
+                bail!("cannot set expiration timer of group without a group change")
+            });
         }
+
+        let timestamp = utc_now_timestamp_msec();
+        let data_message = DataMessage {
+            flags: Some(Flags::ExpirationTimerUpdate as u32),
+            expire_timer: Some(expire_timer.unwrap_or(0)),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+
+        self.send_to_channel(
+            channel,
+            ContentBody::DataMessage(data_message),
+            timestamp,
+            format!("failed to set expiration timer for {}", channel.name),
+        )
+    }
+
+    fn send_typing(&self, channel: &Channel, action: TypingAction) -> Sending {
+        let timestamp = utc_now_timestamp_msec();
+        let typing_message = TypingMessage {
+            timestamp: Some(timestamp),
+            action: Some(action.to_i32()),
+            group_id: None,
+        };
+
+        self.send_to_channel(
+            channel,
+            ContentBody::TypingMessage(typing_message),
+            timestamp,
+            format!("failed to send typing to {}", channel.name),
+        )
     }
 
     async fn contact_name(&self, id: Uuid, profile_key: [u8; 32]) -> Option<String> {
@@ -255,6 +368,73 @@ impl SignalManager for PresageManager {
         }
     }
 
+    fn request_contacts_sync(&self) -> Sending {
+        let manager = self.manager.clone();
+        Box::pin(async move {
+            manager
+                .request_contacts_sync()
+                .await
+                .context("failed to request contacts sync")
+        })
+    }
+
+    fn contacts(&self) -> anyhow::Result<Vec<(Uuid, Contact)>> {
+        let mut contacts = Vec::new();
+        for contact in self.manager.get_contacts()? {
+            let contact = contact?;
+            let uuid = match contact.address.uuid {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+            let phone_number = contact.address.phonenumber.map(|phone_number| {
+                phone_number
+                    .format()
+                    .mode(phonenumber::Mode::E164)
+                    .to_string()
+            });
+            contacts.push((
+                uuid,
+                Contact {
+                    phone_number,
+                    name: contact.name,
+                    profile_key: contact.profile_key,
+                    blocked: contact.blocked,
+                },
+            ));
+        }
+        Ok(contacts)
+    }
+
+    fn request_blocked_sync(&self) -> Sending {
+        let sync_message = SyncMessage {
+            request: Some(sync_message::Request {
+                r#type: Some(sync_message::request::Type::Blocked as i32),
+            }),
+            ..Default::default()
+        };
+        self.send_sync_message(sync_message, "failed to request blocked sync")
+    }
+
+    fn send_blocked(&self, blocked: &HashSet<ChannelId>) -> Sending {
+        let mut uuids = Vec::new();
+        let mut group_ids = Vec::new();
+        for channel_id in blocked {
+            match channel_id {
+                ChannelId::User(uuid) => uuids.push(uuid.to_string()),
+                ChannelId::Group(group_id) => group_ids.push(group_id.to_vec()),
+            }
+        }
+        let sync_message = SyncMessage {
+            blocked: Some(Blocked {
+                numbers: Vec::new(),
+                uuids,
+                group_ids,
+            }),
+            ..Default::default()
+        };
+        self.send_sync_message(sync_message, "failed to send blocked users and groups")
+    }
+
     async fn resolve_group(
         &mut self,
         master_key_bytes: GroupMasterKeyBytes,
@@ -274,6 +454,10 @@ impl SignalManager for PresageManager {
         }
 
         let name = decrypted_group.title;
+        let expire_timer = decrypted_group
+            .disappearing_messages_timer
+            .map(|timer| timer.duration)
+            .filter(|&duration| duration > 0);
         let group_data = GroupData {
             master_key_bytes,
             members,
@@ -284,74 +468,201 @@ impl SignalManager for PresageManager {
             name,
             group_data,
             profile_keys,
+            expire_timer,
         })
     }
 
-    async fn save_attachment(
-        &mut self,
-        attachment_pointer: AttachmentPointer,
-    ) -> anyhow::Result<Attachment> {
-        let data_dir = dirs::data_dir()
-            .ok_or_else(|| anyhow!("could not find data directory"))?
-            .join("gurk");
-        let attachment_data = self.manager.get_attachment(&attachment_pointer).await?;
-
-        let date = Utc::now().to_rfc3339();
-        let filename = match attachment_pointer.content_type.as_deref() {
-            Some("image/jpeg") => format!("signal-{}.jpg", date),
-            Some("image/gif") => format!("signal-{}.gif", date),
-            Some("image/png") => format!("signal-{}.png", date),
-            Some(mimetype) => {
-                log::warn!("unsupported attachment mimetype: {}", mimetype);
-                format!("signal-{}", date)
-            }
-            None => {
-                format!("signal-{}", date)
-            }
-        };
+    fn download_attachment(&self, attachment_pointer: AttachmentPointer) -> AttachmentDownload {
+        let manager = self.manager.clone();
+        Box::pin(async move { Ok(manager.get_attachment(&attachment_pointer).await?) })
+    }
+}
 
-        let filepath = data_dir.join(filename);
-        std::fs::write(&filepath, &attachment_data)?;
+/// Sends the content to the recipients.
+///
+/// Data messages are sent to the group at once, together with its context. Other content is sent
+/// to each member separately.
+async fn send_to_recipients(
+    manager: &Manager,
+    recipients: Recipients,
+    content: ContentBody,
+    timestamp: u64,
+) -> anyhow::Result<()> {
+    match (recipients, content) {
+        (Recipients::User(uuid), content) => {
+            manager
+                .send_message(uuid, content, timestamp)
+                .await
+                .map_err(PresageError::from)?;
+        }
+        (
+            Recipients::Group {
+                context, members, ..
+            },
+            ContentBody::DataMessage(mut data_message),
+        ) => {
+            data_message.group_v2 = Some(context);
+            let recipients = members.into_iter().map(Into::into);
+            manager
+                .send_message_to_group(recipients, data_message, timestamp)
+                .await
+                .map_err(PresageError::from)?;
+        }
+        (
+            Recipients::Group {
+                group_id, members, ..
+            },
+            ContentBody::TypingMessage(mut typing_message),
+        ) => {
+            typing_message.group_id = Some(group_id.to_vec());
+            // a member which cannot be reached must not stop the typing for the others
+            for uuid in members {
+                let content = ContentBody::TypingMessage(typing_message.clone());
+                if let Err(e) = manager.send_message(uuid, content, timestamp).await {
+                    error!("failed to send typing to {}: {}", uuid, e);
+                }
+            }
+        }
+        (Recipients::Group { .. }, _) => bail!("cannot send this content to a group"),
+    }
+    Ok(())
+}
 
-        Ok(Attachment {
-            id: date,
-            content_type: attachment_pointer.content_type.unwrap(),
-            filename: filepath,
-            size: attachment_pointer.size.unwrap(),
+/// Body ranges of the mentions in the message, which count UTF-16 code units
+fn body_ranges(message: &Message) -> Vec<BodyRange> {
+    let text = message.message.as_deref().unwrap_or_default();
+    message
+        .mentions
+        .iter()
+        .filter_map(|mention| {
+            let start = text.get(..mention.start)?.encode_utf16().count();
+            Some(BodyRange {
+                start: Some(start as u32),
+                length: Some(1),
+                associated_value: Some(AssociatedValue::MentionUuid(mention.uuid.to_string())),
+            })
         })
-    }
+        .collect()
 }
 
 async fn upload_attachments(
     manager: &presage::Manager<presage::SledConfigStore>,
     attachments: Vec<(AttachmentSpec, Vec<u8>)>,
     data_message: &mut DataMessage,
-) {
-    match manager.upload_attachments(attachments).await {
-        Ok(attachment_pointers) => {
-            data_message.attachments = attachment_pointers
-                .into_iter()
-                .filter_map(|res| {
-                    if let Err(e) = res.as_ref() {
-                        error!("failed to upload attachment: {}", e);
-                    }
-                    res.ok()
-                })
-                .collect();
-        }
-        Err(e) => {
-            error!("failed to upload attachments: {}", e);
-        }
+) -> anyhow::Result<()> {
+    if attachments.is_empty() {
+        return Ok(());
     }
+    data_message.attachments = manager
+        .upload_attachments(attachments)
+        .await
+        .map_err(PresageError::from)
+        .context("failed to upload attachments")?
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(PresageError::from)
+        .context("failed to upload attachment")?;
+    Ok(())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Attachment {
+    /// Hash of the content, empty if not downloaded yet
     pub id: String,
     pub content_type: String,
+    /// Path of the downloaded file, empty if not downloaded yet
     pub filename: PathBuf,
     pub size: u32,
+    /// Pending download of the attachment, if it is not downloaded yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<PendingDownload>,
+}
+
+impl Attachment {
+    /// Attachment which is downloaded later
+    pub fn pending(attachment_pointer: &AttachmentPointer) -> Self {
+        Self {
+            id: String::new(),
+            content_type: attachment_pointer
+                .content_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            filename: PathBuf::new(),
+            size: attachment_pointer.size.unwrap_or_default(),
+            download: Some(PendingDownload::new(attachment_pointer)),
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.download.is_some()
+    }
+}
+
+/// Everything needed to download an attachment later
+///
+/// Contains the fields of the attachment pointer which are required for the download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingDownload {
+    #[serde(default)]
+    pub cdn_id: Option<u64>,
+    #[serde(default)]
+    pub cdn_key: Option<String>,
+    #[serde(default)]
+    pub cdn_number: Option<u32>,
+    #[serde(default)]
+    pub key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub digest: Option<Vec<u8>>,
+    /// File name given by the sender
+    #[serde(default)]
+    pub file_name: Option<String>,
+    /// Error of the last failed download
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Whether the attachment is being downloaded right now
+    #[serde(skip)]
+    pub in_progress: bool,
+}
+
+impl PendingDownload {
+    fn new(attachment_pointer: &AttachmentPointer) -> Self {
+        let (cdn_id, cdn_key) = match attachment_pointer.attachment_identifier.clone() {
+            Some(AttachmentIdentifier::CdnId(id)) => (Some(id), None),
+            Some(AttachmentIdentifier::CdnKey(key)) => (None, Some(key)),
+            None => (None, None),
+        };
+        Self {
+            cdn_id,
+            cdn_key,
+            cdn_number: attachment_pointer.cdn_number,
+            key: attachment_pointer.key.clone(),
+            digest: attachment_pointer.digest.clone(),
+            file_name: attachment_pointer.file_name.clone(),
+            error: None,
+            in_progress: false,
+        }
+    }
+
+    /// Restores the attachment pointer of the attachment
+    pub fn attachment_pointer(&self, attachment: &Attachment) -> AttachmentPointer {
+        let attachment_identifier = match (self.cdn_id, self.cdn_key.clone()) {
+            (Some(id), _) => Some(AttachmentIdentifier::CdnId(id)),
+            (None, Some(key)) => Some(AttachmentIdentifier::CdnKey(key)),
+            (None, None) => None,
+        };
+        AttachmentPointer {
+            content_type: Some(attachment.content_type.clone()),
+            key: self.key.clone(),
+            size: Some(attachment.size),
+            digest: self.digest.clone(),
+            file_name: self.file_name.clone(),
+            cdn_number: self.cdn_number,
+            attachment_identifier,
+            ..Default::default()
+        }
+    }
 }
 
 /// If `db_path` does not exist, it will be created (including parent directories).
@@ -361,6 +672,15 @@ fn get_signal_manager(db_path: PathBuf) -> anyhow::Result<Manager> {
     Ok(manager)
 }
 
+/// Returns the id of our user from the linked device data without connecting to Signal.
+pub fn linked_user_id(db_path: PathBuf) -> anyhow::Result<Uuid> {
+    let manager = get_signal_manager(db_path)?;
+    if !manager.is_registered() {
+        bail!("no linked device: please run gurk to link the device first");
+    }
+    Ok(manager.uuid())
+}
+
 /// Makes sure that we have linked device.
 ///
 /// Either,
@@ -439,23 +759,73 @@ pub async fn ensure_linked_device(relink: bool) -> anyhow::Result<(Manager, Conf
 pub mod test {
     use super::*;
 
-    use std::{cell::RefCell, rc::Rc};
+    use anyhow::anyhow;
+
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::time::Duration;
 
     /// Signal manager mock which does not send any messages.
+    ///
+    /// Its sends can be made to fail and to take some time.
     pub struct SignalManagerMock {
         user_id: Uuid,
-        emoji_replacer: Replacer,
+        /// Messages passed to `send_text`, including the ones which fail to send
         pub sent_messages: Rc<RefCell<Vec<Message>>>,
+        /// Error with which all sends fail, if any
+        pub send_error: Rc<RefCell<Option<String>>>,
+        /// Whether all sends fail as if presage lost the connection to Signal
+        pub connection_lost: Rc<Cell<bool>>,
+        /// Contacts as if they were synced from the primary device
+        pub contacts: Rc<RefCell<Vec<(Uuid, Contact)>>>,
+        /// Channels and actions passed to `send_typing`
+        pub sent_typing: Rc<RefCell<Vec<(ChannelId, TypingAction)>>>,
+        /// Groups which `resolve_group` resolves once, by master key
+        pub groups: Rc<RefCell<HashMap<GroupMasterKeyBytes, ResolvedGroup>>>,
+        /// Time each send takes
+        send_latency: Duration,
     }
 
     impl SignalManagerMock {
         pub fn new() -> Self {
             Self {
                 user_id: Uuid::new_v4(),
-                emoji_replacer: Replacer::new(),
                 sent_messages: Default::default(),
+                send_error: Default::default(),
+                connection_lost: Default::default(),
+                contacts: Default::default(),
+                sent_typing: Default::default(),
+                groups: Default::default(),
+                send_latency: Duration::ZERO,
+            }
+        }
+
+        pub fn with_send_latency(self, send_latency: Duration) -> Self {
+            Self {
+                send_latency,
+                ..self
             }
         }
+
+        fn sending(&self) -> Sending {
+            let send_latency = self.send_latency;
+            let send_error = self.send_error.borrow().clone();
+            let connection_lost = self.connection_lost.get();
+            Box::pin(async move {
+                if !send_latency.is_zero() {
+                    tokio::time::sleep(send_latency).await;
+                }
+                if connection_lost {
+                    let error = PresageError(anyhow!("websocket closing: connection reset"));
+                    return Err(anyhow::Error::new(error).context("failed to send message"));
+                }
+                match send_error {
+                    Some(e) => Err(anyhow!(e)),
+                    None => Ok(()),
+                }
+            })
+        }
     }
 
     #[async_trait(?Send)]
@@ -464,48 +834,48 @@ pub mod test {
             self.user_id
         }
 
-        fn send_receipt(&self, _: Uuid, _: Vec<u64>, _: Receipt) {}
+        fn send_receipt(&self, _: Uuid, _: Vec<u64>, _: Receipt) -> Sending {
+            self.sending()
+        }
 
         async fn contact_name(&self, _id: Uuid, _profile_key: [u8; 32]) -> Option<String> {
             None
         }
 
+        fn request_contacts_sync(&self) -> Sending {
+            self.sending()
+        }
+
+        fn contacts(&self) -> anyhow::Result<Vec<(Uuid, Contact)>> {
+            Ok(self.contacts.borrow().clone())
+        }
+
+        fn request_blocked_sync(&self) -> Sending {
+            self.sending()
+        }
+
+        fn send_blocked(&self, _blocked: &HashSet<ChannelId>) -> Sending {
+            self.sending()
+        }
+
         async fn resolve_group(
             &mut self,
-            _master_key_bytes: super::GroupMasterKeyBytes,
+            master_key_bytes: super::GroupMasterKeyBytes,
         ) -> anyhow::Result<super::ResolvedGroup> {
-            bail!("mocked signal manager cannot resolve groups");
+            self.groups
+                .borrow_mut()
+                .remove(&master_key_bytes)
+                .context("mocked signal manager cannot resolve unknown group")
         }
 
         fn send_text(
             &self,
             _channel: &crate::app::Channel,
-            text: String,
-            quote_message: Option<&crate::app::Message>,
+            message: &crate::app::Message,
             _attachments: Vec<(AttachmentSpec, Vec<u8>)>,
-        ) -> Message {
-            let message: String = self.emoji_replacer.replace_all(&text).into_owned();
-            let timestamp = utc_now_timestamp_msec();
-            let quote = quote_message.map(|message| Quote {
-                id: Some(message.arrived_at),
-                author_uuid: Some(message.from_id.to_string()),
-                text: message.message.clone(),
-                ..Default::default()
-            });
-            let quote_message = quote.and_then(Message::from_quote).map(Box::new);
-            let message = Message {
-                from_id: self.user_id(),
-                message: Some(message),
-                arrived_at: timestamp,
-                quote: quote_message,
-                attachments: Default::default(),
-                reactions: Default::default(),
-                // TODO make sure the message sending procedure did not fail
-                receipt: Receipt::Sent,
-            };
+        ) -> Sending {
             self.sent_messages.borrow_mut().push(message.clone());
-            println!("sent messages: {:?}", self.sent_messages.borrow());
-            message
+            self.sending()
         }
 
         fn send_reaction(
@@ -514,14 +884,36 @@ pub mod test {
             _message: &crate::app::Message,
             _emoji: String,
             _remove: bool,
-        ) {
+        ) -> Sending {
+            self.sending()
         }
 
-        async fn save_attachment(
-            &mut self,
+        fn send_delete(
+            &self,
+            _channel: &crate::app::Channel,
+            _message: &crate::app::Message,
+        ) -> Sending {
+            self.sending()
+        }
+
+        fn send_expire_timer(
+            &self,
+            _channel: &crate::app::Channel,
+            _expire_timer: Option<u32>,
+        ) -> Sending {
+            self.sending()
+        }
+
+        fn send_typing(&self, channel: &crate::app::Channel, action: TypingAction) -> Sending {
+            self.sent_typing.borrow_mut().push((channel.id, action));
+            self.sending()
+        }
+
+        fn download_attachment(
+            &self,
             _attachment_pointer: AttachmentPointer,
-        ) -> anyhow::Result<Attachment> {
-            bail!("mocked signal manager cannot save attachments");
+        ) -> AttachmentDownload {
+            Box::pin(async { bail!("mocked signal manager cannot download attachments") })
         }
     }
 }
@@ -1,18 +1,39 @@
-use crate::app::AppData;
-use crate::cursor::Cursor;
+mod cipher;
+mod migration;
+mod sqlite;
 
-use anyhow::Context;
-use log::info;
+pub use sqlite::SqliteStorage;
+
+use cipher::Cipher;
+
+use crate::app::{AppData, Channel, ChannelId, Message};
+
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use log::{info, warn};
 use uuid::Uuid;
 
-use std::fs::File;
-use std::io::BufReader;
+use std::cell::Cell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Number of messages per channel loaded at once, by storages which support loading older
+/// messages on demand
+pub const MESSAGES_PAGE_SIZE: usize = 200;
+
+/// Minimal time between two backups of the json data file
+const BACKUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Data storage abstraction
 ///
-/// Note: at the moment, we only support the full saving and loading of app data. Later, we plan
-/// to split it in more granular operations.
+/// Besides the full saving and loading of app data, the storage supports granular operations.
+/// These are called after the corresponding change was applied to the app data, and get the
+/// already updated app data together with the key of the changed entity. A storage which cannot
+/// persist a change incrementally falls back to saving the whole app data, which is what the
+/// default implementations do.
 pub trait Storage {
     fn save_app_data(&self, data: &AppData) -> anyhow::Result<()>;
 
@@ -25,34 +46,236 @@ pub trait Storage {
     /// After the app data is loaded, this method must ensure that the user with the given`user_id`
     /// and `user_name` is indexed in the app data names.
     fn load_app_data(&self, user_id: Uuid, user_name: String) -> anyhow::Result<AppData>;
+
+    /// Saves the metadata of the channel (name, group data and unread messages), and the order
+    /// of all channels.
+    ///
+    /// If the channel is not stored yet, it is saved including its messages.
+    fn update_channel(&self, data: &AppData, _channel_id: ChannelId) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the last message of the channel, which was just appended to it.
+    ///
+    /// If the channel is not stored yet, it is saved including all its messages.
+    fn append_message(&self, data: &AppData, _channel_id: ChannelId) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the last `count` messages of the channel, which were just appended to it.
+    ///
+    /// If the channel is not stored yet, it is saved including all its messages.
+    fn append_messages(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _count: usize,
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the receipts of the messages which arrived at the `arrived_at` timestamps in the
+    /// channel.
+    fn update_receipts(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _arrived_at: &[u64],
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the reactions of the message which arrived at `arrived_at` in the channel.
+    fn update_reactions(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the attachments of the message which arrived at `arrived_at` in the channel.
+    fn update_attachments(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the whole message which arrived at `arrived_at` in the channel, e.g. after its
+    /// content was removed.
+    fn update_message(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Deletes the message of the channel, which was just removed from it.
+    fn delete_message(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _message: &Message,
+    ) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the outbox of messages waiting to be sent.
+    fn update_outbox(&self, data: &AppData) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the name of the user with the given id.
+    fn upsert_name(&self, data: &AppData, _id: Uuid) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the names and contacts of the users with the given ids together with their contact
+    /// channels, and the order of all channels, e.g. after the contacts were synced.
+    ///
+    /// Contact channels which are not stored yet are saved including their messages.
+    fn upsert_contacts(&self, data: &AppData, _ids: &[Uuid]) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Saves the blocked users and groups.
+    fn update_blocked(&self, data: &AppData) -> anyhow::Result<()> {
+        self.save_app_data(data)
+    }
+
+    /// Loads up to `limit` messages of the channel which precede the message that arrived at
+    /// `arrived_before`, in chronological order.
+    ///
+    /// A storage which loads all messages together with the app data has no older messages, which
+    /// is what the default implementation returns.
+    fn load_older_messages(
+        &self,
+        _channel_id: ChannelId,
+        _arrived_before: u64,
+        _limit: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        Ok(Vec::new())
+    }
+
+    /// Deletes the stored messages of the channel which arrived before `arrived_before`, or which
+    /// are not among the latest `keep` messages, and returns them.
+    ///
+    /// Called after the messages were pruned from the app data. A storage which keeps all messages
+    /// in the app data only has to save it, which is what the default implementation does; it
+    /// returns no messages.
+    fn prune_messages(
+        &self,
+        data: &AppData,
+        _channel_id: ChannelId,
+        _arrived_before: Option<u64>,
+        _keep: Option<usize>,
+    ) -> anyhow::Result<Vec<Message>> {
+        self.save_app_data(data)?;
+        Ok(Vec::new())
+    }
+
+    /// Deletes the stored disappearing messages which expired at `now`, and returns them together
+    /// with their channel.
+    ///
+    /// Called after the expired messages were removed from the app data. A storage which keeps all
+    /// messages in the app data has no other expired messages, which is what the default
+    /// implementation returns.
+    fn delete_expired_messages(&self, _now: u64) -> anyhow::Result<Vec<(ChannelId, Message)>> {
+        Ok(Vec::new())
+    }
 }
 
-/// Storage based on a single JSON file.
+/// Storage based on a single JSON file, optionally encrypted.
+///
+/// The file is replaced atomically on each save. Before the file is replaced for the first time,
+/// and then at most once a day, a timestamped backup of it is made.
 pub struct JsonStorage {
     data_path: PathBuf,
     fallback_data_path: Option<PathBuf>,
+    cipher: Option<Cipher>,
+    /// Number of backups to keep
+    backups: usize,
+    last_backup: Cell<Option<Instant>>,
+    /// Whether the data was loaded from a backup, because the data file failed to load
+    loaded_backup: Cell<bool>,
 }
 
 impl Storage for JsonStorage {
     fn save_app_data(&self, data: &AppData) -> anyhow::Result<()> {
-        Self::save_to(data, &self.data_path)
+        if let Err(e) = self.backup_if_due() {
+            warn!("failed to backup '{}': {:#}", self.data_path.display(), e);
+        }
+        Self::save_to(data, &self.data_path, self.cipher.as_ref())?;
+        self.loaded_backup.set(false);
+        Ok(())
     }
 
     fn load_app_data(&self, user_id: Uuid, user_name: String) -> anyhow::Result<AppData> {
         let mut data = self.load_app_data_impl()?;
+        finish_loading(&mut data, user_id, user_name);
+        Ok(data)
+    }
+}
 
-        // ensure that our name is up to date
-        data.names.insert(user_id, user_name);
-
-        // select the first channel if none is selected
-        if data.channels.state.selected().is_none() && !data.channels.items.is_empty() {
-            data.channels.state.select(Some(0));
+/// Loaded messages of the channel prepended by all older messages from the storage
+pub fn load_all_messages(storage: &dyn Storage, channel: &Channel) -> anyhow::Result<Vec<Message>> {
+    let mut messages = channel.messages.items.clone();
+    while let Some(oldest_message) = messages.first() {
+        let older_messages = storage.load_older_messages(
+            channel.id,
+            oldest_message.arrived_at,
+            MESSAGES_PAGE_SIZE,
+        )?;
+        if older_messages.is_empty() {
+            break;
         }
+        messages.splice(0..0, older_messages);
+    }
+    Ok(messages)
+}
 
-        Ok(data)
+/// Post-processing of freshly loaded app data common to all storages.
+fn finish_loading(data: &mut AppData, user_id: Uuid, user_name: String) {
+    // ensure that our name is up to date
+    data.names.insert(user_id, user_name);
+
+    // select the first channel if none is selected
+    if data.channels.state.selected().is_none() && !data.channels.items.is_empty() {
+        data.channels.state.select(Some(0));
     }
 }
 
+/// Finds the channel and its position in the app data.
+fn find_channel(data: &AppData, channel_id: ChannelId) -> anyhow::Result<(usize, &Channel)> {
+    data.channels
+        .items
+        .iter()
+        .enumerate()
+        .find(|(_, channel)| channel.id == channel_id)
+        .ok_or_else(|| anyhow!("no channel {:?}", channel_id))
+}
+
+fn find_message(
+    data: &AppData,
+    channel_id: ChannelId,
+    arrived_at: u64,
+) -> anyhow::Result<&Message> {
+    let (_, channel) = find_channel(data, channel_id)?;
+    channel
+        .messages
+        .items
+        .iter()
+        .rev()
+        .find(|message| message.arrived_at == arrived_at)
+        .ok_or_else(|| anyhow!("no message {} in channel {:?}", arrived_at, channel_id))
+}
+
 impl JsonStorage {
     /// Create a new json storage at the data path.
     ///
@@ -63,65 +286,323 @@ impl JsonStorage {
         Self {
             data_path,
             fallback_data_path,
+            cipher: None,
+            backups: 0,
+            last_backup: Default::default(),
+            loaded_backup: Default::default(),
         }
     }
 
-    fn save_to(data: &AppData, data_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let f = std::io::BufWriter::new(File::create(data_path)?);
-        serde_json::to_writer(f, data)?;
+    /// Keep the given number of backups of the data file.
+    ///
+    /// If the data file fails to load, the newest valid backup is loaded instead.
+    pub fn with_backups(self, backups: usize) -> Self {
+        Self { backups, ..self }
+    }
+
+    /// Create a new json storage at the data path, which encrypts the data with a key derived
+    /// from the passphrase.
+    ///
+    /// Existing plaintext data is still loaded, and is encrypted the next time it is saved.
+    pub fn encrypted(
+        data_path: PathBuf,
+        fallback_data_path: Option<PathBuf>,
+        passphrase: &str,
+    ) -> anyhow::Result<Self> {
+        let mut storage = Self::new(data_path, fallback_data_path);
+        storage.cipher = Some(Cipher::for_file(passphrase, storage.load_path())?);
+        Ok(storage)
+    }
+
+    /// Encrypts the existing plaintext data.
+    ///
+    /// If the plaintext data was loaded from the fallback data path, it is removed after the
+    /// encrypted data is saved at the data path.
+    pub fn encrypt_plaintext_data(&self) -> anyhow::Result<()> {
+        if self.cipher.is_none() {
+            bail!("no passphrase given");
+        }
+        let load_path = self.load_path();
+        let content = fs::read(load_path)
+            .with_context(|| format!("failed to read data from '{}'", load_path.display()))?;
+        if cipher::is_encrypted(&content) {
+            bail!("data at '{}' is already encrypted", load_path.display());
+        }
+
+        let data = self.load_app_data_impl()?;
+        self.save_app_data(&data)?;
+        info!("encrypted data at: {}", self.data_path.display());
+
+        if load_path != self.data_path {
+            fs::remove_file(load_path)?;
+            info!("removed plaintext data at: {}", load_path.display());
+        }
         Ok(())
     }
 
-    fn load_app_data_impl(&self) -> anyhow::Result<AppData> {
-        let mut data_path = &self.data_path;
-        if !data_path.exists() {
-            // try also to load from a fallback (legacy) data path
-            if let Some(fallback_data_path) = self.fallback_data_path.as_ref() {
-                data_path = fallback_data_path;
-            }
+    /// Salvages what is loadable from a corrupted data file.
+    ///
+    /// Names, channels and messages which fail to load are dropped. The original file is kept
+    /// next to the data file, the dropped entries are written to a quarantine file (encrypted if
+    /// encryption is configured), and a report of what was lost is written to a text file.
+    /// Returns `None` without touching anything if the data file loads fine.
+    pub fn repair(&self) -> anyhow::Result<Option<RepairReport>> {
+        let load_path = self.load_path();
+        if !load_path.exists() {
+            bail!("no data file found at '{}'", load_path.display());
         }
+        let original = fs::read(load_path)
+            .with_context(|| format!("failed to read data from '{}'", load_path.display()))?;
+        let content = if cipher::is_encrypted(&original) {
+            let cipher = self.cipher.as_ref().ok_or_else(|| {
+                anyhow!("data is encrypted, but encryption is not configured in the config")
+            })?;
+            cipher
+                .decrypt(&original)
+                .context("failed to decrypt the data: encrypted data can not be repaired")?
+        } else {
+            original.clone()
+        };
+        if migration::from_json_slice(&content).is_ok() {
+            return Ok(None);
+        }
+
+        let (data, dropped) = migration::repair_json_slice(&content)?;
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let sibling_path = |suffix: &str| {
+            let mut path = self.data_path.as_os_str().to_owned();
+            path.push(format!(".{}.{}", timestamp, suffix));
+            PathBuf::from(path)
+        };
+        let report = RepairReport {
+            original_path: sibling_path("corrupt"),
+            quarantine_path: sibling_path("quarantine.json"),
+            report_path: sibling_path("repair-report.txt"),
+            channels: data.channels.items.len(),
+            dropped,
+        };
+
+        fs::write(&report.original_path, &original)?;
+        let mut quarantine = serde_json::to_vec_pretty(&report.dropped)?;
+        if let Some(cipher) = self.cipher.as_ref() {
+            quarantine = cipher.encrypt(&quarantine)?;
+        }
+        fs::write(&report.quarantine_path, quarantine)?;
+        fs::write(&report.report_path, report.to_string())?;
+        Self::save_to(&data, &self.data_path, self.cipher.as_ref())?;
+        info!("repaired data at: {}", self.data_path.display());
+        Ok(Some(report))
+    }
+
+    fn save_to(
+        data: &AppData,
+        data_path: impl AsRef<Path>,
+        cipher: Option<&Cipher>,
+    ) -> anyhow::Result<()> {
+        let data_path = data_path.as_ref();
+        let mut content = migration::to_json_vec(data)?;
+        if let Some(cipher) = cipher {
+            content = cipher.encrypt(&content)?;
+        }
+
+        // write to a temporary file and move it into place, s.t. the data file is never partially
+        // written
+        let mut tmp_path = data_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(&content)?;
+        f.sync_all()?;
+        drop(f);
+        fs::rename(&tmp_path, data_path)?;
+
+        // persist the rename (best effort, not supported on all platforms)
+        if let Some(dir) = data_path.parent().and_then(|dir| File::open(dir).ok()) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    /// Copies the data file to a new timestamped backup, if backups are enabled and the last
+    /// backup was made more than `BACKUP_INTERVAL` ago (or not yet at all by this storage).
+    ///
+    /// Removes the oldest backups, s.t. at most `self.backups` are kept. A data file which failed
+    /// to load is not backed up, s.t. it does not replace a valid backup.
+    fn backup_if_due(&self) -> anyhow::Result<()> {
+        let is_due = self
+            .last_backup
+            .get()
+            .map(|t| t.elapsed() >= BACKUP_INTERVAL)
+            .unwrap_or(true);
+        if self.backups == 0 || !is_due || self.loaded_backup.get() || !self.data_path.exists() {
+            return Ok(());
+        }
+
+        let mut backup_path = self.data_path.as_os_str().to_owned();
+        backup_path.push(format!(".{}.bak", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+        fs::copy(&self.data_path, &backup_path)?;
+        self.last_backup.set(Some(Instant::now()));
+        info!("backed up data to: {}", Path::new(&backup_path).display());
+
+        for outdated in backup_paths(&self.data_path)?
+            .into_iter()
+            .skip(self.backups)
+        {
+            fs::remove_file(&outdated)?;
+        }
+        Ok(())
+    }
+
+    /// Path the data is loaded from: the data path, or the fallback (legacy) data path if the
+    /// former does not exist.
+    fn load_path(&self) -> &Path {
+        match self.fallback_data_path.as_ref() {
+            Some(fallback_data_path) if !self.data_path.exists() => fallback_data_path,
+            _ => &self.data_path,
+        }
+    }
+
+    fn load_app_data_impl(&self) -> anyhow::Result<AppData> {
+        let data_path = self.load_path();
 
         // if data file exists, be conservative and fail rather than overriding and losing the messages
         if data_path.exists() {
-            Self::load_app_data_from(&data_path).with_context(|| {
+            let error = match Self::load_app_data_from(data_path, self.cipher.as_ref()) {
+                Ok(data) => return Ok(data),
+                Err(e) => e,
+            };
+            if let Some(data) = self.load_newest_valid_backup(data_path, &error) {
+                return Ok(data);
+            }
+            Err(error).with_context(|| {
                 format!(
                     "failed to load stored data from '{}':\n\
-            This might happen due to incompatible data model when Gurk is upgraded.\n\
-            Please consider to backup your messages and then remove the store.",
+            The data is corrupted, or was stored by a newer version of Gurk.\n\
+            Please consider to backup your messages and then run `gurk repair`.",
                     data_path.display()
                 )
             })
         } else {
-            Ok(Self::load_app_data_from(data_path).unwrap_or_default())
+            Ok(Self::load_app_data_from(data_path, self.cipher.as_ref()).unwrap_or_default())
         }
     }
 
-    fn load_app_data_from(data_path: impl AsRef<Path>) -> anyhow::Result<AppData> {
+    fn load_newest_valid_backup(&self, data_path: &Path, error: &anyhow::Error) -> Option<AppData> {
+        let backups = backup_paths(data_path).ok()?;
+        backups.into_iter().find_map(|backup_path| {
+            let data = Self::load_app_data_from(&backup_path, self.cipher.as_ref()).ok()?;
+            self.loaded_backup.set(true);
+            warn!(
+                "failed to load stored data from '{}' ({:#}); loaded backup '{}' instead",
+                data_path.display(),
+                error,
+                backup_path.display()
+            );
+            Some(data)
+        })
+    }
+
+    fn load_app_data_from(
+        data_path: impl AsRef<Path>,
+        cipher: Option<&Cipher>,
+    ) -> anyhow::Result<AppData> {
         info!("loading app data from: {}", data_path.as_ref().display());
-        let f = BufReader::new(File::open(data_path)?);
-        let mut data: AppData = serde_json::from_reader(f)?;
-        data.input.cursor = Cursor::end(&data.input.data);
-        Ok(data)
+        let mut content = fs::read(data_path)?;
+        if cipher::is_encrypted(&content) {
+            let cipher = cipher.ok_or_else(|| {
+                anyhow!("data is encrypted, but encryption is not configured in the config")
+            })?;
+            content = cipher.decrypt(&content)?;
+        }
+        migration::from_json_slice(&content)
     }
 }
 
+/// Outcome of repairing a corrupted data file
+pub struct RepairReport {
+    /// Copy of the corrupted data file
+    pub original_path: PathBuf,
+    /// JSON file with the dropped entries
+    pub quarantine_path: PathBuf,
+    /// Text file with this report
+    pub report_path: PathBuf,
+    /// Number of salvaged channels
+    pub channels: usize,
+    dropped: Vec<migration::DroppedEntry>,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Salvaged {} channels, dropped {} entries.",
+            self.channels,
+            self.dropped.len()
+        )?;
+        for entry in &self.dropped {
+            writeln!(f, "- {}: {}", entry.location, entry.error)?;
+        }
+        writeln!(f, "Corrupted data: {}", self.original_path.display())?;
+        writeln!(f, "Dropped entries: {}", self.quarantine_path.display())
+    }
+}
+
+/// Paths of the backups of the data file, newest first.
+fn backup_paths(data_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let dir = match data_path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+    let file_name = data_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid data path: {}", data_path.display()))?;
+    let prefix = format!("{}.", file_name);
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_backup = entry
+            .file_name()
+            .to_str()
+            .map(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+            .unwrap_or(false);
+        if is_backup {
+            paths.push(entry.path());
+        }
+    }
+    // timestamps sort lexicographically
+    paths.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(paths)
+}
+
 #[cfg(test)]
 pub mod test {
     use super::Storage;
 
     use crate::app::AppData;
 
+    use std::cell::Cell;
+    use std::rc::Rc;
+
     /// In-memory storage used for testing.
-    pub struct InMemoryStorage {}
+    pub struct InMemoryStorage {
+        /// Number of times the whole app data was saved
+        pub saves: Rc<Cell<usize>>,
+    }
 
     impl InMemoryStorage {
         pub fn new() -> Self {
-            Self {}
+            Self {
+                saves: Default::default(),
+            }
         }
     }
 
     impl Storage for InMemoryStorage {
         fn save_app_data(&self, _data: &crate::app::AppData) -> anyhow::Result<()> {
+            self.saves.set(self.saves.get() + 1);
             Ok(())
         }
 
@@ -201,7 +682,7 @@ mod tests {
 
         let data_path = PathBuf::from("/tmp/some-non-existent-file.json");
         let fallback_data_path = NamedTempFile::new()?;
-        JsonStorage::save_to(&app_data, fallback_data_path.path())?;
+        JsonStorage::save_to(&app_data, fallback_data_path.path(), None)?;
 
         let storage = JsonStorage::new(data_path, Some(fallback_data_path.path().to_owned()));
 
@@ -220,13 +701,17 @@ mod tests {
             input: BoxData {
                 data: "some input".to_string(),
                 cursor: Cursor::end("some input"),
+                mentions: Default::default(),
             },
             search_box: BoxData {
                 data: "some search".to_string(),
                 cursor: Cursor::end("some search"),
+                mentions: Default::default(),
             },
             is_multiline_input: false,
             names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            contacts: Default::default(),
+            blocked: Default::default(),
             channels: FilteredStatefulList::_with_items(vec![Channel {
                 id: ChannelId::User(user_id),
                 name: user_name.clone(),
@@ -234,7 +719,9 @@ mod tests {
                 messages: Default::default(),
                 unread_messages: 0,
                 typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
             }]),
+            outbox: Vec::new(),
         };
 
         let file = NamedTempFile::new()?;
@@ -246,4 +733,215 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_json_storage_encrypted() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = AppData {
+            input: BoxData::empty(),
+            search_box: BoxData::empty(),
+            names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let file = NamedTempFile::new()?;
+        let storage = JsonStorage::encrypted(file.path().to_owned(), None, "secret")?;
+        storage.save_app_data(&app_data)?;
+        assert!(cipher::is_encrypted(&fs::read(file.path())?));
+
+        let storage = JsonStorage::encrypted(file.path().to_owned(), None, "secret")?;
+        let loaded_app_data = storage.load_app_data(user_id, user_name.clone())?;
+        assert_eq!(loaded_app_data, app_data);
+
+        let storage = JsonStorage::encrypted(file.path().to_owned(), None, "wrong")?;
+        assert!(storage.load_app_data(user_id, user_name.clone()).is_err());
+
+        let storage = JsonStorage::new(file.path().to_owned(), None);
+        assert!(storage.load_app_data(user_id, user_name).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_storage_encrypt_plaintext_data() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = AppData {
+            input: BoxData::empty(),
+            search_box: BoxData::empty(),
+            names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("gurk.data.json");
+        let fallback_data_path = dir.path().join("gurk.json");
+        JsonStorage::save_to(&app_data, &fallback_data_path, None)?;
+
+        let storage = JsonStorage::encrypted(
+            data_path.clone(),
+            Some(fallback_data_path.clone()),
+            "secret",
+        )?;
+        storage.encrypt_plaintext_data()?;
+        assert!(cipher::is_encrypted(&fs::read(&data_path)?));
+        assert!(!fallback_data_path.exists());
+        assert!(storage.encrypt_plaintext_data().is_err());
+
+        let loaded_app_data = storage.load_app_data(user_id, user_name)?;
+        assert_eq!(loaded_app_data, app_data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_storage_atomic_save() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("gurk.data.json");
+        let storage = JsonStorage::new(data_path.clone(), None);
+        storage.save_app_data(&AppData::default())?;
+        storage.save_app_data(&AppData::default())?;
+
+        let files: Vec<_> = fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(files, [data_path]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_storage_backups() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = AppData {
+            input: BoxData::empty(),
+            search_box: BoxData::empty(),
+            names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("gurk.data.json");
+
+        // first save has nothing to backup
+        JsonStorage::new(data_path.clone(), None)
+            .with_backups(2)
+            .save_app_data(&app_data)?;
+        assert!(backup_paths(&data_path)?.is_empty());
+
+        // each new storage backs up once
+        for _ in 0..3 {
+            let storage = JsonStorage::new(data_path.clone(), None).with_backups(2);
+            storage.save_app_data(&app_data)?;
+            storage.save_app_data(&app_data)?;
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(backup_paths(&data_path)?.len(), 2);
+
+        // corrupt data file is replaced by the newest valid backup
+        fs::write(&data_path, "{")?;
+        let corrupt_backup = backup_paths(&data_path)?.remove(0);
+        fs::write(&corrupt_backup, "{")?;
+        let storage = JsonStorage::new(data_path.clone(), None).with_backups(2);
+        let loaded_app_data = storage.load_app_data(user_id, user_name)?;
+        assert_eq!(loaded_app_data, app_data);
+
+        // without valid backups loading fails
+        for backup_path in backup_paths(&data_path)? {
+            fs::write(&backup_path, "{")?;
+        }
+        assert!(storage.load_app_data_impl().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_storage_keeps_valid_backups_after_loading_one() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = AppData {
+            input: BoxData::empty(),
+            search_box: BoxData::empty(),
+            names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("gurk.data.json");
+        for _ in 0..3 {
+            let storage = JsonStorage::new(data_path.clone(), None).with_backups(2);
+            storage.save_app_data(&app_data)?;
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(backup_paths(&data_path)?.len(), 2);
+
+        // the corrupt data file is not backed up when saving the data loaded from a backup
+        fs::write(&data_path, "{")?;
+        for _ in 0..3 {
+            let storage = JsonStorage::new(data_path.clone(), None).with_backups(2);
+            let loaded_app_data = storage.load_app_data(user_id, user_name.clone())?;
+            storage.save_app_data(&loaded_app_data)?;
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let backups = backup_paths(&data_path)?;
+        assert_eq!(backups.len(), 2);
+        for backup_path in backups {
+            assert_eq!(
+                JsonStorage::load_app_data_from(backup_path, None)?,
+                app_data
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_storage_repair() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let dir = tempfile::tempdir()?;
+        let data_path = dir.path().join("gurk.data.json");
+        let storage = JsonStorage::new(data_path.clone(), None);
+
+        let content = serde_json::json!({
+            "version": 1,
+            "channels": {
+                "items": [
+                    {
+                        "id": { "User": user_id },
+                        "name": user_name,
+                        "messages": [
+                            { "from_id": user_id, "message": "hello", "arrived_at": 1 },
+                            { "from_id": user_id, "arrived_at": "broken" },
+                        ],
+                    },
+                ],
+            },
+            "names": {},
+        });
+        fs::write(&data_path, serde_json::to_vec(&content)?)?;
+        assert!(storage.load_app_data(user_id, user_name.clone()).is_err());
+
+        let report = storage.repair()?.expect("nothing repaired");
+        assert_eq!(report.channels, 1);
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(
+            fs::read(&report.original_path)?,
+            serde_json::to_vec(&content)?
+        );
+        let quarantine: serde_json::Value =
+            serde_json::from_slice(&fs::read(&report.quarantine_path)?)?;
+        assert_eq!(quarantine[0]["value"]["arrived_at"], "broken");
+        assert!(report.report_path.exists());
+
+        let app_data = storage.load_app_data(user_id, user_name)?;
+        assert_eq!(app_data.channels.items[0].messages.items.len(), 1);
+
+        // loadable data is left alone
+        assert!(storage.repair()?.is_none());
+
+        Ok(())
+    }
 }
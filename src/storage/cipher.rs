@@ -0,0 +1,159 @@
+use anyhow::{anyhow, bail, Context};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// Magic bytes at the beginning of encrypted data, incl. the format version
+const MAGIC: &[u8] = b"GURKENC\x01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+// Argon2id parameters of format version 1; changing them requires a new format version, otherwise
+// existing data can't be decrypted anymore.
+const ARGON2_M_COST_KIB: u32 = 19 * 1024;
+const ARGON2_T_COST: u32 = 2;
+const ARGON2_P_COST: u32 = 1;
+
+/// Authenticated encryption with a key derived from a passphrase.
+///
+/// Encrypted data has the format `MAGIC | salt | nonce | ciphertext`. The key is derived from the
+/// passphrase and the salt with Argon2id, and the data is encrypted with XChaCha20-Poly1305 using
+/// a fresh random nonce each time.
+pub struct Cipher {
+    salt: [u8; SALT_LEN],
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derives the key from the passphrase and a new random salt.
+    pub fn new(passphrase: &str) -> anyhow::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::with_salt(passphrase, salt)
+    }
+
+    /// Derives the key from the passphrase, reusing the salt of the encrypted file at `path`.
+    ///
+    /// If the file does not exist or is not encrypted, a new salt is generated.
+    pub fn for_file(passphrase: &str, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        match File::open(path.as_ref()) {
+            Ok(f) => {
+                f.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        match read_salt(&header) {
+            Some(salt) => Self::with_salt(passphrase, salt),
+            None => Self::new(passphrase),
+        }
+    }
+
+    fn with_salt(passphrase: &str, salt: [u8; SALT_LEN]) -> anyhow::Result<Self> {
+        if passphrase.is_empty() {
+            bail!("passphrase must not be empty");
+        }
+        let params = Params::new(
+            ARGON2_M_COST_KIB,
+            ARGON2_T_COST,
+            ARGON2_P_COST,
+            Some(KEY_LEN),
+        )
+        .map_err(|e| anyhow!("invalid key derivation parameters: {}", e))?;
+        let mut key = [0; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| anyhow!("failed to derive key from passphrase: {}", e))?;
+        let aead = XChaCha20Poly1305::new(Key::from_slice(&key));
+        Ok(Self { salt, aead })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow!("failed to encrypt data"))?;
+
+        let mut data = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.salt);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let salt = read_salt(data).context("data is not encrypted")?;
+        if salt != self.salt {
+            bail!("data was encrypted with a different key");
+        }
+        let nonce = &data[MAGIC.len() + SALT_LEN..HEADER_LEN];
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), &data[HEADER_LEN..])
+            .map_err(|_| anyhow!("failed to decrypt data: wrong passphrase or corrupted data"))
+    }
+}
+
+/// Returns whether the data starts with the header of encrypted data.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    read_salt(data).is_some()
+}
+
+fn read_salt(data: &[u8]) -> Option<[u8; SALT_LEN]> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        return None;
+    }
+    let mut salt = [0; SALT_LEN];
+    salt.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + SALT_LEN]);
+    Some(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_encrypt_decrypt() -> anyhow::Result<()> {
+        let cipher = Cipher::new("secret")?;
+        let data = cipher.encrypt(b"hello")?;
+        assert!(is_encrypted(&data));
+        assert!(!data.windows(5).any(|w| w == b"hello"));
+        assert_eq!(cipher.decrypt(&data)?, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_passphrase() -> anyhow::Result<()> {
+        let file = NamedTempFile::new()?;
+        std::fs::write(file.path(), Cipher::new("secret")?.encrypt(b"hello")?)?;
+
+        let data = std::fs::read(file.path())?;
+        assert_eq!(
+            Cipher::for_file("secret", file.path())?.decrypt(&data)?,
+            b"hello"
+        );
+        assert!(Cipher::for_file("wrong", file.path())?
+            .decrypt(&data)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plaintext_is_not_encrypted() {
+        assert!(!is_encrypted(b"{}"));
+        assert!(!is_encrypted(MAGIC));
+    }
+}
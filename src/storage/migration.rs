@@ -0,0 +1,556 @@
+use crate::app::{AppData, Channel, ChannelId, Contact, Message};
+use crate::cursor::Cursor;
+use crate::util::FilteredStatefulList;
+
+use anyhow::{anyhow, bail, Context};
+use log::info;
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+/// Version of the stored app data written by this version of gurk
+pub const CURRENT_VERSION: u64 = 1;
+
+/// Ordered migration steps of the stored app data
+///
+/// The step at index `i` migrates the data from version `i` to version `i + 1`. Data stored
+/// before the version was introduced has version 0. Append new steps only, and never change
+/// existing ones.
+const MIGRATIONS: &[fn(&mut Value) -> anyhow::Result<()>] = &[group_ids_from_master_keys];
+
+/// App data together with its version, as it is stored
+#[derive(Serialize)]
+struct VersionedAppData<'a> {
+    version: u64,
+    #[serde(flatten)]
+    data: &'a AppData,
+}
+
+/// Serializes the app data with the current version.
+pub fn to_json_vec(data: &AppData) -> anyhow::Result<Vec<u8>> {
+    let versioned = VersionedAppData {
+        version: CURRENT_VERSION,
+        data,
+    };
+    Ok(serde_json::to_vec(&versioned)?)
+}
+
+/// Deserializes stored app data of any version, migrating it to the current version first.
+pub fn from_json_slice(content: &[u8]) -> anyhow::Result<AppData> {
+    let mut value: Value = serde_json::from_slice(content)?;
+    migrate(&mut value)?;
+    let mut data: AppData = serde_json::from_value(value)?;
+    data.input.cursor = Cursor::end(&data.input.data);
+    Ok(data)
+}
+
+/// Entry of the stored app data which was dropped while repairing it
+#[derive(Debug, Serialize)]
+pub struct DroppedEntry {
+    /// Human readable location of the entry
+    pub location: String,
+    pub error: String,
+    /// The entry as it was stored
+    pub value: Value,
+}
+
+/// Deserializes stored app data of any version leniently.
+///
+/// Names, contacts, blocked channels, channels, messages and queued messages which fail to
+/// migrate or to deserialize are dropped and returned instead of failing. A channel is only
+/// dropped as a whole, if it fails to load even without its broken messages. Fails if the content
+/// is not a JSON object, or was stored by a newer version of gurk.
+pub fn repair_json_slice(content: &[u8]) -> anyhow::Result<(AppData, Vec<DroppedEntry>)> {
+    let mut value: Value = serde_json::from_slice(content).context("data is not valid JSON")?;
+    let version = version(&value)?;
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("invalid data: expected an object"))?;
+    let mut dropped = Vec::new();
+
+    let mut names = HashMap::new();
+    match object.remove("names") {
+        Some(Value::Object(stored_names)) => {
+            for (id, name) in stored_names {
+                match (Uuid::parse_str(&id), name) {
+                    (Ok(id), Value::String(name)) => {
+                        names.insert(id, name);
+                    }
+                    (id_result, name) => dropped.push(DroppedEntry {
+                        location: format!("name of user {}", id),
+                        error: match id_result {
+                            Ok(_) => "expected a string".to_string(),
+                            Err(e) => e.to_string(),
+                        },
+                        value: name,
+                    }),
+                }
+            }
+        }
+        Some(other) => dropped.push(DroppedEntry {
+            location: "names".to_string(),
+            error: "expected an object".to_string(),
+            value: other,
+        }),
+        None => {}
+    }
+
+    let mut contacts = HashMap::new();
+    match object.remove("contacts") {
+        Some(Value::Object(stored_contacts)) => {
+            for (id, contact) in stored_contacts {
+                let parsed = Uuid::parse_str(&id)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|id| {
+                        let contact: Contact = serde_json::from_value(contact.clone())?;
+                        Ok((id, contact))
+                    });
+                match parsed {
+                    Ok((id, contact)) => {
+                        contacts.insert(id, contact);
+                    }
+                    Err(e) => dropped.push(DroppedEntry {
+                        location: format!("contact {}", id),
+                        error: e.to_string(),
+                        value: contact,
+                    }),
+                }
+            }
+        }
+        Some(other) => dropped.push(DroppedEntry {
+            location: "contacts".to_string(),
+            error: "expected an object".to_string(),
+            value: other,
+        }),
+        None => {}
+    }
+
+    let mut blocked = HashSet::new();
+    match object.remove("blocked") {
+        Some(Value::Array(stored_blocked)) => {
+            for (idx, channel_id) in stored_blocked.into_iter().enumerate() {
+                match serde_json::from_value(channel_id.clone()) {
+                    Ok(channel_id) => {
+                        blocked.insert(channel_id);
+                    }
+                    Err(e) => dropped.push(DroppedEntry {
+                        location: format!("blocked channel #{}", idx),
+                        error: e.to_string(),
+                        value: channel_id,
+                    }),
+                }
+            }
+        }
+        Some(other) => dropped.push(DroppedEntry {
+            location: "blocked".to_string(),
+            error: "expected an array".to_string(),
+            value: other,
+        }),
+        None => {}
+    }
+
+    let mut channels = Vec::new();
+    match object
+        .get_mut("channels")
+        .and_then(|channels| channels.get_mut("items"))
+        .map(Value::take)
+    {
+        Some(Value::Array(stored_channels)) => {
+            for (idx, channel) in stored_channels.into_iter().enumerate() {
+                let location = match channel.get("name").and_then(Value::as_str) {
+                    Some(name) => format!("channel '{}'", name),
+                    None => format!("channel #{}", idx),
+                };
+                match repair_channel(version, channel.clone(), &location, &mut dropped) {
+                    Ok(channel) => channels.push(channel),
+                    Err(e) => dropped.push(DroppedEntry {
+                        location,
+                        error: format!("{:#}", e),
+                        value: channel,
+                    }),
+                }
+            }
+        }
+        Some(other) => dropped.push(DroppedEntry {
+            location: "channels".to_string(),
+            error: "expected an array".to_string(),
+            value: other,
+        }),
+        None => {}
+    }
+
+    let mut outbox = Vec::new();
+    match object.remove("outbox") {
+        Some(Value::Array(stored_outbox)) => {
+            for (idx, queued) in stored_outbox.into_iter().enumerate() {
+                match serde_json::from_value(queued.clone()) {
+                    Ok(queued) => outbox.push(queued),
+                    Err(e) => dropped.push(DroppedEntry {
+                        location: format!("queued message #{}", idx),
+                        error: e.to_string(),
+                        value: queued,
+                    }),
+                }
+            }
+        }
+        Some(other) => dropped.push(DroppedEntry {
+            location: "outbox".to_string(),
+            error: "expected an array".to_string(),
+            value: other,
+        }),
+        None => {}
+    }
+
+    let data = AppData {
+        channels: FilteredStatefulList {
+            items: channels,
+            ..Default::default()
+        },
+        names,
+        contacts,
+        blocked,
+        outbox,
+        ..Default::default()
+    };
+    Ok((data, dropped))
+}
+
+/// Loads the channel, dropping its messages which fail to deserialize, if needed.
+fn repair_channel(
+    version: u64,
+    mut channel: Value,
+    location: &str,
+    dropped: &mut Vec<DroppedEntry>,
+) -> anyhow::Result<Channel> {
+    if let Ok(channel) = load_channel(version, channel.clone()) {
+        return Ok(channel);
+    }
+    if let Some(Value::Array(messages)) = channel.get_mut("messages") {
+        let stored_messages = std::mem::take(messages);
+        for (idx, message) in stored_messages.into_iter().enumerate() {
+            match serde_json::from_value::<Message>(message.clone()) {
+                Ok(_) => messages.push(message),
+                Err(e) => dropped.push(DroppedEntry {
+                    location: format!("message #{} in {}", idx, location),
+                    error: e.to_string(),
+                    value: message,
+                }),
+            }
+        }
+    }
+    load_channel(version, channel)
+}
+
+/// Migrates and deserializes a single stored channel.
+fn load_channel(version: u64, channel: Value) -> anyhow::Result<Channel> {
+    let mut value = json!({
+        "version": version,
+        "channels": { "items": [channel] },
+        "names": {},
+    });
+    migrate(&mut value)?;
+    let data: AppData = serde_json::from_value(value)?;
+    data.channels
+        .items
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("channel is missing"))
+}
+
+/// Version of the stored app data
+///
+/// Fails if the data was stored by a newer version of gurk.
+fn version(value: &Value) -> anyhow::Result<u64> {
+    let version = match value.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid data version: {}", version))?,
+        None => 0,
+    };
+    if version > CURRENT_VERSION {
+        bail!(
+            "data has version {}, but this version of gurk supports only versions up to {}; \
+            please upgrade gurk",
+            version,
+            CURRENT_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Migrates the stored app data to the current version.
+///
+/// Fails if the data was stored by a newer version of gurk.
+fn migrate(value: &mut Value) -> anyhow::Result<()> {
+    let version = version(value)?;
+
+    for (from_version, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!(
+            "migrating stored data from version {} to {}",
+            from_version,
+            from_version + 1
+        );
+        step(value).with_context(|| {
+            format!(
+                "failed to migrate data from version {} to {}",
+                from_version,
+                from_version + 1
+            )
+        })?;
+    }
+    value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("invalid data: expected an object"))?
+        .insert("version".to_string(), CURRENT_VERSION.into());
+    Ok(())
+}
+
+fn channels_mut(value: &mut Value) -> impl Iterator<Item = &mut Value> {
+    value
+        .pointer_mut("/channels/items")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+}
+
+/// Version 0 -> 1
+///
+/// The master key in `ChannelId::Group` was replaced by the group identifier, and the master key
+/// is stored in the group data instead.
+fn group_ids_from_master_keys(value: &mut Value) -> anyhow::Result<()> {
+    for channel in channels_mut(value) {
+        let master_key = match channel.pointer("/id/Group") {
+            Some(master_key) => master_key.clone(),
+            None => continue,
+        };
+        let group_data = match channel.get_mut("group_data") {
+            Some(Value::Object(group_data)) => group_data,
+            _ => continue,
+        };
+        let has_master_key = group_data
+            .get("master_key_bytes")
+            .and_then(Value::as_array)
+            .map(|bytes| bytes.iter().any(|b| b.as_u64() != Some(0)))
+            .unwrap_or(false);
+        if has_master_key {
+            continue;
+        }
+
+        let master_key_bytes: Vec<u8> = serde_json::from_value(master_key.clone())?;
+        let master_key_bytes: [u8; 32] = master_key_bytes
+            .try_into()
+            .map_err(|_| anyhow!("invalid group master key"))?;
+        group_data.insert("master_key_bytes".to_string(), master_key);
+        channel["id"] = serde_json::to_value(ChannelId::from_master_key_bytes(master_key_bytes)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Channel, GroupData, Message, Receipt, TypingSet};
+    use crate::util::{FilteredStatefulList, StatefulList};
+
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_roundtrip_current_version() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let data = AppData {
+            names: [(user_id, "Tyler Durden".to_string())]
+                .iter()
+                .cloned()
+                .collect(),
+            channels: FilteredStatefulList::_with_items(vec![Channel {
+                id: user_id.into(),
+                name: "Tyler Durden".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(vec![Message {
+                    from_id: user_id,
+                    message: Some("hello".to_string()),
+                    arrived_at: 1,
+                    quote: None,
+                    attachments: Default::default(),
+                    reactions: Default::default(),
+                    receipt: Receipt::Read,
+                    deleted: false,
+                    expires_at: None,
+                    mentions: Default::default(),
+                    group_change: None,
+                }]),
+                unread_messages: 1,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            }]),
+            ..Default::default()
+        };
+
+        let content = to_json_vec(&data)?;
+        let value: Value = serde_json::from_slice(&content)?;
+        assert_eq!(value["version"], CURRENT_VERSION);
+        assert_eq!(from_json_slice(&content)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_0_minimal_fields() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let content = json!({
+            "channels": {
+                "items": [{
+                    "id": { "User": user_id },
+                    "name": "Tyler Durden",
+                    "messages": [{
+                        "from_id": user_id,
+                        "message": "hello",
+                        "arrived_at": 1,
+                    }],
+                }],
+            },
+            "names": { user_id.to_string(): "Tyler Durden" },
+        });
+
+        let data = from_json_slice(&serde_json::to_vec(&content)?)?;
+        let channel = &data.channels.items[0];
+        assert_eq!(channel.id, user_id.into());
+        assert_eq!(channel.group_data, None);
+        assert_eq!(channel.unread_messages, 0);
+        let message = &channel.messages.items[0];
+        assert_eq!(message.message.as_deref(), Some("hello"));
+        assert_eq!(message.receipt, Receipt::Nothing);
+        assert!(message.attachments.is_empty());
+        assert!(message.reactions.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_0_group_id_is_master_key() -> anyhow::Result<()> {
+        let master_key = [42; 32];
+        let member_id = Uuid::new_v4();
+        let content = json!({
+            "channels": {
+                "items": [{
+                    "id": { "Group": master_key },
+                    "name": "Group",
+                    "group_data": {
+                        "members": [member_id],
+                        "revision": 1,
+                    },
+                    "messages": [],
+                }],
+            },
+            "names": {},
+        });
+
+        let data = from_json_slice(&serde_json::to_vec(&content)?)?;
+        assert_eq!(
+            data.channels.items[0].id,
+            ChannelId::from_master_key_bytes(master_key)?
+        );
+        assert_eq!(
+            data.channels.items[0].group_data,
+            Some(GroupData {
+                master_key_bytes: master_key,
+                members: vec![member_id],
+                revision: 1,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_1_group_id_is_kept() -> anyhow::Result<()> {
+        let master_key = [42; 32];
+        let group_id = ChannelId::from_master_key_bytes(master_key)?;
+        let content = json!({
+            "version": 1,
+            "channels": {
+                "items": [{
+                    "id": group_id,
+                    "name": "Group",
+                    "group_data": {
+                        "master_key_bytes": master_key,
+                        "members": [],
+                        "revision": 1,
+                    },
+                    "messages": [],
+                }],
+            },
+            "names": {},
+        });
+
+        let data = from_json_slice(&serde_json::to_vec(&content)?)?;
+        assert_eq!(data.channels.items[0].id, group_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_drops_broken_entries() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let content = json!({
+            "version": 1,
+            "channels": {
+                "items": [
+                    {
+                        "id": { "User": user_id },
+                        "name": "Tyler Durden",
+                        "messages": [
+                            { "from_id": user_id, "message": "hello", "arrived_at": 1 },
+                            { "from_id": "not a uuid", "arrived_at": 2 },
+                            { "from_id": user_id, "message": "world", "arrived_at": 3 },
+                        ],
+                    },
+                    { "id": { "Unknown": 1 }, "name": "Broken", "messages": [] },
+                ],
+            },
+            "names": { user_id.to_string(): "Tyler Durden", "not a uuid": "Bob" },
+        });
+
+        let (data, dropped) = repair_json_slice(&serde_json::to_vec(&content)?)?;
+
+        assert_eq!(data.channels.items.len(), 1);
+        let messages = &data.channels.items[0].messages.items;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].message.as_deref(), Some("world"));
+        assert_eq!(data.names.len(), 1);
+
+        let locations: Vec<&str> = dropped.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(
+            locations,
+            [
+                "name of user not a uuid",
+                "message #1 in channel 'Tyler Durden'",
+                "channel 'Broken'",
+            ]
+        );
+        assert_eq!(dropped[1].value["arrived_at"], 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_fails_for_newer_version() {
+        let content = json!({ "version": CURRENT_VERSION + 1 });
+        assert!(repair_json_slice(&serde_json::to_vec(&content).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_newer_version_fails() {
+        let content = json!({
+            "version": CURRENT_VERSION + 1,
+            "channels": { "items": [] },
+            "names": {},
+        });
+        let res = from_json_slice(&serde_json::to_vec(&content).unwrap());
+        assert!(res.unwrap_err().to_string().contains("upgrade gurk"));
+    }
+}
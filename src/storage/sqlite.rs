@@ -0,0 +1,1712 @@
+use super::{find_channel, find_message, finish_loading, JsonStorage, Storage, MESSAGES_PAGE_SIZE};
+use crate::app::{
+    AppData, Channel, ChannelId, Contact, GroupChange, GroupData, Mention, Message, QueuedMessage,
+    Receipt, TypingSet,
+};
+use crate::signal::Attachment;
+use crate::util::{FilteredStatefulList, StatefulList};
+
+use anyhow::{anyhow, bail, Context};
+use log::info;
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+/// Ordered migrations of the database schema
+///
+/// The migration at index `i` migrates the schema from version `i` to version `i + 1`, where
+/// version 0 is an empty database. The version is stored in the `user_version` pragma. Append new
+/// migrations only, and never change existing ones.
+const MIGRATIONS: &[&str] = &[
+    SCHEMA_V1, SCHEMA_V2, SCHEMA_V3, SCHEMA_V4, SCHEMA_V5, SCHEMA_V6, SCHEMA_V7, SCHEMA_V8,
+    SCHEMA_V9,
+];
+
+const SCHEMA_V1: &str = "
+    CREATE TABLE IF NOT EXISTS names (
+        id BLOB PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS channels (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        user_id BLOB UNIQUE,
+        group_id BLOB UNIQUE,
+        name TEXT NOT NULL,
+        unread_messages INTEGER NOT NULL DEFAULT 0,
+        -- group data, present iff revision is not null
+        master_key BLOB,
+        revision INTEGER,
+        CHECK ((user_id IS NULL) <> (group_id IS NULL))
+    );
+
+    CREATE TABLE IF NOT EXISTS group_members (
+        channel_id INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        member_id BLOB NOT NULL,
+        PRIMARY KEY (channel_id, position)
+    );
+
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        channel_id INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+        from_id BLOB NOT NULL,
+        arrived_at INTEGER NOT NULL,
+        body TEXT,
+        receipt INTEGER NOT NULL,
+        -- quoted message, present iff quote_from_id is not null
+        quote_from_id BLOB,
+        quote_arrived_at INTEGER,
+        quote_body TEXT
+    );
+
+    CREATE INDEX IF NOT EXISTS messages_by_channel ON messages (channel_id, arrived_at);
+
+    CREATE TABLE IF NOT EXISTS attachments (
+        message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        id TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        PRIMARY KEY (message_id, position)
+    );
+
+    CREATE TABLE IF NOT EXISTS reactions (
+        message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        from_id BLOB NOT NULL,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (message_id, position)
+    );
+";
+
+/// Adds the pending downloads of attachments, stored as JSON
+const SCHEMA_V2: &str = "
+    ALTER TABLE attachments ADD COLUMN download TEXT;
+";
+
+/// Adds the outbox of messages waiting to be sent, stored as JSON
+const SCHEMA_V3: &str = "
+    CREATE TABLE IF NOT EXISTS outbox (
+        position INTEGER PRIMARY KEY NOT NULL,
+        message TEXT NOT NULL
+    );
+";
+
+/// Adds the flag of messages deleted for everyone
+const SCHEMA_V4: &str = "
+    ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+";
+
+/// Adds the timers of disappearing messages
+const SCHEMA_V5: &str = "
+    ALTER TABLE channels ADD COLUMN expire_timer INTEGER;
+    ALTER TABLE messages ADD COLUMN expires_at INTEGER;
+";
+
+/// Adds the mentions of messages and their quotes, stored as JSON
+const SCHEMA_V6: &str = "
+    ALTER TABLE messages ADD COLUMN mentions TEXT;
+    ALTER TABLE messages ADD COLUMN quote_mentions TEXT;
+";
+
+/// Adds the group changes of system messages, stored as JSON
+const SCHEMA_V7: &str = "
+    ALTER TABLE messages ADD COLUMN group_change TEXT;
+";
+
+/// Adds the contacts synced from the primary device
+const SCHEMA_V8: &str = "
+    CREATE TABLE IF NOT EXISTS contacts (
+        id BLOB PRIMARY KEY NOT NULL,
+        phone_number TEXT,
+        name TEXT NOT NULL,
+        profile_key BLOB NOT NULL,
+        blocked INTEGER NOT NULL
+    );
+";
+
+/// Adds the blocked users and groups
+const SCHEMA_V9: &str = "
+    CREATE TABLE IF NOT EXISTS blocked (
+        user_id BLOB UNIQUE,
+        group_id BLOB UNIQUE,
+        CHECK ((user_id IS NULL) <> (group_id IS NULL))
+    );
+";
+
+/// Ids of the latest `?1` messages of each channel
+const LATEST_MESSAGE_IDS: &str = "
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY channel_id ORDER BY id DESC) AS page_rank
+        FROM messages
+    )
+    WHERE page_rank <= ?1";
+
+/// Ids of the `?3` messages of the channel `?1` preceding the message with the id `?2`
+const OLDER_MESSAGE_IDS: &str = "
+    SELECT id FROM messages WHERE channel_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3";
+
+/// Ids of the messages of the channel `?1` which arrived before `?2` or are not among the latest
+/// `?3` messages (negative for no limit)
+const PRUNED_MESSAGE_IDS: &str = "
+    SELECT id FROM messages WHERE channel_id = ?1 AND (arrived_at < ?2 OR id NOT IN (
+        SELECT id FROM messages WHERE channel_id = ?1 ORDER BY id DESC LIMIT ?3
+    ))";
+
+/// Ids of the disappearing messages which expired at `?1`, except our unsent ones with the
+/// receipts `?2`, `?3` and `?4`
+const EXPIRED_MESSAGE_IDS: &str = "
+    SELECT id FROM messages WHERE expires_at <= ?1 AND receipt NOT IN (?2, ?3, ?4)";
+
+/// Storage based on a SQLite database with normalized tables for channels, messages, names,
+/// contacts, blocked channels, attachments and reactions.
+///
+/// Only the latest page of messages of each channel is loaded with the app data; older messages
+/// are loaded on demand.
+pub struct SqliteStorage {
+    conn: Connection,
+    legacy_storage: Option<JsonStorage>,
+}
+
+impl Storage for SqliteStorage {
+    /// Replaces the stored data by the app data.
+    ///
+    /// Stored messages which are older than the loaded messages of a channel are kept.
+    fn save_app_data(&self, data: &AppData) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM names", [])?;
+        for (id, name) in &data.names {
+            tx.execute(
+                "INSERT INTO names (id, name) VALUES (?1, ?2)",
+                params![id, name],
+            )?;
+        }
+        tx.execute("DELETE FROM contacts", [])?;
+        for (id, contact) in &data.contacts {
+            insert_contact(&tx, *id, contact)?;
+        }
+        replace_blocked(&tx, data)?;
+
+        let mut channel_rowids = HashSet::new();
+        for (position, channel) in data.channels.items.iter().enumerate() {
+            let rowid = match channel_rowid(&tx, channel.id)? {
+                Some(rowid) => {
+                    update_channel_row(&tx, rowid, position, channel)?;
+                    if let Some(oldest_message) = channel.messages.items.first() {
+                        tx.execute(
+                            "DELETE FROM messages WHERE channel_id = ?1 AND arrived_at >= ?2",
+                            params![rowid, oldest_message.arrived_at],
+                        )?;
+                        for message in &channel.messages.items {
+                            insert_message(&tx, rowid, message)?;
+                        }
+                    }
+                    rowid
+                }
+                None => insert_channel(&tx, position, channel)?,
+            };
+            channel_rowids.insert(rowid);
+        }
+
+        let stored_rowids: Vec<i64> = tx
+            .prepare("SELECT id FROM channels")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for rowid in stored_rowids {
+            if !channel_rowids.contains(&rowid) {
+                tx.execute("DELETE FROM channels WHERE id = ?1", params![rowid])?;
+            }
+        }
+        replace_outbox(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load_app_data(&self, user_id: Uuid, user_name: String) -> anyhow::Result<AppData> {
+        let mut data = if self.is_empty()? {
+            self.migrate_legacy_storage()?
+        } else {
+            self.load_app_data_impl()
+                .context("failed to load app data from the SQLite database")?
+        };
+        finish_loading(&mut data, user_id, user_name);
+        Ok(data)
+    }
+
+    fn update_channel(&self, data: &AppData, channel_id: ChannelId) -> anyhow::Result<()> {
+        let (position, channel) = find_channel(data, channel_id)?;
+        let tx = self.conn.unchecked_transaction()?;
+        match channel_rowid(&tx, channel_id)? {
+            Some(rowid) => update_channel_row(&tx, rowid, position, channel)?,
+            None => {
+                insert_channel(&tx, position, channel)?;
+            }
+        }
+        for (position, channel) in data.channels.items.iter().enumerate() {
+            let (user_id, group_id) = channel_key(&channel.id);
+            tx.execute(
+                "UPDATE channels SET position = ?1 WHERE user_id = ?2 OR group_id = ?3",
+                params![position, user_id, group_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn append_message(&self, data: &AppData, channel_id: ChannelId) -> anyhow::Result<()> {
+        self.append_messages(data, channel_id, 1)
+    }
+
+    fn append_messages(
+        &self,
+        data: &AppData,
+        channel_id: ChannelId,
+        count: usize,
+    ) -> anyhow::Result<()> {
+        let (position, channel) = find_channel(data, channel_id)?;
+        let messages = &channel.messages.items;
+        if messages.len() < count {
+            bail!(
+                "no {} messages to append in channel {:?}",
+                count,
+                channel_id
+            );
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        match channel_rowid(&tx, channel_id)? {
+            Some(rowid) => {
+                for message in &messages[messages.len() - count..] {
+                    insert_message(&tx, rowid, message)?;
+                }
+            }
+            None => {
+                insert_channel(&tx, position, channel)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_receipts(
+        &self,
+        data: &AppData,
+        channel_id: ChannelId,
+        arrived_at: &[u64],
+    ) -> anyhow::Result<()> {
+        let (user_id, group_id) = channel_key(&channel_id);
+        let tx = self.conn.unchecked_transaction()?;
+        for &arrived_at in arrived_at {
+            let message = find_message(data, channel_id, arrived_at)?;
+            tx.execute(
+                "UPDATE messages SET receipt = ?3
+                WHERE channel_id = (SELECT id FROM channels WHERE user_id = ?1 OR group_id = ?2)
+                    AND arrived_at = ?4",
+                params![user_id, group_id, message.receipt, arrived_at],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_reactions(
+        &self,
+        data: &AppData,
+        channel_id: ChannelId,
+        arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        let message = find_message(data, channel_id, arrived_at)?;
+        let tx = self.conn.unchecked_transaction()?;
+        let message_id = message_rowid(&tx, channel_id, arrived_at)?;
+        tx.execute(
+            "DELETE FROM reactions WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        insert_reactions(&tx, message_id, message)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_attachments(
+        &self,
+        data: &AppData,
+        channel_id: ChannelId,
+        arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        let message = find_message(data, channel_id, arrived_at)?;
+        let tx = self.conn.unchecked_transaction()?;
+        let message_id = message_rowid(&tx, channel_id, arrived_at)?;
+        tx.execute(
+            "DELETE FROM attachments WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        insert_attachments(&tx, message_id, message)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update_message(
+        &self,
+        data: &AppData,
+        channel_id: ChannelId,
+        arrived_at: u64,
+    ) -> anyhow::Result<()> {
+        let message = find_message(data, channel_id, arrived_at)?;
+        let quote = message.quote.as_deref();
+        let tx = self.conn.unchecked_transaction()?;
+        let message_id = message_rowid(&tx, channel_id, arrived_at)?;
+        tx.execute(
+            "UPDATE messages SET body = ?2, receipt = ?3,
+                quote_from_id = ?4, quote_arrived_at = ?5, quote_body = ?6, deleted = ?7,
+                expires_at = ?8, mentions = ?9, quote_mentions = ?10, group_change = ?11
+            WHERE id = ?1",
+            params![
+                message_id,
+                message.message,
+                message.receipt,
+                quote.map(|quote| quote.from_id),
+                quote.map(|quote| quote.arrived_at),
+                quote.and_then(|quote| quote.message.as_ref()),
+                message.deleted,
+                message.expires_at,
+                mentions_to_sql(&message.mentions)?,
+                mentions_to_sql(quote.map(|quote| &quote.mentions[..]).unwrap_or_default())?,
+                group_change_to_sql(message.group_change.as_ref())?,
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM attachments WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        insert_attachments(&tx, message_id, message)?;
+        tx.execute(
+            "DELETE FROM reactions WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        insert_reactions(&tx, message_id, message)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_message(
+        &self,
+        _data: &AppData,
+        channel_id: ChannelId,
+        message: &Message,
+    ) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let message_id = message_rowid(&tx, channel_id, message.arrived_at)?;
+        tx.execute("DELETE FROM messages WHERE id = ?1", params![message_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load_older_messages(
+        &self,
+        channel_id: ChannelId,
+        arrived_before: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        let (user_id, group_id) = channel_key(&channel_id);
+        let ids: Option<(i64, Option<i64>)> = self
+            .conn
+            .query_row(
+                "SELECT channels.id, MIN(messages.id)
+                FROM channels LEFT JOIN messages
+                    ON messages.channel_id = channels.id AND messages.arrived_at = ?3
+                WHERE channels.user_id = ?1 OR channels.group_id = ?2
+                GROUP BY channels.id",
+                params![user_id, group_id, arrived_before],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (rowid, message_id) = match ids {
+            Some((rowid, Some(message_id))) => (rowid, message_id),
+            _ => return Ok(Vec::new()),
+        };
+        let mut messages =
+            self.load_messages(OLDER_MESSAGE_IDS, params![rowid, message_id, limit])?;
+        Ok(messages.remove(&rowid).unwrap_or_default())
+    }
+
+    fn prune_messages(
+        &self,
+        _data: &AppData,
+        channel_id: ChannelId,
+        arrived_before: Option<u64>,
+        keep: Option<usize>,
+    ) -> anyhow::Result<Vec<Message>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let rowid = match channel_rowid(&tx, channel_id)? {
+            Some(rowid) => rowid,
+            None => return Ok(Vec::new()),
+        };
+        let arrived_before = arrived_before.unwrap_or(0);
+        let keep = keep.map_or(-1, |keep| keep as i64);
+        let mut messages =
+            self.load_messages(PRUNED_MESSAGE_IDS, params![rowid, arrived_before, keep])?;
+        tx.execute(
+            &format!("DELETE FROM messages WHERE id IN ({})", PRUNED_MESSAGE_IDS),
+            params![rowid, arrived_before, keep],
+        )?;
+        tx.commit()?;
+        Ok(messages.remove(&rowid).unwrap_or_default())
+    }
+
+    fn delete_expired_messages(&self, now: u64) -> anyhow::Result<Vec<(ChannelId, Message)>> {
+        let params = params![now, Receipt::Pending, Receipt::Failed, Receipt::Queued];
+        let tx = self.conn.unchecked_transaction()?;
+        let messages = self.load_messages(EXPIRED_MESSAGE_IDS, params)?;
+        let mut expired = Vec::new();
+        for (rowid, messages) in messages {
+            let (user_id, group_id) = tx.query_row(
+                "SELECT user_id, group_id FROM channels WHERE id = ?1",
+                params![rowid],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let channel_id = channel_id(rowid, user_id, group_id)?;
+            expired.extend(messages.into_iter().map(|message| (channel_id, message)));
+        }
+        tx.execute(
+            &format!("DELETE FROM messages WHERE id IN ({})", EXPIRED_MESSAGE_IDS),
+            params,
+        )?;
+        tx.commit()?;
+        Ok(expired)
+    }
+
+    fn update_outbox(&self, data: &AppData) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        replace_outbox(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn upsert_name(&self, data: &AppData, id: Uuid) -> anyhow::Result<()> {
+        let name = data
+            .names
+            .get(&id)
+            .ok_or_else(|| anyhow!("no name for user {}", id))?;
+        self.conn.execute(
+            "INSERT INTO names (id, name) VALUES (?1, ?2)
+            ON CONFLICT (id) DO UPDATE SET name = excluded.name",
+            params![id, name],
+        )?;
+        Ok(())
+    }
+
+    fn update_blocked(&self, data: &AppData) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        replace_blocked(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn upsert_contacts(&self, data: &AppData, ids: &[Uuid]) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for &id in ids {
+            if let Some(name) = data.names.get(&id) {
+                tx.execute(
+                    "INSERT INTO names (id, name) VALUES (?1, ?2)
+                    ON CONFLICT (id) DO UPDATE SET name = excluded.name",
+                    params![id, name],
+                )?;
+            }
+            if let Some(contact) = data.contacts.get(&id) {
+                insert_contact(&tx, id, contact)?;
+            }
+            let channel_id = ChannelId::User(id);
+            if let Ok((position, channel)) = find_channel(data, channel_id) {
+                match channel_rowid(&tx, channel_id)? {
+                    Some(rowid) => update_channel_row(&tx, rowid, position, channel)?,
+                    None => {
+                        insert_channel(&tx, position, channel)?;
+                    }
+                }
+            }
+        }
+        for (position, channel) in data.channels.items.iter().enumerate() {
+            let (user_id, group_id) = channel_key(&channel.id);
+            tx.execute(
+                "UPDATE channels SET position = ?1 WHERE user_id = ?2 OR group_id = ?3",
+                params![position, user_id, group_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the SQLite database at `db_path`.
+    ///
+    /// If the database is empty when the app data is loaded the first time, the data is migrated
+    /// from the `legacy_storage` (if any). The legacy data is left untouched.
+    pub fn open(
+        db_path: impl AsRef<Path>,
+        legacy_storage: Option<JsonStorage>,
+    ) -> anyhow::Result<Self> {
+        let db_path = db_path.as_ref();
+        if let Some(parent_dir) = db_path.parent() {
+            fs::create_dir_all(parent_dir).context("could not create database dir")?;
+        }
+        let conn = Connection::open(db_path)
+            .with_context(|| format!("failed to open database at '{}'", db_path.display()))?;
+        Self::with_connection(conn, legacy_storage)
+    }
+
+    fn with_connection(
+        conn: Connection,
+        legacy_storage: Option<JsonStorage>,
+    ) -> anyhow::Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        migrate(&conn)?;
+        Ok(Self {
+            conn,
+            legacy_storage,
+        })
+    }
+
+    fn is_empty(&self) -> anyhow::Result<bool> {
+        let has_data: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM channels) OR EXISTS (SELECT 1 FROM names)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(!has_data)
+    }
+
+    fn migrate_legacy_storage(&self) -> anyhow::Result<AppData> {
+        let legacy_storage = match self.legacy_storage.as_ref() {
+            Some(legacy_storage) => legacy_storage,
+            None => return Ok(Default::default()),
+        };
+        let data = legacy_storage.load_app_data_impl()?;
+        if !data.channels.items.is_empty() || !data.names.is_empty() {
+            info!(
+                "migrating {} channels from JSON storage to SQLite",
+                data.channels.items.len()
+            );
+            self.save_app_data(&data)
+                .context("failed to migrate app data to the SQLite database")?;
+        }
+        Ok(data)
+    }
+
+    fn load_app_data_impl(&self) -> anyhow::Result<AppData> {
+        let names = self
+            .conn
+            .prepare("SELECT id, name FROM names")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let contacts = self
+            .conn
+            .prepare("SELECT id, phone_number, name, profile_key, blocked FROM contacts")?
+            .query_map([], |row| {
+                let contact = Contact {
+                    phone_number: row.get(1)?,
+                    name: row.get(2)?,
+                    profile_key: row.get(3)?,
+                    blocked: row.get(4)?,
+                };
+                Ok((row.get(0)?, contact))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let mut blocked = HashSet::new();
+        let mut stmt = self.conn.prepare("SELECT user_id, group_id FROM blocked")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let user_id: Option<Uuid> = row.get(0)?;
+            let group_id: Option<Vec<u8>> = row.get(1)?;
+            blocked.insert(match (user_id, group_id) {
+                (Some(user_id), _) => ChannelId::User(user_id),
+                (None, Some(group_id)) => ChannelId::Group(
+                    group_id
+                        .try_into()
+                        .map_err(|_| anyhow!("invalid blocked group id"))?,
+                ),
+                (None, None) => unreachable!("logic error: violated database constraint"),
+            });
+        }
+
+        let mut members: HashMap<i64, Vec<Uuid>> = HashMap::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT channel_id, member_id FROM group_members ORDER BY position")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            members.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+
+        let mut messages = self.load_messages(LATEST_MESSAGE_IDS, params![MESSAGES_PAGE_SIZE])?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, user_id, group_id, name, unread_messages, master_key, revision,
+                expire_timer
+            FROM channels ORDER BY position",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut channels = Vec::new();
+        while let Some(row) = rows.next()? {
+            let channel_id: i64 = row.get(0)?;
+            let id = self::channel_id(channel_id, row.get(1)?, row.get(2)?)?;
+            let revision: Option<u32> = row.get(6)?;
+            let group_data = match revision {
+                Some(revision) => {
+                    let master_key: Vec<u8> = row.get(5)?;
+                    Some(GroupData {
+                        master_key_bytes: master_key.try_into().map_err(|_| {
+                            anyhow!("invalid group master key of channel {}", channel_id)
+                        })?,
+                        members: members.remove(&channel_id).unwrap_or_default(),
+                        revision,
+                    })
+                }
+                None => None,
+            };
+            let typing = if group_data.is_some() {
+                TypingSet::GroupTyping(HashSet::new())
+            } else {
+                TypingSet::SingleTyping(false)
+            };
+            channels.push(Channel {
+                id,
+                name: row.get(3)?,
+                group_data,
+                messages: StatefulList::with_items(
+                    messages.remove(&channel_id).unwrap_or_default(),
+                ),
+                unread_messages: row.get(4)?,
+                typing,
+                expire_timer: row.get(7)?,
+            });
+        }
+
+        let outbox = self
+            .conn
+            .prepare("SELECT message FROM outbox ORDER BY position")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|message| Ok(serde_json::from_str::<QueuedMessage>(&message?)?))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(AppData {
+            channels: FilteredStatefulList {
+                items: channels,
+                ..Default::default()
+            },
+            names,
+            contacts,
+            blocked,
+            outbox,
+            ..Default::default()
+        })
+    }
+
+    /// Loads the messages selected by the `message_ids` query, grouped by the row id of their
+    /// channel, in chronological order.
+    fn load_messages(
+        &self,
+        message_ids: &str,
+        params: &[&dyn ToSql],
+    ) -> anyhow::Result<HashMap<i64, Vec<Message>>> {
+        let mut attachments = self.load_attachments(message_ids, params)?;
+        let mut reactions = self.load_reactions(message_ids, params)?;
+
+        let mut messages: HashMap<i64, Vec<Message>> = HashMap::new();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, channel_id, from_id, arrived_at, body, receipt,
+                quote_from_id, quote_arrived_at, quote_body, deleted, expires_at,
+                mentions, quote_mentions, group_change
+            FROM messages WHERE id IN ({}) ORDER BY id",
+            message_ids
+        ))?;
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            let message_id: i64 = row.get(0)?;
+            let mut message = Message {
+                attachments: attachments.remove(&message_id).unwrap_or_default(),
+                reactions: reactions.remove(&message_id).unwrap_or_default(),
+                mentions: mentions_from_sql(row.get(11)?)?,
+                group_change: group_change_from_sql(row.get(13)?)?,
+                ..message_from_row(row)?
+            };
+            if let Some(quote) = message.quote.as_mut() {
+                quote.mentions = mentions_from_sql(row.get(12)?)?;
+            }
+            messages.entry(row.get(1)?).or_default().push(message);
+        }
+        Ok(messages)
+    }
+
+    fn load_attachments(
+        &self,
+        message_ids: &str,
+        params: &[&dyn ToSql],
+    ) -> anyhow::Result<HashMap<i64, Vec<Attachment>>> {
+        let mut attachments: HashMap<i64, Vec<Attachment>> = HashMap::new();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT message_id, id, content_type, filename, size, download
+            FROM attachments WHERE message_id IN ({}) ORDER BY position",
+            message_ids
+        ))?;
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            let filename: String = row.get(3)?;
+            let download: Option<String> = row.get(5)?;
+            let attachment = Attachment {
+                id: row.get(1)?,
+                content_type: row.get(2)?,
+                filename: filename.into(),
+                size: row.get(4)?,
+                download: download
+                    .map(|download| serde_json::from_str(&download))
+                    .transpose()?,
+            };
+            attachments.entry(row.get(0)?).or_default().push(attachment);
+        }
+        Ok(attachments)
+    }
+
+    fn load_reactions(
+        &self,
+        message_ids: &str,
+        params: &[&dyn ToSql],
+    ) -> anyhow::Result<HashMap<i64, Vec<(Uuid, String)>>> {
+        let mut reactions: HashMap<i64, Vec<(Uuid, String)>> = HashMap::new();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT message_id, from_id, emoji
+            FROM reactions WHERE message_id IN ({}) ORDER BY position",
+            message_ids
+        ))?;
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            reactions
+                .entry(row.get(0)?)
+                .or_default()
+                .push((row.get(1)?, row.get(2)?));
+        }
+        Ok(reactions)
+    }
+}
+
+/// Migrates the database schema to the latest version.
+///
+/// Fails if the database was created by a newer version of gurk.
+fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "database has schema version {}, but this version of gurk supports only versions up \
+            to {}; please upgrade gurk",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!(
+            "migrating database schema from version {} to {}",
+            from_version,
+            from_version + 1
+        );
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)
+            .and_then(|_| tx.execute_batch(&format!("PRAGMA user_version = {}", from_version + 1)))
+            .with_context(|| {
+                format!(
+                    "failed to migrate database schema from version {} to {}",
+                    from_version,
+                    from_version + 1
+                )
+            })?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Values of the `user_id` and `group_id` columns identifying the channel
+fn channel_key(channel_id: &ChannelId) -> (Option<&Uuid>, Option<&[u8]>) {
+    match channel_id {
+        ChannelId::User(id) => (Some(id), None),
+        ChannelId::Group(id) => (None, Some(&id[..])),
+    }
+}
+
+/// Channel id from the values of the `user_id` and `group_id` columns of the channel with the
+/// row id
+fn channel_id(
+    rowid: i64,
+    user_id: Option<Uuid>,
+    group_id: Option<Vec<u8>>,
+) -> anyhow::Result<ChannelId> {
+    Ok(match (user_id, group_id) {
+        (Some(user_id), _) => ChannelId::User(user_id),
+        (None, Some(group_id)) => ChannelId::Group(
+            group_id
+                .try_into()
+                .map_err(|_| anyhow!("invalid group id of channel {}", rowid))?,
+        ),
+        (None, None) => unreachable!("logic error: violated database constraint"),
+    })
+}
+
+fn channel_rowid(tx: &Transaction, channel_id: ChannelId) -> anyhow::Result<Option<i64>> {
+    let (user_id, group_id) = channel_key(&channel_id);
+    let rowid = tx
+        .query_row(
+            "SELECT id FROM channels WHERE user_id = ?1 OR group_id = ?2",
+            params![user_id, group_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(rowid)
+}
+
+/// Updates the metadata, position and group members of the stored channel.
+fn update_channel_row(
+    tx: &Transaction,
+    rowid: i64,
+    position: usize,
+    channel: &Channel,
+) -> anyhow::Result<()> {
+    let group_data = channel.group_data.as_ref();
+    tx.execute(
+        "UPDATE channels
+        SET position = ?2, name = ?3, unread_messages = ?4, master_key = ?5, revision = ?6,
+            expire_timer = ?7
+        WHERE id = ?1",
+        params![
+            rowid,
+            position,
+            channel.name,
+            channel.unread_messages,
+            group_data.map(|data| &data.master_key_bytes[..]),
+            group_data.map(|data| data.revision),
+            channel.expire_timer,
+        ],
+    )?;
+    tx.execute(
+        "DELETE FROM group_members WHERE channel_id = ?1",
+        params![rowid],
+    )?;
+    insert_group_members(tx, rowid, channel)
+}
+
+/// Inserts the channel including its messages, and returns its row id.
+fn insert_channel(tx: &Transaction, position: usize, channel: &Channel) -> anyhow::Result<i64> {
+    let (user_id, group_id) = channel_key(&channel.id);
+    let group_data = channel.group_data.as_ref();
+    tx.execute(
+        "INSERT INTO channels
+            (position, user_id, group_id, name, unread_messages, master_key, revision,
+                expire_timer)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            position,
+            user_id,
+            group_id,
+            channel.name,
+            channel.unread_messages,
+            group_data.map(|data| &data.master_key_bytes[..]),
+            group_data.map(|data| data.revision),
+            channel.expire_timer,
+        ],
+    )?;
+    let channel_id = tx.last_insert_rowid();
+
+    insert_group_members(tx, channel_id, channel)?;
+    for message in &channel.messages.items {
+        insert_message(tx, channel_id, message)?;
+    }
+    Ok(channel_id)
+}
+
+fn insert_group_members(
+    tx: &Transaction,
+    channel_id: i64,
+    channel: &Channel,
+) -> anyhow::Result<()> {
+    if let Some(group_data) = channel.group_data.as_ref() {
+        for (position, member_id) in group_data.members.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO group_members (channel_id, position, member_id) VALUES (?1, ?2, ?3)",
+                params![channel_id, position, member_id],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_message(tx: &Transaction, channel_id: i64, message: &Message) -> anyhow::Result<()> {
+    let quote = message.quote.as_deref();
+    tx.execute(
+        "INSERT INTO messages
+            (channel_id, from_id, arrived_at, body, receipt,
+                quote_from_id, quote_arrived_at, quote_body, deleted, expires_at,
+                mentions, quote_mentions, group_change)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            channel_id,
+            message.from_id,
+            message.arrived_at,
+            message.message,
+            message.receipt,
+            quote.map(|quote| quote.from_id),
+            quote.map(|quote| quote.arrived_at),
+            quote.and_then(|quote| quote.message.as_ref()),
+            message.deleted,
+            message.expires_at,
+            mentions_to_sql(&message.mentions)?,
+            mentions_to_sql(quote.map(|quote| &quote.mentions[..]).unwrap_or_default())?,
+            group_change_to_sql(message.group_change.as_ref())?,
+        ],
+    )?;
+    let message_id = tx.last_insert_rowid();
+    insert_attachments(tx, message_id, message)?;
+    insert_reactions(tx, message_id, message)
+}
+
+fn insert_contact(tx: &Transaction, id: Uuid, contact: &Contact) -> anyhow::Result<()> {
+    tx.execute(
+        "INSERT INTO contacts (id, phone_number, name, profile_key, blocked)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (id) DO UPDATE SET phone_number = excluded.phone_number,
+            name = excluded.name, profile_key = excluded.profile_key, blocked = excluded.blocked",
+        params![
+            id,
+            contact.phone_number,
+            contact.name,
+            contact.profile_key,
+            contact.blocked,
+        ],
+    )?;
+    Ok(())
+}
+
+fn replace_blocked(tx: &Transaction, data: &AppData) -> anyhow::Result<()> {
+    tx.execute("DELETE FROM blocked", [])?;
+    for channel_id in &data.blocked {
+        let (user_id, group_id) = channel_key(channel_id);
+        tx.execute(
+            "INSERT INTO blocked (user_id, group_id) VALUES (?1, ?2)",
+            params![user_id, group_id],
+        )?;
+    }
+    Ok(())
+}
+
+fn replace_outbox(tx: &Transaction, data: &AppData) -> anyhow::Result<()> {
+    tx.execute("DELETE FROM outbox", [])?;
+    for (position, queued) in data.outbox.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO outbox (position, message) VALUES (?1, ?2)",
+            params![position, serde_json::to_string(queued)?],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_attachments(tx: &Transaction, message_id: i64, message: &Message) -> anyhow::Result<()> {
+    for (position, attachment) in message.attachments.iter().enumerate() {
+        let download = attachment
+            .download
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        tx.execute(
+            "INSERT INTO attachments
+                (message_id, position, id, content_type, filename, size, download)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message_id,
+                position,
+                attachment.id,
+                attachment.content_type,
+                attachment.filename.to_string_lossy(),
+                attachment.size,
+                download,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_reactions(tx: &Transaction, message_id: i64, message: &Message) -> anyhow::Result<()> {
+    for (position, (from_id, emoji)) in message.reactions.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO reactions (message_id, position, from_id, emoji)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![message_id, position, from_id, emoji],
+        )?;
+    }
+    Ok(())
+}
+
+/// Mentions stored as JSON, or NULL if there are none
+fn mentions_to_sql(mentions: &[Mention]) -> anyhow::Result<Option<String>> {
+    if mentions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::to_string(mentions)?))
+}
+
+fn mentions_from_sql(mentions: Option<String>) -> anyhow::Result<Vec<Mention>> {
+    Ok(mentions
+        .map(|mentions| serde_json::from_str(&mentions))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+fn group_change_to_sql(change: Option<&GroupChange>) -> anyhow::Result<Option<String>> {
+    Ok(change.map(serde_json::to_string).transpose()?)
+}
+
+fn group_change_from_sql(change: Option<String>) -> anyhow::Result<Option<GroupChange>> {
+    Ok(change
+        .map(|change| serde_json::from_str(&change))
+        .transpose()?)
+}
+
+/// Row id of the stored message which arrived at `arrived_at` in the channel
+fn message_rowid(tx: &Transaction, channel_id: ChannelId, arrived_at: u64) -> anyhow::Result<i64> {
+    let (user_id, group_id) = channel_key(&channel_id);
+    tx.query_row(
+        "SELECT id FROM messages
+        WHERE channel_id = (SELECT id FROM channels WHERE user_id = ?1 OR group_id = ?2)
+            AND arrived_at = ?3",
+        params![user_id, group_id, arrived_at],
+        |row| row.get(0),
+    )
+    .optional()?
+    .ok_or_else(|| anyhow!("message {} is not stored", arrived_at))
+}
+
+/// Reads a message without attachments, reactions, mentions and group change from a row of the
+/// `messages` table.
+///
+/// Expects the columns `from_id`, `arrived_at`, `body`, `receipt`, `quote_from_id`,
+/// `quote_arrived_at`, `quote_body`, `deleted` and `expires_at` at the indices 2 to 10.
+fn message_from_row(row: &Row) -> rusqlite::Result<Message> {
+    let quote_from_id: Option<Uuid> = row.get(6)?;
+    let quote = match quote_from_id {
+        Some(from_id) => Some(Box::new(Message {
+            from_id,
+            message: row.get(8)?,
+            arrived_at: row.get(7)?,
+            quote: None,
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Sent,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        })),
+        None => None,
+    };
+    Ok(Message {
+        from_id: row.get(2)?,
+        message: row.get(4)?,
+        arrived_at: row.get(3)?,
+        quote,
+        attachments: Default::default(),
+        reactions: Default::default(),
+        receipt: row.get(5)?,
+        deleted: row.get(9)?,
+        expires_at: row.get(10)?,
+        mentions: Default::default(),
+        group_change: None,
+    })
+}
+
+impl ToSql for Receipt {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok((*self as i32).into())
+    }
+}
+
+impl FromSql for Receipt {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match i32::column_result(value)? {
+            -5 => Self::Queued,
+            -4 => Self::Failed,
+            -3 => Self::Pending,
+            -1 => Self::Sent,
+            0 => Self::Delivered,
+            1 => Self::Read,
+            _ => Self::Nothing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{BoxData, MENTION_PLACEHOLDER};
+
+    use tempfile::{tempdir, NamedTempFile};
+
+    fn in_memory_storage() -> SqliteStorage {
+        SqliteStorage::with_connection(Connection::open_in_memory().unwrap(), None).unwrap()
+    }
+
+    fn example_app_data(user_id: Uuid, user_name: &str) -> AppData {
+        let contact_id = Uuid::new_v4();
+        let message = Message {
+            from_id: contact_id,
+            message: Some("Hello, World!".to_string()),
+            arrived_at: 1642334397421,
+            quote: None,
+            attachments: vec![Attachment {
+                id: "2022-01-16T11:59:58.405665+00:00".to_string(),
+                content_type: "image/jpeg".to_string(),
+                filename: "/tmp/gurk/signal-2022-01-16T11:59:58.405665+00:00.jpg".into(),
+                size: 238987,
+                download: None,
+            }],
+            reactions: vec![(user_id, "👍".to_string())],
+            receipt: Receipt::Read,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        let reply = Message {
+            from_id: user_id,
+            message: Some("Hi!".to_string()),
+            arrived_at: 1642334397422,
+            quote: Some(Box::new(Message {
+                quote: None,
+                attachments: Default::default(),
+                reactions: Default::default(),
+                receipt: Receipt::Sent,
+                ..message.clone()
+            })),
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Delivered,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        let contact_channel = Channel {
+            id: ChannelId::User(contact_id),
+            name: "Marla Singer".to_string(),
+            group_data: None,
+            messages: StatefulList::with_items(vec![message, reply]),
+            unread_messages: 1,
+            typing: TypingSet::SingleTyping(false),
+            expire_timer: None,
+        };
+        let group_channel = Channel {
+            id: ChannelId::Group([42; 32]),
+            name: "Project Mayhem".to_string(),
+            group_data: Some(GroupData {
+                master_key_bytes: [23; 32],
+                members: vec![user_id, contact_id],
+                revision: 7,
+            }),
+            messages: StatefulList::with_items(vec![Message {
+                from_id: contact_id,
+                message: None,
+                arrived_at: 1642334397423,
+                quote: None,
+                attachments: Default::default(),
+                reactions: Default::default(),
+                receipt: Receipt::Nothing,
+                deleted: false,
+                expires_at: None,
+                mentions: Default::default(),
+                group_change: None,
+            }]),
+            unread_messages: 0,
+            typing: TypingSet::GroupTyping(HashSet::new()),
+            expire_timer: None,
+        };
+        AppData {
+            channels: FilteredStatefulList {
+                items: vec![group_channel, contact_channel],
+                ..Default::default()
+            },
+            names: [
+                (user_id, user_name.to_string()),
+                (contact_id, "Marla Singer".to_string()),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sqlite_storage_load_existing_app_data() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = AppData {
+            input: BoxData::empty(),
+            search_box: BoxData::empty(),
+            names: [(user_id, user_name.clone())].iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+        let loaded_app_data = storage.load_app_data(user_id, user_name)?;
+
+        assert_eq!(loaded_app_data, app_data);
+        assert_eq!(loaded_app_data.channels.state.selected(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_load_non_existent_app_data() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let storage = SqliteStorage::open(dir.path().join("some-dir/gurk.sqlite"), None)?;
+
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden".to_string();
+        let app_data = storage.load_app_data(user_id, user_name.clone())?;
+
+        assert_eq!(
+            app_data,
+            AppData {
+                names: [(user_id, user_name)].iter().cloned().collect(),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_roundtrip() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden";
+        let app_data = example_app_data(user_id, user_name);
+
+        let dir = tempdir()?;
+        let db_path = dir.path().join("gurk.sqlite");
+        SqliteStorage::open(&db_path, None)?.save_app_data(&app_data)?;
+
+        let storage = SqliteStorage::open(&db_path, None)?;
+        let loaded_app_data = storage.load_app_data(user_id, user_name.to_string())?;
+
+        assert_eq!(loaded_app_data, app_data);
+        assert_eq!(
+            loaded_app_data.channels.items[0].typing,
+            TypingSet::GroupTyping(HashSet::new())
+        );
+
+        // saving again replaces the old data
+        storage.save_app_data(&loaded_app_data)?;
+        assert_eq!(
+            storage.load_app_data(user_id, user_name.to_string())?,
+            app_data
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_selected_channel() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden";
+        let app_data = example_app_data(user_id, user_name);
+
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+        let app_data = storage.load_app_data(user_id, user_name.to_string())?;
+
+        assert_eq!(app_data.channels.state.selected(), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_granular_operations() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden";
+        let mut app_data = example_app_data(user_id, user_name);
+
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+
+        // new message in the contact channel, which is bubbled up
+        let contact_channel = &mut app_data.channels.items[1];
+        let contact_channel_id = contact_channel.id;
+        contact_channel.messages.items.push(Message {
+            from_id: user_id,
+            message: Some("Are you there?".to_string()),
+            arrived_at: 1642334397424,
+            quote: None,
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Sent,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        });
+        contact_channel.unread_messages = 0;
+        storage.append_message(&app_data, contact_channel_id)?;
+        app_data.channels.items.swap(0, 1);
+        storage.update_channel(&app_data, contact_channel_id)?;
+
+        // receipt and reactions of the first message
+        let message = &mut app_data.channels.items[0].messages.items[0];
+        message.receipt = Receipt::Delivered;
+        message.reactions = vec![(Uuid::new_v4(), "❤️".to_string())];
+        let arrived_at = message.arrived_at;
+        storage.update_receipts(&app_data, contact_channel_id, &[arrived_at])?;
+        storage.update_reactions(&app_data, contact_channel_id, arrived_at)?;
+
+        // disappearing messages are enabled
+        let contact_channel = &mut app_data.channels.items[0];
+        contact_channel.expire_timer = Some(60 * 60);
+        storage.update_channel(&app_data, contact_channel_id)?;
+
+        // message which is deleted for everyone
+        let message = &mut app_data.channels.items[0].messages.items[1];
+        message.message = None;
+        message.deleted = true;
+        let arrived_at = message.arrived_at;
+        storage.update_message(&app_data, contact_channel_id, arrived_at)?;
+
+        // system messages about changes of the group
+        let group_channel = &mut app_data.channels.items[1];
+        let group_channel_id = group_channel.id;
+        group_channel.messages.items.push(Message::group_change(
+            GroupChange::TitleChanged("Paper Street Soap Company".to_string()),
+            1642334397427,
+        ));
+        group_channel.messages.items.push(Message::group_change(
+            GroupChange::MemberAdded(Uuid::new_v4()),
+            1642334397428,
+        ));
+        storage.append_messages(&app_data, group_channel_id, 2)?;
+
+        // message which failed to send is discarded
+        let contact_channel = &mut app_data.channels.items[0];
+        contact_channel.messages.items.push(Message {
+            from_id: user_id,
+            message: Some("Hello?".to_string()),
+            arrived_at: 1642334397426,
+            quote: None,
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Failed,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        });
+        storage.append_message(&app_data, contact_channel_id)?;
+        let failed_message = app_data.channels.items[0].messages.items.pop().unwrap();
+        storage.delete_message(&app_data, contact_channel_id, &failed_message)?;
+
+        // message which is queued while offline
+        let queued_message = Message {
+            from_id: user_id,
+            message: Some("Are you there?".to_string()),
+            arrived_at: 1642334397427,
+            quote: None,
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt: Receipt::Queued,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        let contact_channel = &mut app_data.channels.items[0];
+        contact_channel.messages.items.push(queued_message.clone());
+        storage.append_message(&app_data, contact_channel_id)?;
+        app_data.outbox.push(QueuedMessage {
+            channel_id: contact_channel_id,
+            message: queued_message,
+            attempts: 0,
+        });
+        storage.update_outbox(&app_data)?;
+
+        // group is renamed
+        let group_channel = &mut app_data.channels.items[1];
+        group_channel.name = "Paper Street Soap Company".to_string();
+        group_channel.group_data.as_mut().unwrap().revision += 1;
+        let group_channel_id = group_channel.id;
+        storage.update_channel(&app_data, group_channel_id)?;
+
+        // message in a new channel
+        let new_contact_id = Uuid::new_v4();
+        app_data
+            .names
+            .insert(new_contact_id, "Robert Paulson".to_string());
+        app_data.contacts.insert(
+            new_contact_id,
+            Contact {
+                phone_number: Some("+0000000002".to_string()),
+                name: "Robert Paulson".to_string(),
+                profile_key: vec![42; 32],
+                blocked: false,
+            },
+        );
+        storage.upsert_contacts(&app_data, &[new_contact_id])?;
+
+        // blocked user and group
+        app_data.blocked.insert(ChannelId::User(new_contact_id));
+        app_data.blocked.insert(ChannelId::Group([42; 32]));
+        storage.update_blocked(&app_data)?;
+        app_data.channels.items.insert(
+            0,
+            Channel {
+                id: ChannelId::User(new_contact_id),
+                name: "Robert Paulson".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(vec![Message {
+                    from_id: new_contact_id,
+                    message: Some(format!(
+                        "{}, his name was Robert Paulson.",
+                        MENTION_PLACEHOLDER
+                    )),
+                    arrived_at: 1642334397425,
+                    quote: None,
+                    attachments: Default::default(),
+                    reactions: Default::default(),
+                    receipt: Receipt::Sent,
+                    deleted: false,
+                    expires_at: Some(1642334397425 + 60 * 60 * 1000),
+                    mentions: vec![Mention {
+                        start: 0,
+                        uuid: user_id,
+                    }],
+                    group_change: None,
+                }]),
+                unread_messages: 1,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            },
+        );
+        storage.append_message(&app_data, ChannelId::User(new_contact_id))?;
+        storage.update_channel(&app_data, ChannelId::User(new_contact_id))?;
+
+        let loaded_app_data = storage.load_app_data(user_id, user_name.to_string())?;
+        assert_eq!(loaded_app_data, app_data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_migrates_json_storage() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let user_name = "Tyler Durden";
+        let app_data = example_app_data(user_id, user_name);
+
+        let json_file = NamedTempFile::new()?;
+        JsonStorage::save_to(&app_data, json_file.path(), None)?;
+        let json_storage = JsonStorage::new(json_file.path().to_owned(), None);
+
+        let dir = tempdir()?;
+        let db_path = dir.path().join("gurk.sqlite");
+        let storage = SqliteStorage::open(&db_path, Some(json_storage))?;
+        let loaded_app_data = storage.load_app_data(user_id, user_name.to_string())?;
+        assert_eq!(loaded_app_data, app_data);
+
+        // migrated data is now in the database
+        let storage = SqliteStorage::open(&db_path, None)?;
+        let loaded_app_data = storage.load_app_data(user_id, user_name.to_string())?;
+        assert_eq!(loaded_app_data, app_data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_schema_version() -> anyhow::Result<()> {
+        let storage = in_memory_storage();
+        let version: usize = storage
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(version, MIGRATIONS.len());
+
+        // reopening an up-to-date database is a no-op
+        migrate(&storage.conn)?;
+
+        storage
+            .conn
+            .execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))?;
+        assert!(migrate(&storage.conn).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_loads_messages_in_pages() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let num_messages = MESSAGES_PAGE_SIZE + 5;
+        let messages = (0..num_messages as u64)
+            .map(|arrived_at| Message {
+                from_id: user_id,
+                message: Some(arrived_at.to_string()),
+                arrived_at,
+                quote: None,
+                attachments: Default::default(),
+                reactions: vec![(user_id, "👍".to_string())],
+                receipt: Receipt::Sent,
+                deleted: false,
+                expires_at: None,
+                mentions: Default::default(),
+                group_change: None,
+            })
+            .collect::<Vec<_>>();
+        let app_data = AppData {
+            channels: FilteredStatefulList::_with_items(vec![Channel {
+                id: ChannelId::User(user_id),
+                name: "Tyler Durden".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(messages.clone()),
+                unread_messages: 0,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            }]),
+            ..Default::default()
+        };
+
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+
+        let mut loaded_app_data = storage.load_app_data(user_id, "Tyler Durden".to_string())?;
+        let channel = &mut loaded_app_data.channels.items[0];
+        assert_eq!(channel.messages.items, messages[5..]);
+
+        let older_messages = storage.load_older_messages(channel.id, 5, MESSAGES_PAGE_SIZE)?;
+        assert_eq!(older_messages, messages[..5]);
+        assert!(storage.load_older_messages(channel.id, 0, 10)?.is_empty());
+
+        // saving the partially loaded app data keeps the older messages
+        channel.messages.items.last_mut().unwrap().receipt = Receipt::Read;
+        storage.save_app_data(&loaded_app_data)?;
+        let num_stored: usize =
+            storage
+                .conn
+                .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+        assert_eq!(num_stored, num_messages);
+        assert_eq!(
+            storage.load_older_messages(ChannelId::User(user_id), 5, MESSAGES_PAGE_SIZE)?,
+            messages[..5]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_prune_messages() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let messages = (0..10)
+            .map(|arrived_at| Message {
+                from_id: user_id,
+                message: Some(arrived_at.to_string()),
+                arrived_at,
+                quote: None,
+                attachments: Default::default(),
+                reactions: Default::default(),
+                receipt: Receipt::Sent,
+                deleted: false,
+                expires_at: None,
+                mentions: Default::default(),
+                group_change: None,
+            })
+            .collect::<Vec<_>>();
+        let app_data = AppData {
+            channels: FilteredStatefulList::_with_items(vec![Channel {
+                id: ChannelId::User(user_id),
+                name: "Tyler Durden".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(messages.clone()),
+                unread_messages: 0,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            }]),
+            ..Default::default()
+        };
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+        let channel_id = ChannelId::User(user_id);
+
+        let pruned = storage.prune_messages(&app_data, channel_id, Some(2), None)?;
+        assert_eq!(pruned, messages[..2]);
+        let pruned = storage.prune_messages(&app_data, channel_id, None, Some(5))?;
+        assert_eq!(pruned, messages[2..5]);
+        let pruned = storage.prune_messages(&app_data, channel_id, Some(7), Some(4))?;
+        assert_eq!(pruned, messages[5..7]);
+        assert!(storage
+            .prune_messages(&app_data, channel_id, None, None)?
+            .is_empty());
+
+        let loaded_app_data = storage.load_app_data(user_id, "Tyler Durden".to_string())?;
+        assert_eq!(
+            loaded_app_data.channels.items[0].messages.items,
+            messages[7..]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_storage_delete_expired_messages() -> anyhow::Result<()> {
+        let user_id = Uuid::new_v4();
+        let message = |arrived_at: u64, expires_at, receipt| Message {
+            from_id: user_id,
+            message: Some(arrived_at.to_string()),
+            arrived_at,
+            quote: None,
+            attachments: Default::default(),
+            reactions: Default::default(),
+            receipt,
+            deleted: false,
+            expires_at,
+            mentions: Default::default(),
+            group_change: None,
+        };
+        let messages = vec![
+            message(0, Some(5), Receipt::Sent),
+            message(1, Some(5), Receipt::Queued),
+            message(2, Some(20), Receipt::Sent),
+            message(3, None, Receipt::Sent),
+        ];
+        let app_data = AppData {
+            channels: FilteredStatefulList::_with_items(vec![Channel {
+                id: ChannelId::User(user_id),
+                name: "Tyler Durden".to_string(),
+                group_data: None,
+                messages: StatefulList::with_items(messages.clone()),
+                unread_messages: 0,
+                typing: TypingSet::SingleTyping(false),
+                expire_timer: None,
+            }]),
+            ..Default::default()
+        };
+        let storage = in_memory_storage();
+        storage.save_app_data(&app_data)?;
+
+        let expired = storage.delete_expired_messages(10)?;
+        assert_eq!(expired, [(ChannelId::User(user_id), messages[0].clone())]);
+        assert!(storage.delete_expired_messages(10)?.is_empty());
+
+        let loaded_app_data = storage.load_app_data(user_id, "Tyler Durden".to_string())?;
+        assert_eq!(
+            loaded_app_data.channels.items[0].messages.items,
+            messages[1..]
+        );
+
+        Ok(())
+    }
+}
@@ -3,13 +3,13 @@ use crate::cursor::Cursor;
 use crate::shortcuts::{ShortCut, SHORTCUTS};
 use crate::util;
 use crate::{app, App};
-use app::Receipt;
+use app::{GroupChange, Receipt};
 
 use chrono::{Datelike, Timelike};
 use itertools::Itertools;
 use tui::backend::Backend;
 use tui::layout::{Constraint, Corner, Direction, Layout, Rect};
-use tui::style::{Color, Style};
+use tui::style::{Color, Modifier, Style};
 use tui::text::{Span, Spans, Text};
 use tui::widgets::{Block, Borders, List, ListItem, Paragraph};
 use tui::Frame;
@@ -123,6 +123,9 @@ fn draw_channels_column<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect)
     }
 }
 
+/// Prefix of the names of blocked channels
+const BLOCKED_LABEL: &str = "⊘ ";
+
 fn draw_channels<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let channel_list_width = area.width.saturating_sub(2) as usize;
     let pattern = app.data.search_box.data.as_str();
@@ -138,7 +141,12 @@ fn draw_channels<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
             } else {
                 String::new()
             };
-            let label = format!("{}{}", channel.name, unread_messages_label);
+            let blocked_label = if app.data.blocked.contains(&channel.id) {
+                BLOCKED_LABEL
+            } else {
+                ""
+            };
+            let label = format!("{}{}{}", blocked_label, channel.name, unread_messages_label);
             let label_width = label.width();
             let label = if label.width() <= channel_list_width || unread_messages_label.is_empty() {
                 label
@@ -148,7 +156,12 @@ fn draw_channels<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
                 while !channel.name.is_char_boundary(end) {
                     end += 1;
                 }
-                format!("{}{}", &channel.name[0..end], unread_messages_label)
+                format!(
+                    "{}{}{}",
+                    blocked_label,
+                    &channel.name[0..end],
+                    unread_messages_label
+                )
             };
             ListItem::new(vec![Spans::from(Span::raw(label))])
         })
@@ -256,6 +269,7 @@ fn prepare_receipts(app: &mut App, height: usize) {
         Some(c) if !c.messages.items.is_empty() => c,
         _ => return,
     };
+    let is_blocked = app.data.blocked.contains(&channel.id);
 
     let offset = if let Some(selected) = channel.messages.state.selected() {
         channel
@@ -275,7 +289,12 @@ fn prepare_receipts(app: &mut App, height: usize) {
         .rev()
         .skip(offset)
         .for_each(|msg| match msg.receipt {
-            Receipt::Read | Receipt::Nothing | Receipt::Sent => (),
+            Receipt::Read
+            | Receipt::Nothing
+            | Receipt::Sent
+            | Receipt::Queued
+            | Receipt::Pending
+            | Receipt::Failed => (),
             Receipt::Delivered => {
                 if msg.from_id != user_id {
                     to_send.push((msg.from_id, msg.arrived_at));
@@ -283,7 +302,7 @@ fn prepare_receipts(app: &mut App, height: usize) {
                 }
             }
         });
-    if !to_send.is_empty() {
+    if !to_send.is_empty() && !is_blocked {
         to_send
             .into_iter()
             .for_each(|(u, t)| app.add_receipt_event(ReceiptEvent::new(u, t, Receipt::Read)))
@@ -384,7 +403,11 @@ fn draw_messages<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
         items.insert(unread_messages, ListItem::new(Span::from(new_message_line)));
     }
 
-    let title = format!("Messages {}", writing_people);
+    let expire_timer = channel
+        .expire_timer
+        .map(|timer| format!("⏱ {} ", display_expire_timer(timer)))
+        .unwrap_or_default();
+    let title = format!("Messages {}{}", expire_timer, writing_people);
 
     let list = List::new(items)
         .block(Block::default().title(title).borders(Borders::ALL))
@@ -444,6 +467,9 @@ fn display_receipt(receipt: Receipt, show: ShowReceipt) -> &'static str {
     use ShowReceipt::*;
     match (show, receipt) {
         (Yes, Receipt::Nothing) => "  ",
+        (Yes, Receipt::Queued) => "⧗ ",
+        (Yes, Receipt::Failed) => "✗ ",
+        (Yes, Receipt::Pending) => "◌ ",
         (Yes, Receipt::Sent) => "○ ",
         (Yes, Receipt::Delivered) => "◉ ",
         (Yes, Receipt::Read) => "● ",
@@ -452,6 +478,38 @@ fn display_receipt(receipt: Receipt, show: ShowReceipt) -> &'static str {
     }
 }
 
+/// Formats the timer of disappearing messages in seconds in the largest unit dividing it.
+fn display_expire_timer(timer: u32) -> String {
+    const UNITS: &[(u32, &str)] = &[
+        (7 * 24 * 60 * 60, "w"),
+        (24 * 60 * 60, "d"),
+        (60 * 60, "h"),
+        (60, "m"),
+    ];
+    UNITS
+        .iter()
+        .find(|(secs, _)| timer.checked_rem(*secs) == Some(0))
+        .map(|(secs, unit)| format!("{}{}", timer / secs, unit))
+        .unwrap_or_else(|| format!("{}s", timer))
+}
+
+/// Text of the system message about the change of a group
+pub fn display_group_change(change: &GroupChange, name: impl FnOnce(Uuid) -> String) -> String {
+    match change {
+        GroupChange::MemberAdded(uuid) => format!("{} joined the group", name(*uuid)),
+        GroupChange::MemberRemoved(uuid) => format!("{} is no longer in the group", name(*uuid)),
+        GroupChange::TitleChanged(title) => format!("title changed to {}", title),
+        GroupChange::ExpireTimerChanged(Some(timer)) => format!(
+            "disappearing messages set to {}",
+            display_expire_timer(*timer)
+        ),
+        GroupChange::ExpireTimerChanged(None) => "disappearing messages turned off".to_string(),
+    }
+}
+
+/// Text shown instead of a message deleted for everyone
+const DELETED_MESSAGE_TEXT: &str = "This message was deleted.";
+
 #[allow(clippy::too_many_arguments)]
 fn display_message(
     names: &NameResolver,
@@ -461,9 +519,13 @@ fn display_message(
     height: usize,
     show_receipt: ShowReceipt,
 ) -> Option<ListItem<'static>> {
+    let receipt_color = match msg.receipt {
+        Receipt::Failed => Color::Red,
+        _ => Color::Yellow,
+    };
     let receipt = Span::styled(
         display_receipt(msg.receipt, show_receipt),
-        Style::default().fg(Color::Yellow),
+        Style::default().fg(receipt_color),
     );
 
     let time = Span::styled(
@@ -471,6 +533,34 @@ fn display_message(
         Style::default().fg(Color::Yellow),
     );
 
+    let wrap_opts = textwrap::Options::new(width)
+        .initial_indent(prefix)
+        .subsequent_indent(prefix);
+
+    if let Some(change) = msg.group_change.as_ref() {
+        let text = display_group_change(change, |uuid| names.resolve(uuid).0.to_string());
+        let style = Style::default()
+            .fg(Color::Rgb(150, 150, 150))
+            .add_modifier(Modifier::ITALIC);
+        let spans: Vec<Spans> = textwrap::wrap(&text, &wrap_opts)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, line)| {
+                let res = if idx == 0 {
+                    vec![
+                        receipt.clone(),
+                        time.clone(),
+                        Span::styled(line.strip_prefix(prefix).unwrap().to_string(), style),
+                    ]
+                } else {
+                    vec![Span::styled(line.to_string(), style)]
+                };
+                Spans::from(res)
+            })
+            .collect();
+        return Some(ListItem::new(Text::from(spans)));
+    }
+
     let (from, from_color) = names.resolve(msg.from_id);
 
     let from = Span::styled(
@@ -487,18 +577,28 @@ fn display_message(
     );
     let delimiter = Span::from(": ");
 
-    let wrap_opts = textwrap::Options::new(width)
-        .initial_indent(prefix)
-        .subsequent_indent(prefix);
-
     // collect message text
-    let mut text = msg.message.clone().unwrap_or_default();
+    let mut text = if msg.deleted {
+        DELETED_MESSAGE_TEXT.to_string()
+    } else {
+        msg.text_with_mentions(|uuid| names.resolve(uuid).0.to_string())
+            .unwrap_or_default()
+    };
     add_attachments(msg, &mut text);
     if text.is_empty() {
         return None; // no text => nothing to render
     }
     add_reactions(msg, &mut text);
 
+    let mentions: Vec<(String, Color)> = msg
+        .mentions
+        .iter()
+        .map(|mention| {
+            let (name, color) = names.resolve(mention.uuid);
+            (format!("@{}", name), color)
+        })
+        .collect();
+
     let mut spans: Vec<Spans> = vec![];
 
     // prepend quote if any
@@ -539,15 +639,19 @@ fn display_message(
             .enumerate()
             .map(|(idx, line)| {
                 let res = if add_time && idx == 0 {
-                    vec![
+                    let mut res = vec![
                         receipt.clone(),
                         time.clone(),
                         from.clone(),
                         delimiter.clone(),
-                        Span::from(line.strip_prefix(prefix).unwrap().to_string()),
-                    ]
+                    ];
+                    res.extend(highlight_mentions(
+                        line.strip_prefix(prefix).unwrap(),
+                        &mentions,
+                    ));
+                    res
                 } else {
-                    vec![Span::from(line.to_string())]
+                    highlight_mentions(&line, &mentions)
                 };
                 Spans::from(res)
             }),
@@ -561,6 +665,35 @@ fn display_message(
     Some(ListItem::new(Text::from(spans)))
 }
 
+/// Splits the line into spans, such that the mentions are highlighted in the colors of the
+/// mentioned users.
+fn highlight_mentions(line: &str, mentions: &[(String, Color)]) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut rest = line;
+    loop {
+        let next_mention = mentions
+            .iter()
+            .filter_map(|(mention, color)| Some((rest.find(mention.as_str())?, mention, color)))
+            .min_by_key(|&(idx, mention, _)| (idx, std::cmp::Reverse(mention.len())));
+        let (idx, mention, &color) = match next_mention {
+            Some(next_mention) => next_mention,
+            None => break,
+        };
+        if idx > 0 {
+            spans.push(Span::raw(rest[..idx].to_string()));
+        }
+        spans.push(Span::styled(
+            mention.clone(),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ));
+        rest = &rest[idx + mention.len()..];
+    }
+    if !rest.is_empty() || spans.is_empty() {
+        spans.push(Span::raw(rest.to_string()));
+    }
+    spans
+}
+
 fn add_attachments(msg: &app::Message, out: &mut String) {
     if !msg.attachments.is_empty() {
         if !out.is_empty() {
@@ -571,18 +704,47 @@ fn add_attachments(msg: &app::Message, out: &mut String) {
             out,
             format_args!(
                 "{}",
-                msg.attachments
-                    .iter()
-                    .format_with("\n", |attachment, f| f(&format_args!(
-                        "<file://{}>",
-                        attachment.filename.display()
-                    )))
+                msg.attachments.iter().format_with("\n", |attachment, f| {
+                    match attachment.download.as_ref() {
+                        None => f(&format_args!("<file://{}>", attachment.filename.display())),
+                        Some(download) => {
+                            let name = download
+                                .file_name
+                                .as_deref()
+                                .unwrap_or(&attachment.content_type);
+                            let size = format_size(attachment.size);
+                            if download.in_progress {
+                                f(&format_args!("<downloading {} ({})>", name, size))
+                            } else if let Some(error) = download.error.as_ref() {
+                                f(&format_args!(
+                                    "<{} ({}): download failed: {}; select to retry>",
+                                    name, size, error
+                                ))
+                            } else {
+                                f(&format_args!("<{} ({}): select to download>", name, size))
+                            }
+                        }
+                    }
+                })
             ),
         )
         .expect("formatting attachments failed");
     }
 }
 
+/// Formats the size in bytes for humans.
+fn format_size(bytes: u32) -> String {
+    const KIB: f64 = 1024.0;
+    let bytes = bytes as f64;
+    if bytes < KIB {
+        format!("{} B", bytes)
+    } else if bytes < KIB * KIB {
+        format!("{:.1} KiB", bytes / KIB)
+    } else {
+        format!("{:.1} MiB", bytes / (KIB * KIB))
+    }
+}
+
 fn add_reactions(msg: &app::Message, out: &mut String) {
     if !msg.reactions.is_empty() {
         fmt::write(
@@ -782,14 +944,17 @@ impl<'a> NameResolver<'a> {
 
 fn displayed_quote(names: &NameResolver, quote: &app::Message) -> Option<String> {
     let (name, _) = names.resolve(quote.from_id);
-    Some(format!("({}) {}", name, quote.message.as_ref()?))
+    let text = quote.text_with_mentions(|uuid| names.resolve(uuid).0.to_string())?;
+    Some(format!("({}) {}", name, text))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::app::{Message, Receipt};
+    use crate::app::{Mention, Message, Receipt, MENTION_PLACEHOLDER};
     use crate::signal::Attachment;
 
+    use presage::prelude::proto::AttachmentPointer;
+
     use super::*;
 
     const USER_ID: Uuid = Uuid::nil();
@@ -816,6 +981,10 @@ mod tests {
             attachments: vec![],
             reactions: vec![],
             receipt: Receipt::Sent,
+            deleted: false,
+            expires_at: None,
+            mentions: Default::default(),
+            group_change: None,
         }
     }
 
@@ -825,9 +994,44 @@ mod tests {
             content_type: "image/jpeg".into(),
             filename: "/tmp/gurk/signal-2022-01-16T11:59:58.405665+00:00.jpg".into(),
             size: 238987,
+            download: None,
         }
     }
 
+    #[test]
+    fn test_add_pending_attachments() {
+        let mut attachment = Attachment::pending(&AttachmentPointer {
+            content_type: Some("video/mp4".into()),
+            size: Some(3 * 1024 * 1024),
+            ..Default::default()
+        });
+        let mut msg = Message {
+            attachments: vec![attachment.clone()],
+            ..test_message()
+        };
+        let mut text = String::new();
+        add_attachments(&msg, &mut text);
+        assert_eq!(text, "<video/mp4 (3.0 MiB): select to download>");
+
+        attachment.download.as_mut().unwrap().in_progress = true;
+        msg.attachments = vec![attachment.clone()];
+        let mut text = String::new();
+        add_attachments(&msg, &mut text);
+        assert_eq!(text, "<downloading video/mp4 (3.0 MiB)>");
+
+        let download = attachment.download.as_mut().unwrap();
+        download.in_progress = false;
+        download.error = Some("timeout".into());
+        download.file_name = Some("clip.mp4".into());
+        msg.attachments = vec![attachment];
+        let mut text = String::new();
+        add_attachments(&msg, &mut text);
+        assert_eq!(
+            text,
+            "<clip.mp4 (3.0 MiB): download failed: timeout; select to retry>"
+        );
+    }
+
     #[test]
     fn test_display_attachment_only_message() {
         let names = name_resolver(USER_ID);
@@ -886,6 +1090,99 @@ mod tests {
         assert_eq!(rendered, Some(expected));
     }
 
+    #[test]
+    fn test_display_mention() {
+        let names = name_resolver(USER_ID);
+        let msg = Message {
+            message: Some(format!("Hi {}!", MENTION_PLACEHOLDER)),
+            mentions: vec![Mention {
+                start: 3,
+                uuid: USER_ID,
+            }],
+            ..test_message()
+        };
+        let rendered = display_message(&names, &msg, PREFIX, WIDTH, HEIGHT, ShowReceipt::Never);
+
+        let expected = ListItem::new(Text::from(vec![Spans(vec![
+            Span::styled("", Style::default().fg(Color::Yellow)),
+            Span::styled(
+                display_datetime(msg.arrived_at),
+                Style::default().fg(Color::Yellow),
+            ),
+            Span::styled("boxdot", Style::default().fg(Color::Green)),
+            Span::raw(": "),
+            Span::raw("Hi "),
+            Span::styled(
+                "@boxdot",
+                Style::default()
+                    .fg(Color::Green)
+                    .add_modifier(Modifier::BOLD),
+            ),
+            Span::raw("!"),
+        ])]));
+        assert_eq!(rendered, Some(expected));
+    }
+
+    #[test]
+    fn test_display_group_change() {
+        let names = name_resolver(USER_ID);
+        let msg = Message::group_change(GroupChange::MemberAdded(USER_ID), 1642334397421);
+        let rendered = display_message(&names, &msg, PREFIX, WIDTH, HEIGHT, ShowReceipt::Never);
+
+        let style = Style::default()
+            .fg(Color::Rgb(150, 150, 150))
+            .add_modifier(Modifier::ITALIC);
+        let expected = ListItem::new(Text::from(vec![Spans(vec![
+            Span::styled("", Style::default().fg(Color::Yellow)),
+            Span::styled(
+                display_datetime(msg.arrived_at),
+                Style::default().fg(Color::Yellow),
+            ),
+            Span::styled("boxdot joined the group", style),
+        ])]));
+        assert_eq!(rendered, Some(expected));
+
+        assert_eq!(
+            display_group_change(
+                &GroupChange::ExpireTimerChanged(Some(3600)),
+                |_| unreachable!()
+            ),
+            "disappearing messages set to 1h"
+        );
+    }
+
+    #[test]
+    fn test_display_expire_timer() {
+        assert_eq!(display_expire_timer(30), "30s");
+        assert_eq!(display_expire_timer(5 * 60), "5m");
+        assert_eq!(display_expire_timer(8 * 60 * 60), "8h");
+        assert_eq!(display_expire_timer(24 * 60 * 60), "1d");
+        assert_eq!(display_expire_timer(4 * 7 * 24 * 60 * 60), "4w");
+        assert_eq!(display_expire_timer(90), "90s");
+    }
+
+    #[test]
+    fn test_display_deleted_message() {
+        let names = name_resolver(USER_ID);
+        let msg = Message {
+            deleted: true,
+            ..test_message()
+        };
+        let rendered = display_message(&names, &msg, PREFIX, WIDTH, HEIGHT, ShowReceipt::Never);
+
+        let expected = ListItem::new(Text::from(vec![Spans(vec![
+            Span::styled("", Style::default().fg(Color::Yellow)),
+            Span::styled(
+                display_datetime(msg.arrived_at),
+                Style::default().fg(Color::Yellow),
+            ),
+            Span::styled("boxdot", Style::default().fg(Color::Green)),
+            Span::raw(": "),
+            Span::raw("This message was deleted."),
+        ])]));
+        assert_eq!(rendered, Some(expected));
+    }
+
     #[test]
     fn test_display_sent_receipt() {
         let names = name_resolver(USER_ID);